@@ -0,0 +1,109 @@
+use protocol::{
+    CaptureSample, CaptureState, CaptureTrigger, RequestBody, ResponseBody,
+    CAPTURE_CHUNK, CAPTURE_LENGTH,
+};
+use std::fs::File;
+use std::io::{ self, BufWriter, Write };
+use std::path::Path;
+use std::thread;
+use std::time::{ Duration, Instant };
+
+use crate::link::Connection;
+
+/// Arm a capture, wait for it to trigger and fill, and read it back.
+pub fn capture(connection: &mut Connection, trigger: CaptureTrigger, pre_trigger: u16, timeout: Duration)
+    -> Result<Vec<CaptureSample>, String> {
+    match connection.request(RequestBody::ArmCapture { trigger, pre_trigger }) {
+        ResponseBody::CaptureStatus { .. } => (),
+        other => return Err(format!("Can't arm the capture: {:?}", other)),
+    }
+
+    let start = Instant::now();
+    loop {
+        match connection.request(RequestBody::CaptureStatus) {
+            ResponseBody::CaptureStatus { state: CaptureState::Complete, .. } => break,
+            ResponseBody::CaptureStatus { .. } => (),
+            other => return Err(format!("Unexpected response {:?}", other)),
+        }
+        if start.elapsed() > timeout {
+            return Err("Timed out waiting for the trigger".to_string());
+        }
+        thread::sleep(Duration::from_millis(20));
+    }
+
+    let mut samples = Vec::with_capacity(CAPTURE_LENGTH as usize);
+    while samples.len() < CAPTURE_LENGTH as usize {
+        let offset = samples.len() as u16;
+        match connection.request(RequestBody::ReadCapture { offset }) {
+            ResponseBody::CaptureChunk { offset: got, count, samples: chunk } if got == offset =>
+                samples.extend_from_slice(&chunk[..(count as usize).min(CAPTURE_CHUNK)]),
+            other => return Err(format!("Unexpected response {:?}", other)),
+        }
+    }
+    Ok(samples)
+}
+
+/// Writes the inputs of the first `encoders` encoders as CSV with a time
+/// column, or as a WAV file with a channel per input if the path ends in .wav.
+pub fn save(path: &Path, samples: &[CaptureSample], encoders: usize, sample_rate: u32) -> io::Result<()> {
+    let mut file = BufWriter::new(File::create(path)?);
+    match path.extension().and_then(|extension| extension.to_str()) {
+        Some("wav") => write_wav(&mut file, samples, encoders * 2, sample_rate),
+        _ => write_csv(&mut file, samples, encoders * 2, sample_rate),
+    }?;
+    file.flush()
+}
+
+fn write_csv<W: Write>(out: &mut W, samples: &[CaptureSample], channels: usize, sample_rate: u32) -> io::Result<()> {
+    write!(out, "time")?;
+    for channel in 0..channels {
+        write!(out, ",encoder{}_{}", channel / 2, if channel % 2 == 0 { "a" } else { "b" })?;
+    }
+    writeln!(out)?;
+    for (i, sample) in samples.iter().enumerate() {
+        write!(out, "{:.6}", i as f64 / sample_rate as f64)?;
+        for value in sample[..channels].iter() {
+            write!(out, ",{}", value)?;
+        }
+        writeln!(out)?;
+    }
+    Ok(())
+}
+
+// 16 bit PCM. The ADC is 12 bit and unsigned, so shift it up and centre it.
+fn write_wav<W: Write>(out: &mut W, samples: &[CaptureSample], channels: usize, sample_rate: u32) -> io::Result<()> {
+    let (channels, values) = (channels as u16, channels);
+    let block_align = channels * 2;
+    let data_length = samples.len() as u32 * block_align as u32;
+
+    out.write_all(b"RIFF")?;
+    out.write_all(&(36 + data_length).to_le_bytes())?;
+    out.write_all(b"WAVEfmt ")?;
+    out.write_all(&16u32.to_le_bytes())?;
+    out.write_all(&1u16.to_le_bytes())?;
+    out.write_all(&channels.to_le_bytes())?;
+    out.write_all(&sample_rate.to_le_bytes())?;
+    out.write_all(&(sample_rate * block_align as u32).to_le_bytes())?;
+    out.write_all(&block_align.to_le_bytes())?;
+    out.write_all(&16u16.to_le_bytes())?;
+    out.write_all(b"data")?;
+    out.write_all(&data_length.to_le_bytes())?;
+    for sample in samples {
+        for value in sample[..values].iter() {
+            let pcm = ((*value as i32 - 2048) << 4) as i16;
+            out.write_all(&pcm.to_le_bytes())?;
+        }
+    }
+    Ok(())
+}
+
+/// Parses the trigger options of the capture subcommand.
+pub fn trigger(kind: &str, channel: u8, level: u16) -> Option<CaptureTrigger> {
+    match kind {
+        "immediate" => Some(CaptureTrigger::Immediate),
+        "rising" => Some(CaptureTrigger::Rising { channel, level }),
+        "falling" => Some(CaptureTrigger::Falling { channel, level }),
+        "glitch" => Some(CaptureTrigger::Glitch { channel, threshold: level }),
+        _ => None,
+    }
+}
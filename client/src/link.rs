@@ -0,0 +1,203 @@
+use cobs;
+use postcard;
+use protocol::{ self, RADIO_PACKET, RADIO_HEADER };
+use serialport::{ SerialPort, SerialPortType };
+use std::io::{ self, BufRead, BufReader, ErrorKind, Write };
+
+const DELIMITER : u8 = 0;
+
+// Find the microcontroller's USB CDC-ACM port, if it is plugged in
+pub fn find_usb_device() -> Option<String> {
+    serialport::available_ports().ok()?
+        .into_iter()
+        .find(|port| match &port.port_type {
+            SerialPortType::UsbPort(usb) =>
+                usb.vid == protocol::USB_VID && usb.pid == protocol::USB_PID,
+            _ => false
+        })
+        .map(|port| port.port_name)
+}
+
+/// How the COBS frames are carried over the serial port.
+#[derive(Clone, Copy)]
+pub enum Framing {
+    /// Straight onto the port, for USART and USB links
+    Direct,
+    /// Through a USB attached radio: each COBS frame on the port is one radio
+    /// packet, which carries a fragment of the stream the microcontroller sees.
+    RadioBridge,
+}
+
+pub struct Sender {
+    port: Box<dyn SerialPort>,
+    framing: Framing,
+    sequence: u8,
+}
+
+impl Sender {
+    pub fn new(port: Box<dyn SerialPort>, framing: Framing) -> Self {
+        Sender { port, framing, sequence: 0 }
+    }
+
+    /// Changes the rate for the receiving half too, since they share the port.
+    pub fn set_baud_rate(&mut self, baud_rate: u32) {
+        self.port.set_baud_rate(baud_rate).unwrap();
+    }
+
+    pub fn send(&mut self, request: &protocol::Request) {
+        let frame = postcard::to_stdvec_cobs(request).unwrap();
+        match self.framing {
+            Framing::Direct => self.port.write_all(&frame[..]).unwrap(),
+            Framing::RadioBridge => {
+                for fragment in frame.chunks(RADIO_PACKET - RADIO_HEADER) {
+                    let mut packet = vec![self.sequence, fragment.len() as u8];
+                    packet.extend_from_slice(fragment);
+                    packet.resize(RADIO_PACKET, 0);
+                    let mut encoded = cobs::encode_vec(&packet);
+                    encoded.push(DELIMITER);
+                    self.port.write_all(&encoded[..]).unwrap();
+                    self.sequence = self.sequence.wrapping_add(1);
+                }
+            }
+        }
+    }
+}
+
+pub struct Receiver {
+    reader: BufReader<Box<dyn SerialPort>>,
+    framing: Framing,
+    // Stream bytes reassembled from radio packets
+    stream: Vec<u8>,
+    sequence: Option<u8>,
+}
+
+impl Receiver {
+    pub fn new(port: Box<dyn SerialPort>, framing: Framing) -> Self {
+        Receiver {
+            reader: BufReader::new(port),
+            framing,
+            stream: Vec::new(),
+            sequence: None,
+        }
+    }
+
+    fn read_frame(&mut self) -> io::Result<Vec<u8>> {
+        let mut frame = Vec::new();
+        match self.reader.read_until(DELIMITER, &mut frame)? {
+            0 => Err(io::Error::new(ErrorKind::UnexpectedEof, "Serial port closed")),
+            _ => Ok(frame),
+        }
+    }
+
+    // The next COBS frame from the microcontroller, including its delimiter
+    fn next_frame(&mut self) -> io::Result<Vec<u8>> {
+        match self.framing {
+            Framing::Direct => self.read_frame(),
+            Framing::RadioBridge => loop {
+                if let Some(end) = self.stream.iter().position(|b| *b == DELIMITER) {
+                    return Ok(self.stream.drain(..=end).collect());
+                }
+
+                let mut packet = self.read_frame()?;
+                packet.pop();
+                let packet = match cobs::decode_vec(&packet) {
+                    Ok(packet) if packet.len() >= RADIO_HEADER => packet,
+                    _ => continue
+                };
+
+                if self.sequence.is_some_and(|expected| expected != packet[0]) {
+                    // Lost a fragment, so whatever we have so far is garbage
+                    self.stream.push(DELIMITER);
+                }
+                self.sequence = Some(packet[0].wrapping_add(1));
+                let count = (packet[1] as usize).min(packet.len() - RADIO_HEADER);
+                self.stream.extend_from_slice(&packet[RADIO_HEADER..RADIO_HEADER + count]);
+            }
+        }
+    }
+
+    /// The next response, or an error if the port times out first.
+    pub fn receive(&mut self) -> io::Result<postcard::Result<protocol::Response>> {
+        loop {
+            let mut frame = self.next_frame()?;
+            // Empty frames are just delimiters
+            if frame.len() <= 1 { continue; }
+            return Ok(postcard::from_bytes_cobs(&mut frame[..]));
+        }
+    }
+}
+
+/// Waits through timeouts, and ends when the port closes.
+impl Iterator for Receiver {
+    type Item = postcard::Result<protocol::Response>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            match self.receive() {
+                Ok(result) => return Some(result),
+                Err(ref e) if e.kind() == ErrorKind::TimedOut => continue,
+                Err(e) if e.kind() == ErrorKind::UnexpectedEof => return None,
+                Err(e) => panic!("Error reading from serial port: {:?}", e),
+            }
+        }
+    }
+}
+
+/// Both halves of the link, for commands that make a request and wait for
+/// its response.
+pub struct Connection {
+    sender: Sender,
+    receiver: Receiver,
+    next_id: i32,
+}
+
+impl Connection {
+    pub fn new(sender: Sender, receiver: Receiver) -> Self {
+        Connection { sender, receiver, next_id: 0 }
+    }
+
+    /// Send a request without waiting, returning its correlation id.
+    pub fn send(&mut self, body: protocol::RequestBody) -> i32 {
+        let id = self.next_id;
+        self.next_id += 1;
+        self.sender.send(&protocol::Request { correlation_id: id, body });
+        id
+    }
+
+    /// The next response that decodes, whatever it is a response to.
+    pub fn receive(&mut self) -> protocol::Response {
+        loop {
+            match self.receiver.next().expect("Serial port closed") {
+                Ok(response) => return response,
+                Err(e) => eprintln!("Deserialisation error: {:?}", e),
+            }
+        }
+    }
+
+    pub fn request(&mut self, body: protocol::RequestBody) -> protocol::ResponseBody {
+        let id = self.send(body);
+        loop {
+            let response = self.receive();
+            if response.correlation_id == id {
+                return response.body;
+            }
+        }
+    }
+
+    /// Like `request`, but gives up if the port times out.
+    pub fn try_request(&mut self, body: protocol::RequestBody) -> Option<protocol::ResponseBody> {
+        let id = self.send(body);
+        loop {
+            match self.receiver.receive() {
+                Ok(Ok(response)) if response.correlation_id == id => return Some(response.body),
+                Ok(_) => continue,
+                Err(ref e) if e.kind() == ErrorKind::TimedOut => return None,
+                Err(e) => panic!("Error reading from serial port: {:?}", e),
+            }
+        }
+    }
+
+    pub fn set_baud_rate(&mut self, baud_rate: u32) {
+        self.sender.set_baud_rate(baud_rate);
+    }
+}
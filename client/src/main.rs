@@ -1,34 +1,23 @@
 extern crate clap;
-use clap::{ Arg, App };
+use clap::{ Arg, App, ArgMatches, SubCommand };
 use serialport;
 use serialport::{ SerialPortSettings, FlowControl,DataBits,Parity,StopBits };
-use postcard;
-use protocol;
-use std::io::{BufRead, BufReader, Write};
-use std::time::Duration;
+use protocol::{ self, RequestBody, ResponseBody };
+use std::time::{ Duration, Instant };
 use std::{ thread };
+use std::path::Path;
+use std::process::Command;
 
-const DELIMITER : u8 = 0;
+mod link;
+mod capture;
 
-fn main() {
-    let matches = App::new("quadrature-ping")
-    .version("0.1")
-    .about("Test connectivity to the microcontroller")
-    .author("David Ireland")
-    .arg(Arg::with_name("serial-device-path")
-    .short("d")
-    .long("device")
-    .help("Path to serial device")
-    .required(true)
-    .takes_value(true))
-    .arg(Arg::with_name("serial-baud")
-    .short("b")
-    .long("baud")
-    .help("Serial baud rate")
-    .takes_value(true))
-    .get_matches();
-    
-    let serial_device_path = matches.value_of("serial-device-path").unwrap();
+use link::{ Connection, Framing, Sender, Receiver, find_usb_device };
+
+fn open(matches: &ArgMatches) -> (Sender, Receiver) {
+    let serial_device_path = match matches.value_of("serial-device-path") {
+        Some(path) => path.to_string(),
+        None => find_usb_device().expect("No --device given and no USB device found")
+    };
     let serial_baud = matches.value_of("serial-baud").unwrap_or("115200");
     
     let settings = SerialPortSettings {
@@ -40,38 +29,1396 @@ fn main() {
         timeout: Duration::from_millis(2000)
     };
     
-    let mut serial_port = serialport::open_with_settings(serial_device_path, &settings).unwrap();
-    let mut sending_port = serial_port.try_clone().expect("Failed to clone");
-    
+    let framing = match matches.is_present("radio-bridge") {
+        true => Framing::RadioBridge,
+        false => Framing::Direct
+    };
+
+    let serial_port = serialport::open_with_settings(&serial_device_path, &settings).unwrap();
+    let sender = Sender::new(serial_port.try_clone().expect("Failed to clone"), framing);
+    let receiver = Receiver::new(serial_port, framing);
+    (sender, receiver)
+}
+
+fn ping(mut sender: Sender, receiver: Receiver) {
     thread::spawn(move || for id in 0.. {
         let request = protocol::Request {
             correlation_id: id,
-            body: protocol::RequestBody::Ping
+            body: RequestBody::Ping
         };
         
-        let mut buffer : [u8; 256] = [0; 256];
-        let frame = postcard::to_slice_cobs(&request, &mut buffer).unwrap();
-        sending_port.write_all(&frame[..]).unwrap();
+        sender.send(&request);
         thread::sleep(Duration::from_millis(1000));
     });
     
-    let buffered = BufReader::new(&mut serial_port);
-    for unterminated in buffered.split(DELIMITER) {
-        let mut frame = unterminated.unwrap().clone();
-        frame.push(DELIMITER);
-        let result : 
-            postcard::Result<(protocol::Response, &mut [u8])> = 
-            postcard::take_from_bytes_cobs(&mut frame[..]);
+    for result in receiver {
         match result {
-            Ok((response, _)) => {
+            Ok(response) => {
                 println!("Response: {:?}", response.correlation_id);
             },
             Err(e) =>  {
                 eprintln!("Deserialisation error: {:?}", e);
-                eprintln!("{:?}", &frame[..]);
             }
         }
+    }
+}
+
+fn link_statistics(connection: &mut Connection) -> protocol::LinkStatistics {
+    match connection.request(RequestBody::LinkStatistics) {
+        ResponseBody::LinkStatistics(statistics) => statistics,
+        other => panic!("Unexpected response {:?}", other)
+    }
+}
+
+// How many pings can be waiting for a response
+const THROUGHPUT_WINDOW: u32 = 8;
+
+// Flood the link with pings, and see how much the microcontroller got through
+fn throughput(mut connection: Connection, count: u32) {
+    let before = link_statistics(&mut connection);
+    let start = Instant::now();
+    let mut sent = 0;
+    for received in 0..count {
+        while sent < count && sent < received + THROUGHPUT_WINDOW {
+            connection.send(RequestBody::Ping);
+            sent += 1;
+        }
+        connection.receive();
+    }
+    let after = link_statistics(&mut connection);
+    let seconds = start.elapsed().as_secs_f64();
+
+    println!("{:?}", after);
+    println!("Received {:.0} bytes/s, {:.0} frames/s",
+        (after.rx_bytes - before.rx_bytes) as f64 / seconds,
+        (after.rx_frames - before.rx_frames) as f64 / seconds);
+    println!("Sent {:.0} bytes/s, {:.0} frames/s",
+        (after.tx_bytes - before.tx_bytes) as f64 / seconds,
+        (after.tx_frames - before.tx_frames) as f64 / seconds);
+    println!("{} receive errors, {} overruns, {} responses dropped, {} fragments lost, {} unreadable",
+        after.rx_errors - before.rx_errors,
+        after.rx_overruns - before.rx_overruns,
+        after.tx_dropped - before.tx_dropped,
+        after.tx_lost - before.tx_lost,
+        after.rx_lost - before.rx_lost);
+}
+
+// Change the microcontroller's baud rate and follow it, going back to the old
+// rate if we can't ping it at the new one.
+fn set_baud_rate(mut connection: Connection, current: u32, baud_rate: u32, persist: bool) {
+    let request = RequestBody::SetBaudRate { baud_rate, persist };
+    let actual = match connection.request(request) {
+        ResponseBody::SetBaudRate { baud_rate } => baud_rate,
+        other => {
+            eprintln!("Can't change the baud rate: {:?}", other);
+            return;
+        }
+    };
+
+    // Give the response time to finish going before the rate changes
+    thread::sleep(Duration::from_millis(50));
+    connection.set_baud_rate(actual);
+    if let Some(ResponseBody::Ping) = connection.try_request(RequestBody::Ping) {
+        println!("Now at {} baud{}", actual, if persist { ", saved" } else { "" });
+        return;
+    }
+
+    eprintln!("No response at {} baud, going back to {}", actual, current);
+    connection.set_baud_rate(current);
+    // The microcontroller gives up on the new rate after 2s
+    thread::sleep(Duration::from_millis(1000));
+    match connection.try_request(RequestBody::Ping) {
+        Some(ResponseBody::Ping) => println!("Back at {} baud", current),
+        _ => eprintln!("No response at {} baud either", current),
+    }
+}
+
+fn sample_rate(mut connection: Connection) {
+    match connection.request(RequestBody::SampleRate) {
+        ResponseBody::SampleRate { configured, measured, overruns } =>
+            println!("Sampling at {} Hz, configured for {} Hz, {} overruns", measured, configured, overruns),
+        other => eprintln!("Unexpected response {:?}", other),
+    }
+}
+
+fn print_channel(name: &str, channel: &protocol::ChannelHealth) {
+    let verdict = match channel.fault {
+        protocol::SignalFault::None => "ok",
+        protocol::SignalFault::LowAmplitude => "low amplitude (is it turning?)",
+        protocol::SignalFault::Disconnected => "disconnected",
+    };
+    println!("  {}: offset {:.0}, amplitude {:.0}, noise {:.1} counts RMS, {}",
+        name, channel.offset, channel.amplitude, channel.noise, verdict);
+}
+
+/// How many encoders the board has, and how many of them have motors
+fn encoders(connection: &mut Connection) -> (u8, u8) {
+    match connection.request(RequestBody::Encoders) {
+        ResponseBody::Encoders { encoders, motors } => (encoders, motors),
+        other => panic!("Unexpected response {:?}", other)
+    }
+}
+
+// Turn the encoders by hand, or drive the motors, while this runs
+fn diagnose(mut connection: Connection, compare_trigger: bool) {
+    let (encoders, motors) = encoders(&mut connection);
+    for encoder in 0..encoders {
+        match connection.request(RequestBody::EncoderHealth { encoder }) {
+            ResponseBody::EncoderHealth(health) => {
+                println!("Encoder {}{}, over {} samples:",
+                    encoder, if encoder < motors { " (motor)" } else { "" }, health.samples);
+                print_channel("A", &health.a);
+                print_channel("B", &health.b);
+                println!("  amplitude mismatch {:.1}%, phase error {:.1} degrees",
+                    health.amplitude_mismatch * 100.0, health.phase_error);
+            },
+            other => eprintln!("Unexpected response {:?}", other),
+        }
+    }
+    if compare_trigger {
+        compare_sample_trigger(&mut connection, encoders);
+    }
+}
+
+// Each encoder input's noise, in counts RMS, over a health window that
+// started after now
+fn noise(connection: &mut Connection, encoders: u8) -> Option<Vec<f32>> {
+    // Two windows, at about 0.4s each
+    thread::sleep(Duration::from_millis(1000));
+    let mut noise = Vec::new();
+    for encoder in 0..encoders {
+        match connection.request(RequestBody::EncoderHealth { encoder }) {
+            ResponseBody::EncoderHealth(health) => {
+                noise.push(health.a.noise);
+                noise.push(health.b.noise);
+            },
+            other => {
+                eprintln!("Can't read encoder {}'s signal quality: {:?}", encoder, other);
+                return None;
+            }
+        }
+    }
+    Some(noise)
+}
 
+// Whether the PWM is locked to the sampling now
+fn set_sample_trigger(connection: &mut Connection, trigger: protocol::SampleTrigger) -> Option<bool> {
+    match connection.request(RequestBody::SetSampleTrigger { trigger, persist: false }) {
+        ResponseBody::SampleTrigger { locked, .. } => Some(locked),
+        other => {
+            eprintln!("Can't change the sample trigger: {:?}", other);
+            None
+        }
     }
+}
 
+// How much quieter the encoder inputs are with the sampling synchronised to
+// the motor PWM, which only shows with the motors running
+fn compare_sample_trigger(connection: &mut Connection, encoders: u8) {
+    let original = match connection.request(RequestBody::SampleTrigger) {
+        ResponseBody::SampleTrigger { trigger, .. } => trigger,
+        other => {
+            eprintln!("Can't read the sample trigger: {:?}", other);
+            return;
+        }
+    };
+
+    let measured = set_sample_trigger(connection, protocol::SampleTrigger { synchronised: false, ..original })
+        .and_then(|_| noise(connection, encoders))
+        .and_then(|free| {
+            let locked = set_sample_trigger(connection, protocol::SampleTrigger { synchronised: true, ..original })?;
+            Some((free, locked, noise(connection, encoders)?))
+        });
+    set_sample_trigger(connection, original);
+    let (free, locked, synchronised) = match measured {
+        Some(measured) => measured,
+        None => return,
+    };
+
+    if !locked {
+        eprintln!("The PWM can't be synchronised to the sampling: its frequency has to be a multiple of the sample rate");
+        return;
+    }
+    println!("Noise in counts RMS, free running and synchronised at {} of the PWM period:", original.offset);
+    for (channel, (free, synchronised)) in free.iter().zip(synchronised.iter()).enumerate() {
+        println!("  encoder {} {}: {:.1}, {:.1}, {:.1}dB better",
+            channel / 2,
+            if channel % 2 == 0 { "A" } else { "B" },
+            free,
+            synchronised,
+            20.0 * (free / synchronised).log10());
+    }
+}
+
+// Show the sample trigger, changing whatever was given first
+fn sample_trigger(mut connection: Connection, sub: &ArgMatches) {
+    let (mut trigger, mut locked) = match connection.request(RequestBody::SampleTrigger) {
+        ResponseBody::SampleTrigger { trigger, locked } => (trigger, locked),
+        other => {
+            eprintln!("Can't read the sample trigger: {:?}", other);
+            return;
+        }
+    };
+
+    let synchronised = sub.value_of("synchronised").map(|value| value == "true");
+    let offset = sub.value_of("offset").map(|offset| offset.parse::<f32>().unwrap());
+    if synchronised.is_some() || offset.is_some() || sub.is_present("persist") {
+        trigger.synchronised = synchronised.unwrap_or(trigger.synchronised);
+        trigger.offset = offset.unwrap_or(trigger.offset);
+        match connection.request(RequestBody::SetSampleTrigger { trigger, persist: sub.is_present("persist") }) {
+            ResponseBody::SampleTrigger { trigger: set, locked: set_locked } => {
+                trigger = set;
+                locked = set_locked;
+            },
+            other => {
+                eprintln!("Can't change the sample trigger: {:?}", other);
+                return;
+            }
+        }
+    }
+    match (trigger.synchronised, locked) {
+        (false, _) => println!("Free running"),
+        (true, true) => println!("Synchronised, sampling at {} of the PWM period", trigger.offset),
+        (true, false) => println!("Not synchronised: the PWM frequency isn't a multiple of the sample rate"),
+    }
+}
+
+fn print_calibration(response: ResponseBody) {
+    match response {
+        ResponseBody::Calibration { encoder, calibration, frozen } => {
+            let state = if frozen { "frozen" } else { "learning" };
+            match calibration {
+                Some(c) => println!(
+                    "Encoder {} ({}): A offset {:.1} amplitude {:.1}, B offset {:.1} amplitude {:.1}, phase error {:.2} degrees",
+                    encoder, state, c.offset_a, c.amplitude_a, c.offset_b, c.amplitude_b, c.phase_error),
+                None => println!("Encoder {} ({}): not calibrated yet", encoder, state),
+            }
+        },
+        other => eprintln!("Unexpected response {:?}", other),
+    }
+}
+
+// Show, change, freeze or reset the sin/cos calibration of one or both
+// encoders
+fn calibrate(mut connection: Connection, only: Option<u8>, sub: &ArgMatches) {
+    let encoders = match only {
+        Some(encoder) => encoder..encoder + 1,
+        None => 0..encoders(&mut connection).0,
+    };
+    for encoder in encoders {
+        if sub.is_present("reset") {
+            connection.request(RequestBody::SetCalibration { encoder, calibration: None });
+        }
+        if let Some(values) = sub.values_of("set") {
+            let values: Vec<f32> = values.map(|value| value.parse::<f32>().unwrap()).collect();
+            let calibration = protocol::Calibration {
+                offset_a: values[0],
+                amplitude_a: values[1],
+                offset_b: values[2],
+                amplitude_b: values[3],
+                phase_error: values[4],
+            };
+            connection.request(RequestBody::SetCalibration { encoder, calibration: Some(calibration) });
+        }
+        if sub.is_present("freeze") || sub.is_present("learn") {
+            let frozen = sub.is_present("freeze");
+            connection.request(RequestBody::FreezeCalibration { encoder, frozen });
+        }
+        print_calibration(connection.request(RequestBody::Calibration { encoder }));
+    }
+}
+
+// Drive an encoder's motor round to its index and wait until it's there
+fn home(mut connection: Connection, encoder: u8, duty: f32, timeout_ms: u32) {
+    let id = connection.send(RequestBody::Home { encoder, duty, timeout_ms });
+    loop {
+        let response = connection.receive();
+        if response.correlation_id != id {
+            continue;
+        }
+        match response.body {
+            ResponseBody::Homing { state: protocol::HomingState::Searching, position, .. } =>
+                println!("Searching, at {:.3} cycles", position),
+            ResponseBody::Homing { state, position, .. } => {
+                println!("{:?}, at {:.3} cycles", state, position);
+                return;
+            },
+            other => {
+                eprintln!("Can't home encoder {}: {:?}", encoder, other);
+                return;
+            }
+        }
+    }
+}
+
+// Print how far the analog decoding has drifted from the reference decoder
+// every `interval`, as CSV
+fn compare(mut connection: Connection, encoder: u8, interval: Duration, count: Option<u32>) {
+    let start = Instant::now();
+    let mut reset = true;
+    println!("time,analog,reference,difference,min,max,rms");
+    for _ in 0..count.unwrap_or(u32::MAX) {
+        match connection.request(RequestBody::CompareReference { encoder, reset }) {
+            ResponseBody::ReferenceComparison(c) => println!("{:.3},{:.4},{:.4},{:.4},{:.4},{:.4},{:.4}",
+                start.elapsed().as_secs_f64(), c.analog, c.reference, c.difference, c.min, c.max, c.rms),
+            other => {
+                eprintln!("Can't compare with the reference: {:?}", other);
+                return;
+            }
+        }
+        reset = false;
+        thread::sleep(interval);
+    }
+}
+
+// Show how the reference decoder's count is scaled, changing whatever was
+// given first
+fn reference_config(mut connection: Connection, sub: &ArgMatches) {
+    let mut config = match connection.request(RequestBody::ReferenceConfig) {
+        ResponseBody::ReferenceConfig(config) => config,
+        other => {
+            eprintln!("Can't read the reference config: {:?}", other);
+            return;
+        }
+    };
+
+    let counts_per_cycle = sub.value_of("counts-per-cycle").map(|value| value.parse::<f32>().unwrap());
+    let reverse = sub.value_of("reverse").map(|value| value == "true");
+    if counts_per_cycle.is_some() || reverse.is_some() || sub.is_present("persist") {
+        config.counts_per_cycle = counts_per_cycle.unwrap_or(config.counts_per_cycle);
+        config.reverse = reverse.unwrap_or(config.reverse);
+        let request = RequestBody::SetReferenceConfig { config, persist: sub.is_present("persist") };
+        match connection.request(request) {
+            ResponseBody::ReferenceConfig(set) => config = set,
+            other => {
+                eprintln!("Can't change the reference config: {:?}", other);
+                return;
+            }
+        }
+    }
+    println!("{:?}", config);
+}
+
+// Show an encoder's motor config, changing whatever was given first
+fn motor_config(mut connection: Connection, encoder: u8, sub: &ArgMatches) {
+    let mut config = match connection.request(RequestBody::MotorConfig { encoder }) {
+        ResponseBody::MotorConfig { config, .. } => config,
+        other => {
+            eprintln!("Can't read encoder {}'s config: {:?}", encoder, other);
+            return;
+        }
+    };
+
+    let option = |name| sub.value_of(name).map(|value: &str| value == "true");
+    let changes = (option("invert-output"), option("reverse-encoder"), option("swap-channels"));
+    if changes != (None, None, None) || sub.is_present("persist") {
+        config.invert_output = changes.0.unwrap_or(config.invert_output);
+        config.reverse_encoder = changes.1.unwrap_or(config.reverse_encoder);
+        config.swap_channels = changes.2.unwrap_or(config.swap_channels);
+        let request = RequestBody::SetMotorConfig { encoder, config, persist: sub.is_present("persist") };
+        match connection.request(request) {
+            ResponseBody::MotorConfig { config: set, .. } => config = set,
+            other => {
+                eprintln!("Can't change encoder {}'s config: {:?}", encoder, other);
+                return;
+            }
+        }
+    }
+    println!("Encoder {}: {:?}", encoder, config);
+}
+
+// Show a motor's output shaping, changing whatever was given first
+fn shaping(mut connection: Connection, encoder: u8, sub: &ArgMatches) {
+    let mut shaping = match connection.request(RequestBody::OutputShaping { encoder }) {
+        ResponseBody::OutputShaping { shaping, .. } => shaping,
+        other => {
+            eprintln!("Can't read motor {}'s output shaping: {:?}", encoder, other);
+            return;
+        }
+    };
+
+    let option = |name| sub.value_of(name).map(|value: &str| value.parse::<f32>().unwrap());
+    let changes = (option("max-step"), option("min-duty"), option("max-duty"));
+    if changes != (None, None, None) || sub.is_present("persist") {
+        shaping.max_step = changes.0.unwrap_or(shaping.max_step);
+        shaping.min_duty = changes.1.unwrap_or(shaping.min_duty);
+        shaping.max_duty = changes.2.unwrap_or(shaping.max_duty);
+        let request = RequestBody::SetOutputShaping { encoder, shaping, persist: sub.is_present("persist") };
+        match connection.request(request) {
+            ResponseBody::OutputShaping { shaping: set, .. } => shaping = set,
+            other => {
+                eprintln!("Can't change motor {}'s output shaping: {:?}", encoder, other);
+                return;
+            }
+        }
+    }
+    println!("Motor {}: duty {} to {}, changing by at most {} every 10ms",
+        encoder, shaping.min_duty, shaping.max_duty, shaping.max_step);
+}
+
+// Show the motor PWM, changing whatever was given first
+fn pwm(mut connection: Connection, sub: &ArgMatches) {
+    let (mut config, mut max_duty) = match connection.request(RequestBody::PwmConfig) {
+        ResponseBody::PwmConfig { config, max_duty } => (config, max_duty),
+        other => {
+            eprintln!("Can't read the PWM config: {:?}", other);
+            return;
+        }
+    };
+
+    let frequency = sub.value_of("frequency").map(|frequency| frequency.parse::<u32>().unwrap());
+    let centre_aligned = sub.value_of("centre-aligned").map(|value| value == "true");
+    if frequency.is_some() || centre_aligned.is_some() || sub.is_present("persist") {
+        config.frequency = frequency.unwrap_or(config.frequency);
+        config.centre_aligned = centre_aligned.unwrap_or(config.centre_aligned);
+        match connection.request(RequestBody::SetPwmConfig { config, persist: sub.is_present("persist") }) {
+            ResponseBody::PwmConfig { config: set, max_duty: set_max_duty } => {
+                config = set;
+                max_duty = set_max_duty;
+            },
+            other => {
+                eprintln!("Can't change the PWM config: {:?}", other);
+                return;
+            }
+        }
+    }
+    let steps = max_duty as u32 + 1;
+    println!("{}Hz, {}, {} duty steps ({:.1} bits)",
+        config.frequency,
+        if config.centre_aligned { "centre aligned" } else { "edge aligned" },
+        steps,
+        (steps as f32).log2());
+}
+
+// Print a telemetry snapshot as a CSV row every interval
+fn telemetry(mut connection: Connection, interval: Duration, count: Option<u32>) {
+    let start = Instant::now();
+    let mut header = false;
+    for _ in 0..count.unwrap_or(u32::MAX) {
+        let t = match connection.request(RequestBody::Telemetry) {
+            ResponseBody::Telemetry(telemetry) => telemetry,
+            other => {
+                eprintln!("Can't read the telemetry: {:?}", other);
+                return;
+            }
+        };
+        let encoders = t.encoders as usize;
+        if !header {
+            let columns: Vec<String> = (0..encoders)
+                .map(|i| format!("position{},velocity{}", i, i))
+                .collect();
+            println!("time,{},supply,supply_state,current,heading,estop_active,estop_latched", columns.join(","));
+            header = true;
+        }
+        let values: Vec<String> = (0..encoders)
+            .map(|i| format!("{:.4},{:.3}", t.positions[i], t.velocities[i]))
+            .collect();
+        println!("{:.3},{},{:.2},{:?},{:.2},{:.2},{},{}",
+            start.elapsed().as_secs_f64(), values.join(","), t.supply_voltage, t.supply_state, t.motor_current,
+            t.heading, t.estop.active, t.estop.latched);
+        thread::sleep(interval);
+    }
+}
+
+// Show the supply voltage and limits, changing whichever were given first
+fn supply(mut connection: Connection, sub: &ArgMatches) {
+    let (mut voltage, mut state, mut config) = match connection.request(RequestBody::Supply) {
+        ResponseBody::Supply { voltage, state, config } => (voltage, state, config),
+        other => {
+            eprintln!("Can't read the supply: {:?}", other);
+            return;
+        }
+    };
+
+    let option = |name| sub.value_of(name).map(|value: &str| value.parse::<f32>().unwrap());
+    let changes = [
+        option("divider"), option("undervoltage"), option("derate"), option("overvoltage"), option("nominal"),
+    ];
+    if changes.iter().any(|change| change.is_some()) || sub.is_present("persist") {
+        config.divider = changes[0].unwrap_or(config.divider);
+        config.undervoltage = changes[1].unwrap_or(config.undervoltage);
+        // Moving the undervoltage limit alone leaves it without derating
+        config.derate = changes[2].unwrap_or(config.derate.max(config.undervoltage));
+        config.overvoltage = changes[3].unwrap_or(config.overvoltage);
+        config.nominal = changes[4].unwrap_or(config.nominal);
+        match connection.request(RequestBody::SetSupplyConfig { config, persist: sub.is_present("persist") }) {
+            ResponseBody::Supply { voltage: v, state: s, config: c } => {
+                voltage = v;
+                state = s;
+                config = c;
+            },
+            other => {
+                eprintln!("Can't change the supply config: {:?}", other);
+                return;
+            }
+        }
+    }
+    println!("{:.2}V, {:?}", voltage, state);
+    println!("  divider {}, motors off below {}V and above {}V, derated below {}V",
+        config.divider, config.undervoltage, config.overvoltage, config.derate);
+    if config.nominal > 0.0 {
+        println!("  duty compensated to a {}V supply", config.nominal);
+    }
+}
+
+// Show the current limit and stall detection, changing whatever was given
+// first
+fn protection(mut connection: Connection, sub: &ArgMatches) {
+    let (mut config, mut current) = match connection.request(RequestBody::MotorProtection) {
+        ResponseBody::MotorProtection { config, current } => (config, current),
+        other => {
+            eprintln!("Can't read the motor protection: {:?}", other);
+            return;
+        }
+    };
+
+    let option = |name| sub.value_of(name).map(|value: &str| value.parse::<f32>().unwrap());
+    let changes = [
+        option("amps-per-volt"), option("zero-current"), option("current-limit"),
+        option("stall-duty"), option("stall-velocity"), option("stall-timeout"),
+    ];
+    if changes.iter().any(|change| change.is_some()) || sub.is_present("persist") {
+        config.amps_per_volt = changes[0].unwrap_or(config.amps_per_volt);
+        config.zero_current = changes[1].unwrap_or(config.zero_current);
+        config.current_limit = changes[2].unwrap_or(config.current_limit);
+        config.stall_duty = changes[3].unwrap_or(config.stall_duty);
+        config.stall_velocity = changes[4].unwrap_or(config.stall_velocity);
+        config.stall_timeout_ms = changes[5].map_or(config.stall_timeout_ms, |seconds| (seconds * 1000.0) as u32);
+        match connection.request(RequestBody::SetMotorProtection { config, persist: sub.is_present("persist") }) {
+            ResponseBody::MotorProtection { config: c, current: a } => {
+                config = c;
+                current = a;
+            },
+            other => {
+                eprintln!("Can't change the motor protection: {:?}", other);
+                return;
+            }
+        }
+    }
+    if config.amps_per_volt > 0.0 {
+        println!("{:.2}A, {} amps per volt from {}V, limited to {}A",
+            current, config.amps_per_volt, config.zero_current, config.current_limit);
+    } else {
+        println!("No current sensor");
+    }
+    if config.stall_timeout_ms > 0 {
+        println!("A motor driven at {} or more that turns slower than {} cycles/s for {}s is freed",
+            config.stall_duty, config.stall_velocity, config.stall_timeout_ms as f32 / 1000.0);
+    } else {
+        println!("Stalled motors are left alone");
+    }
+}
+
+// Show a servo, changing its config and then its angle if they were given
+fn servo(mut connection: Connection, servo: u8, sub: &ArgMatches) {
+    let (mut config, mut angle) = match connection.request(RequestBody::Servo { servo }) {
+        ResponseBody::Servo { config, angle, .. } => (config, angle),
+        other => {
+            eprintln!("Can't read servo {}: {:?}", servo, other);
+            return;
+        }
+    };
+
+    let pulse = |name| sub.value_of(name).map(|value: &str| value.parse::<u16>().unwrap());
+    let angle_option = |name| sub.value_of(name).map(|value: &str| value.parse::<f32>().unwrap());
+    let changes = (pulse("min-pulse"), pulse("max-pulse"), angle_option("min-angle"), angle_option("max-angle"));
+    if changes != (None, None, None, None) || sub.is_present("persist") {
+        config.min_pulse_us = changes.0.unwrap_or(config.min_pulse_us);
+        config.max_pulse_us = changes.1.unwrap_or(config.max_pulse_us);
+        config.min_angle = changes.2.unwrap_or(config.min_angle);
+        config.max_angle = changes.3.unwrap_or(config.max_angle);
+        let request = RequestBody::SetServoConfig { servo, config, persist: sub.is_present("persist") };
+        match connection.request(request) {
+            ResponseBody::Servo { config: set, angle: set_angle, .. } => {
+                config = set;
+                angle = set_angle;
+            },
+            other => {
+                eprintln!("Can't change servo {}'s config: {:?}", servo, other);
+                return;
+            }
+        }
+    }
+
+    let target = match (angle_option("angle"), sub.is_present("off")) {
+        (Some(angle), _) => Some(Some(angle)),
+        (None, true) => Some(None),
+        (None, false) => None,
+    };
+    if let Some(target) = target {
+        match connection.request(RequestBody::SetServo { servo, angle: target }) {
+            ResponseBody::Servo { angle: set, .. } => angle = set,
+            other => {
+                eprintln!("Can't move servo {}: {:?}", servo, other);
+                return;
+            }
+        }
+    }
+
+    let position = match angle {
+        Some(angle) => format!("at {}", angle),
+        None => "limp".to_string(),
+    };
+    println!("Servo {}: {}, {}us to {}us for {} to {}",
+        servo, position, config.min_pulse_us, config.max_pulse_us, config.min_angle, config.max_angle);
+}
+
+// Print the IMU's readings every `interval`, as CSV
+fn imu(mut connection: Connection, interval: Duration, count: Option<u32>) {
+    let start = Instant::now();
+    println!("time,accel_x,accel_y,accel_z,gyro_x,gyro_y,gyro_z,temperature");
+    for _ in 0..count.unwrap_or(u32::MAX) {
+        let r = match connection.request(RequestBody::Imu) {
+            ResponseBody::Imu(reading) => reading,
+            other => {
+                eprintln!("Can't read the IMU: {:?}", other);
+                return;
+            }
+        };
+        println!("{:.3},{:.3},{:.3},{:.3},{:.2},{:.2},{:.2},{:.1}",
+            start.elapsed().as_secs_f64(), r.accel[0], r.accel[1], r.accel[2],
+            r.gyro[0], r.gyro[1], r.gyro[2], r.temperature);
+        thread::sleep(interval);
+    }
+}
+
+// Show the heading, changing how it's worked out, then starting it again,
+// if asked
+fn heading(mut connection: Connection, sub: &ArgMatches) {
+    let (mut heading, mut config) = match connection.request(RequestBody::Heading) {
+        ResponseBody::Heading { heading, config } => (heading, config),
+        other => {
+            eprintln!("Can't read the heading: {:?}", other);
+            return;
+        }
+    };
+
+    let option = |name| sub.value_of(name).map(|value: &str| value.parse::<f32>().unwrap());
+    let changes = (option("metres-per-cycle"), option("track-width"), option("gyro-weight"));
+    if changes != (None, None, None) || sub.is_present("persist") {
+        config.metres_per_cycle = changes.0.unwrap_or(config.metres_per_cycle);
+        config.track_width = changes.1.unwrap_or(config.track_width);
+        config.gyro_weight = changes.2.unwrap_or(config.gyro_weight);
+        match connection.request(RequestBody::SetHeadingConfig { config, persist: sub.is_present("persist") }) {
+            ResponseBody::Heading { heading: h, config: c } => {
+                heading = h;
+                config = c;
+            },
+            other => {
+                eprintln!("Can't change the heading config: {:?}", other);
+                return;
+            }
+        }
+    }
+    if let Some(set) = option("set") {
+        match connection.request(RequestBody::SetHeading { heading: set }) {
+            ResponseBody::Heading { heading: h, .. } => heading = h,
+            other => {
+                eprintln!("Can't set the heading: {:?}", other);
+                return;
+            }
+        }
+    }
+
+    println!("Heading {:.2} degrees: {:.2} from the wheels, {:.2} from the gyro, which is {:.3} degrees/s out",
+        heading.heading, heading.odometry, heading.gyro, heading.gyro_bias);
+    println!("{}m a cycle, {}m between the wheels, gyro weight {}",
+        config.metres_per_cycle, config.track_width, config.gyro_weight);
+}
+
+// Write bytes to a device on the expansion bus and read some back
+fn i2c(mut connection: Connection, address: u8, write: &[u8], read: u8) {
+    if write.len() > protocol::I2C_MAX || read as usize > protocol::I2C_MAX {
+        eprintln!("At most {} bytes can be written or read at once", protocol::I2C_MAX);
+        return;
+    }
+    let mut data = [0; protocol::I2C_MAX];
+    data[..write.len()].copy_from_slice(write);
+    let request = RequestBody::I2c { address, write: write.len() as u8, data, read };
+    match connection.request(request) {
+        ResponseBody::I2c { read, data } => {
+            let bytes: Vec<String> = data[..read as usize].iter().map(|byte| format!("{:02x}", byte)).collect();
+            println!("{}", bytes.join(" "));
+        },
+        other => eprintln!("I2C transfer with {:#04x} failed: {:?}", address, other),
+    }
+}
+
+// A byte in hex, with or without 0x in front
+fn parse_byte(value: &str) -> u8 {
+    u8::from_str_radix(value.trim_start_matches("0x"), 16).unwrap()
+}
+
+// Show why the board last reset
+fn device_info(mut connection: Connection) {
+    match connection.request(RequestBody::DeviceInfo) {
+        ResponseBody::DeviceInfo { reset_cause, watchdog_ms } => {
+            let cause = match reset_cause {
+                protocol::ResetCause::PowerOn => "the power coming on, or a brown-out",
+                protocol::ResetCause::Pin => "the reset pin",
+                protocol::ResetCause::Software => "the firmware",
+                protocol::ResetCause::Watchdog => "the watchdog: the firmware had hung or the sampling had stopped",
+                protocol::ResetCause::WindowWatchdog => "the window watchdog",
+                protocol::ResetCause::LowPower => "a low power mode",
+            };
+            println!("Last reset by {}", cause);
+            println!("Watchdog timeout {}ms", watchdog_ms);
+        },
+        other => eprintln!("Can't read the device info: {:?}", other),
+    }
+}
+
+// Show the e-stop, changing its config, then clearing it, if asked
+fn estop(mut connection: Connection, sub: &ArgMatches) {
+    let (mut status, mut config) = match connection.request(RequestBody::EStop) {
+        ResponseBody::EStop { status, config } => (status, config),
+        other => {
+            eprintln!("Can't read the e-stop: {:?}", other);
+            return;
+        }
+    };
+
+    let flag = |name| sub.value_of(name).map(|value| value == "true");
+    let changes = (flag("enabled"), flag("active-high"), flag("brake"),
+        sub.value_of("debounce").map(|value| value.parse::<u16>().unwrap()));
+    if changes != (None, None, None, None) || sub.is_present("persist") {
+        config.enabled = changes.0.unwrap_or(config.enabled);
+        config.active_high = changes.1.unwrap_or(config.active_high);
+        config.brake = changes.2.unwrap_or(config.brake);
+        config.debounce_ms = changes.3.unwrap_or(config.debounce_ms);
+        match connection.request(RequestBody::SetEStopConfig { config, persist: sub.is_present("persist") }) {
+            ResponseBody::EStop { status: s, config: c } => {
+                status = s;
+                config = c;
+            },
+            other => {
+                eprintln!("Can't change the e-stop config: {:?}", other);
+                return;
+            }
+        }
+    }
+    if sub.is_present("clear") {
+        match connection.request(RequestBody::ClearEStop) {
+            ResponseBody::EStop { status: s, .. } => {
+                status = s;
+                if status.latched {
+                    eprintln!("Can't clear the e-stop while its input is active");
+                }
+            },
+            other => {
+                eprintln!("Can't clear the e-stop: {:?}", other);
+                return;
+            }
+        }
+    }
+
+    if !config.enabled {
+        println!("No e-stop");
+    } else {
+        println!("Input {}, motors {}",
+            if status.active { "active" } else { "inactive" },
+            if status.latched { "stopped until it's cleared" } else { "free to run" });
+        println!("  active {}, debounced for {}ms, {} the motors",
+            if config.active_high { "high" } else { "low" }, config.debounce_ms,
+            if config.brake { "braking" } else { "freeing" });
+    }
+}
+
+// Show the power switch, cutting the power later or not after all if asked
+fn power(mut connection: Connection, sub: &ArgMatches) {
+    let request = match (sub.value_of("off"), sub.is_present("cancel")) {
+        (Some(delay), _) => RequestBody::PowerOff { delay_ms: Some((delay.parse::<f32>().unwrap() * 1000.0) as u32) },
+        (None, true) => RequestBody::PowerOff { delay_ms: None },
+        (None, false) => RequestBody::Power,
+    };
+    match connection.request(request) {
+        ResponseBody::Power { button, off_in_ms } => {
+            println!("Button {}", if button { "down" } else { "up" });
+            match off_in_ms {
+                Some(off_in_ms) => println!("Power off in {}s", off_in_ms as f32 / 1000.0),
+                None => println!("Staying on"),
+            }
+        },
+        other => eprintln!("Can't control the power: {:?}", other),
+    }
+}
+
+// Wait for the power button, then have the board cut the power after
+// `delay`, and run `command` to shut down before it does
+fn power_button(mut connection: Connection, delay: Duration, command: &str) {
+    let id = connection.send(RequestBody::WatchEvents { watch: true });
+    loop {
+        let response = connection.receive();
+        if response.correlation_id == id && response.body == ResponseBody::Event(protocol::Event::PowerButton) {
+            break;
+        }
+    }
+    match connection.request(RequestBody::PowerOff { delay_ms: Some(delay.as_millis() as u32) }) {
+        ResponseBody::Power { .. } => println!("Power off in {}s", delay.as_secs_f32()),
+        other => {
+            eprintln!("Can't schedule the power off: {:?}", other);
+            return;
+        }
+    }
+    match Command::new("sh").arg("-c").arg(command).status() {
+        Ok(status) if status.success() => (),
+        Ok(status) => eprintln!("{} failed: {}", command, status),
+        Err(e) => eprintln!("Can't run {}: {}", command, e),
+    }
+}
+
+// Print events, like motors stalling, as they happen
+fn events(mut connection: Connection) {
+    let id = connection.send(RequestBody::WatchEvents { watch: true });
+    loop {
+        let response = connection.receive();
+        if response.correlation_id != id {
+            continue;
+        }
+        match response.body {
+            ResponseBody::WatchEvents { .. } => println!("Watching for events"),
+            ResponseBody::Event(protocol::Event::Stall { encoder, duty, current }) =>
+                println!("Motor {} stalled at duty {:.2}, {:.2}A, and was freed", encoder, duty, current),
+            ResponseBody::Event(protocol::Event::PowerButton) => println!("Power button pressed"),
+            ResponseBody::Event(protocol::Event::EStop) => println!("E-stop: the motors are stopped until it's cleared"),
+            other => eprintln!("Unexpected response {:?}", other),
+        }
+    }
+}
+
+fn configured_sample_rate(connection: &mut Connection) -> u32 {
+    match connection.request(RequestBody::SampleRate) {
+        ResponseBody::SampleRate { configured, .. } => configured,
+        other => panic!("Unexpected response {:?}", other)
+    }
+}
+
+fn capture(mut connection: Connection, trigger: protocol::CaptureTrigger, pre_trigger: u16, output: &str) {
+    let sample_rate = configured_sample_rate(&mut connection);
+    let (encoders, _) = encoders(&mut connection);
+    match capture::capture(&mut connection, trigger, pre_trigger, Duration::from_secs(10)) {
+        Ok(samples) => {
+            capture::save(Path::new(output), &samples, encoders as usize, sample_rate)
+                .expect("Failed to save the capture");
+            println!("Saved {} samples at {} Hz to {}", samples.len(), sample_rate, output);
+        },
+        Err(e) => eprintln!("{}", e),
+    }
+}
+
+fn main() {
+    let matches = App::new("quadrature-ping")
+    .version("0.1")
+    .about("Test connectivity to the microcontroller")
+    .author("David Ireland")
+    .arg(Arg::with_name("serial-device-path")
+    .short("d")
+    .long("device")
+    .help("Path to serial device, found by USB id if not given")
+    .takes_value(true))
+    .arg(Arg::with_name("serial-baud")
+    .short("b")
+    .long("baud")
+    .help("Serial baud rate")
+    .takes_value(true))
+    .arg(Arg::with_name("radio-bridge")
+    .short("r")
+    .long("radio")
+    .help("The device is a USB radio bridge, rather than the microcontroller itself"))
+    .subcommand(SubCommand::with_name("ping")
+    .about("Ping the microcontroller once a second (the default)"))
+    .subcommand(SubCommand::with_name("throughput")
+    .about("Measure the command link throughput")
+    .arg(Arg::with_name("count")
+    .short("n")
+    .long("count")
+    .help("Number of pings to send")
+    .takes_value(true)))
+    .subcommand(SubCommand::with_name("baud")
+    .about("Change the microcontroller's baud rate")
+    .arg(Arg::with_name("rate")
+    .help("The new baud rate")
+    .required(true))
+    .arg(Arg::with_name("persist")
+    .short("p")
+    .long("persist")
+    .help("Keep using the new rate after a reset")))
+    .subcommand(SubCommand::with_name("sample-rate")
+    .about("Show the encoder sample rate"))
+    .subcommand(SubCommand::with_name("encoders")
+    .about("List the board's encoders and motors"))
+    .subcommand(SubCommand::with_name("diagnose")
+    .about("Check the quality of the encoder signals")
+    .arg(Arg::with_name("compare-trigger")
+    .long("compare-trigger")
+    .help("Also measure the noise with the sampling free running and synchronised to the motor PWM")))
+    .subcommand(SubCommand::with_name("calibration")
+    .about("Show or change the encoders' sin/cos calibration")
+    .arg(Arg::with_name("encoder")
+    .short("e")
+    .long("encoder")
+    .help("Only this encoder, numbered from 0 (on a differential drive, 0 is the left and 1 the right)")
+    .takes_value(true))
+    .arg(Arg::with_name("freeze")
+    .long("freeze")
+    .help("Keep the current calibration")
+    .conflicts_with("learn"))
+    .arg(Arg::with_name("learn")
+    .long("learn")
+    .help("Keep updating the calibration from the signals"))
+    .arg(Arg::with_name("reset")
+    .long("reset")
+    .help("Forget the calibration and go back to the raw signals until the next fit"))
+    .arg(Arg::with_name("set")
+    .long("set")
+    .help("Use this calibration")
+    .value_names(&["A offset", "A amplitude", "B offset", "B amplitude", "phase error"])
+    .number_of_values(5)
+    .allow_hyphen_values(true)))
+    .subcommand(SubCommand::with_name("home")
+    .about("Turn a motor until its encoder's index pulse, and make that position 0")
+    .arg(Arg::with_name("encoder")
+    .help("The encoder, numbered from 0")
+    .required(true))
+    .arg(Arg::with_name("duty")
+    .short("u")
+    .long("duty")
+    .help("How hard to drive the motor, from -1 to 1; the sign is the direction")
+    .allow_hyphen_values(true)
+    .default_value("0.2"))
+    .arg(Arg::with_name("timeout")
+    .short("t")
+    .long("timeout")
+    .help("Seconds to look for the index")
+    .default_value("10")))
+    .subcommand(SubCommand::with_name("compare")
+    .about("Compare an encoder's analog decoding with the timer decoding a digital encoder on the same shaft")
+    .arg(Arg::with_name("encoder")
+    .short("e")
+    .long("encoder")
+    .help("The analog encoder, numbered from 0")
+    .default_value("0"))
+    .arg(Arg::with_name("interval")
+    .short("i")
+    .long("interval")
+    .help("Milliseconds between readings")
+    .default_value("100"))
+    .arg(Arg::with_name("count")
+    .short("n")
+    .long("count")
+    .help("Stop after this many readings")
+    .takes_value(true)))
+    .subcommand(SubCommand::with_name("reference-config")
+    .about("Show or change how the reference decoder's count compares with the analog encoder's cycles")
+    .arg(Arg::with_name("counts-per-cycle")
+    .long("counts-per-cycle")
+    .help("Timer counts to each analog cycle: four for each line of the digital encoder")
+    .takes_value(true))
+    .arg(Arg::with_name("reverse")
+    .long("reverse")
+    .help("The reference counts the other way from the analog encoder")
+    .possible_values(&["true", "false"])
+    .takes_value(true))
+    .arg(Arg::with_name("persist")
+    .short("p")
+    .long("persist")
+    .help("Keep the config after a reset")))
+    .subcommand(SubCommand::with_name("motor-config")
+    .about("Show or change which way round a motor and its encoder are")
+    .arg(Arg::with_name("encoder")
+    .help("The encoder, numbered from 0")
+    .required(true))
+    .arg(Arg::with_name("invert-output")
+    .long("invert-output")
+    .help("Positive duty drives the motor backwards")
+    .possible_values(&["true", "false"])
+    .takes_value(true))
+    .arg(Arg::with_name("reverse-encoder")
+    .long("reverse-encoder")
+    .help("The encoder counts backwards")
+    .possible_values(&["true", "false"])
+    .takes_value(true))
+    .arg(Arg::with_name("swap-channels")
+    .long("swap-channels")
+    .help("The encoder's A and B inputs are the other way round")
+    .possible_values(&["true", "false"])
+    .takes_value(true))
+    .arg(Arg::with_name("persist")
+    .short("p")
+    .long("persist")
+    .help("Keep the config after a reset")))
+    .subcommand(SubCommand::with_name("shaping")
+    .about("Show or change how the duty asked of a motor is shaped")
+    .arg(Arg::with_name("encoder")
+    .help("The motor's encoder, numbered from 0")
+    .required(true))
+    .arg(Arg::with_name("max-step")
+    .long("max-step")
+    .help("The most the duty can change by every 10ms")
+    .takes_value(true))
+    .arg(Arg::with_name("min-duty")
+    .long("min-duty")
+    .help("The duty that just gets the motor moving, which the smallest one is raised to")
+    .takes_value(true))
+    .arg(Arg::with_name("max-duty")
+    .long("max-duty")
+    .help("The duty that full speed is limited to")
+    .takes_value(true))
+    .arg(Arg::with_name("persist")
+    .short("p")
+    .long("persist")
+    .help("Keep the shaping after a reset")))
+    .subcommand(SubCommand::with_name("pwm")
+    .about("Show or change the motor PWM frequency and alignment")
+    .arg(Arg::with_name("frequency")
+    .short("f")
+    .long("frequency")
+    .help("In Hz, from 1000 to 25000")
+    .takes_value(true))
+    .arg(Arg::with_name("centre-aligned")
+    .long("centre-aligned")
+    .help("Centre each pulse in the period, which keeps switching noise away from the ADC")
+    .possible_values(&["true", "false"])
+    .takes_value(true))
+    .arg(Arg::with_name("persist")
+    .short("p")
+    .long("persist")
+    .help("Keep the config after a reset")))
+    .subcommand(SubCommand::with_name("sample-trigger")
+    .about("Show or change when the encoders are sampled, relative to the motor PWM")
+    .arg(Arg::with_name("synchronised")
+    .long("synchronised")
+    .help("Lock the PWM to the sampling; its frequency has to be a multiple of the sample rate")
+    .possible_values(&["true", "false"])
+    .takes_value(true))
+    .arg(Arg::with_name("offset")
+    .long("offset")
+    .help("Where in the PWM period to sample, from 0 to 1")
+    .takes_value(true))
+    .arg(Arg::with_name("persist")
+    .short("p")
+    .long("persist")
+    .help("Keep the trigger after a reset")))
+    .subcommand(SubCommand::with_name("telemetry")
+    .about("Print the encoders' positions and velocities and the supply voltage as CSV")
+    .arg(Arg::with_name("interval")
+    .short("i")
+    .long("interval")
+    .help("Milliseconds between readings")
+    .default_value("100"))
+    .arg(Arg::with_name("count")
+    .short("n")
+    .long("count")
+    .help("Stop after this many readings")
+    .takes_value(true)))
+    .subcommand(SubCommand::with_name("supply")
+    .about("Show or change the supply voltage measurement and limits")
+    .arg(Arg::with_name("divider")
+    .long("divider")
+    .help("Supply volts per volt at PA4")
+    .takes_value(true))
+    .arg(Arg::with_name("undervoltage")
+    .long("undervoltage")
+    .help("Volts below which the motors are freed")
+    .takes_value(true))
+    .arg(Arg::with_name("derate")
+    .long("derate")
+    .help("Volts below which the motors' duty is scaled down, to nothing at the undervoltage limit")
+    .takes_value(true))
+    .arg(Arg::with_name("overvoltage")
+    .long("overvoltage")
+    .help("Volts above which the motors are freed")
+    .takes_value(true))
+    .arg(Arg::with_name("nominal")
+    .long("nominal")
+    .help("Volts the duty is meant for, to scale it so speed doesn't depend on the battery; 0 for none")
+    .takes_value(true))
+    .arg(Arg::with_name("persist")
+    .short("p")
+    .long("persist")
+    .help("Keep the config after a reset")))
+    .subcommand(SubCommand::with_name("protection")
+    .about("Show or change the motor current limit and stall detection")
+    .arg(Arg::with_name("amps-per-volt")
+    .long("amps-per-volt")
+    .help("The current sensor on PA5's scale; 0 if there isn't one")
+    .takes_value(true))
+    .arg(Arg::with_name("zero-current")
+    .long("zero-current")
+    .help("Volts at PA5 with no current")
+    .takes_value(true))
+    .arg(Arg::with_name("current-limit")
+    .long("current-limit")
+    .help("Amps above which the duty is cut back; 0 for no limit")
+    .takes_value(true))
+    .arg(Arg::with_name("stall-duty")
+    .long("stall-duty")
+    .help("The least duty a stalled motor is driven at")
+    .takes_value(true))
+    .arg(Arg::with_name("stall-velocity")
+    .long("stall-velocity")
+    .help("Cycles a second below which a motor counts as not turning")
+    .takes_value(true))
+    .arg(Arg::with_name("stall-timeout")
+    .long("stall-timeout")
+    .help("Seconds a motor can be stalled before it's freed; 0 never to free it")
+    .takes_value(true))
+    .arg(Arg::with_name("persist")
+    .short("p")
+    .long("persist")
+    .help("Keep the config after a reset")))
+    .subcommand(SubCommand::with_name("servo")
+    .about("Show, move or change the config of a hobby servo")
+    .arg(Arg::with_name("servo")
+    .help("The servo, numbered from 0 on PB6 to 3 on PB9")
+    .required(true))
+    .arg(Arg::with_name("angle")
+    .help("Where to move it to, within the range of its config")
+    .allow_hyphen_values(true))
+    .arg(Arg::with_name("off")
+    .long("off")
+    .conflicts_with("angle")
+    .help("Stop its pulses, so it goes limp"))
+    .arg(Arg::with_name("min-pulse")
+    .long("min-pulse")
+    .help("The pulse width, in microseconds, at the min angle")
+    .takes_value(true))
+    .arg(Arg::with_name("max-pulse")
+    .long("max-pulse")
+    .help("The pulse width, in microseconds, at the max angle")
+    .takes_value(true))
+    .arg(Arg::with_name("min-angle")
+    .long("min-angle")
+    .help("The angle at the min pulse width")
+    .takes_value(true)
+    .allow_hyphen_values(true))
+    .arg(Arg::with_name("max-angle")
+    .long("max-angle")
+    .help("The angle at the max pulse width")
+    .takes_value(true)
+    .allow_hyphen_values(true))
+    .arg(Arg::with_name("persist")
+    .short("p")
+    .long("persist")
+    .help("Keep the config after a reset")))
+    .subcommand(SubCommand::with_name("power")
+    .about("Show the power switch, or cut the power")
+    .arg(Arg::with_name("off")
+    .long("off")
+    .value_name("SECONDS")
+    .help("Cut the power this long from now")
+    .takes_value(true))
+    .arg(Arg::with_name("cancel")
+    .long("cancel")
+    .conflicts_with("off")
+    .help("Don't cut the power after all")))
+    .subcommand(SubCommand::with_name("power-button")
+    .about("Wait for the power button, then shut down and have the board cut the power")
+    .arg(Arg::with_name("delay")
+    .long("delay")
+    .value_name("SECONDS")
+    .help("How long shutting down takes")
+    .default_value("20"))
+    .arg(Arg::with_name("command")
+    .long("command")
+    .help("What to run to shut down")
+    .default_value("systemctl poweroff")))
+    .subcommand(SubCommand::with_name("imu")
+    .about("Print the IMU's readings as CSV")
+    .arg(Arg::with_name("interval")
+    .short("i")
+    .long("interval")
+    .help("Milliseconds between readings")
+    .default_value("100"))
+    .arg(Arg::with_name("count")
+    .short("n")
+    .long("count")
+    .help("Stop after this many readings")
+    .takes_value(true)))
+    .subcommand(SubCommand::with_name("heading")
+    .about("Show the heading, or change how it's worked out")
+    .arg(Arg::with_name("set")
+    .long("set")
+    .value_name("DEGREES")
+    .help("Start the heading again from here")
+    .takes_value(true)
+    .allow_hyphen_values(true))
+    .arg(Arg::with_name("metres-per-cycle")
+    .long("metres-per-cycle")
+    .help("How far a wheel goes for each cycle of its encoder")
+    .takes_value(true))
+    .arg(Arg::with_name("track-width")
+    .long("track-width")
+    .help("Metres between the middles of the wheels")
+    .takes_value(true))
+    .arg(Arg::with_name("gyro-weight")
+    .long("gyro-weight")
+    .help("From 0, for the wheels alone, to 1, for the gyro alone")
+    .takes_value(true))
+    .arg(Arg::with_name("persist")
+    .short("p")
+    .long("persist")
+    .help("Keep the config after a reset")))
+    .subcommand(SubCommand::with_name("i2c")
+    .about("Write to and read from a device on the I2C2 expansion bus")
+    .arg(Arg::with_name("address")
+    .help("The device's 7 bit address, in hex")
+    .required(true))
+    .arg(Arg::with_name("write")
+    .help("Bytes to write, in hex")
+    .multiple(true))
+    .arg(Arg::with_name("read")
+    .short("r")
+    .long("read")
+    .help("How many bytes to read back")
+    .default_value("0")))
+    .subcommand(SubCommand::with_name("estop")
+    .about("Show, set up or clear the e-stop input on PA8")
+    .arg(Arg::with_name("clear")
+    .long("clear")
+    .help("Let the motors be driven again"))
+    .arg(Arg::with_name("enabled")
+    .long("enabled")
+    .help("Whether there's an e-stop wired up")
+    .possible_values(&["true", "false"])
+    .takes_value(true))
+    .arg(Arg::with_name("active-high")
+    .long("active-high")
+    .help("Whether the input is high when it's stopping the motors")
+    .possible_values(&["true", "false"])
+    .takes_value(true))
+    .arg(Arg::with_name("debounce")
+    .long("debounce")
+    .help("Milliseconds the input has to be active for; 0 to stop on the first edge")
+    .takes_value(true))
+    .arg(Arg::with_name("brake")
+    .long("brake")
+    .help("Brake the motors rather than letting them coast")
+    .possible_values(&["true", "false"])
+    .takes_value(true))
+    .arg(Arg::with_name("persist")
+    .short("p")
+    .long("persist")
+    .help("Keep the config after a reset")))
+    .subcommand(SubCommand::with_name("info")
+    .about("Show why the board last reset"))
+    .subcommand(SubCommand::with_name("events")
+    .about("Print events, like motors stalling, as they happen"))
+    .subcommand(SubCommand::with_name("capture")
+    .about("Record the raw encoder inputs, like an oscilloscope")
+    .arg(Arg::with_name("trigger")
+    .short("t")
+    .long("trigger")
+    .help("What starts the capture")
+    .possible_values(&["immediate", "rising", "falling", "glitch"])
+    .default_value("immediate"))
+    .arg(Arg::with_name("channel")
+    .short("c")
+    .long("channel")
+    .help("The input to trigger on: 2n is encoder n's A input and 2n + 1 its B input")
+    .default_value("0"))
+    .arg(Arg::with_name("level")
+    .short("l")
+    .long("level")
+    .help("The trigger level, or for a glitch the smallest jump between samples")
+    .default_value("2048"))
+    .arg(Arg::with_name("pre-trigger")
+    .long("pre")
+    .help("How many samples to keep from before the trigger")
+    .default_value("64"))
+    .arg(Arg::with_name("output")
+    .short("o")
+    .long("output")
+    .help("File to save to: WAV if it ends in .wav, otherwise CSV")
+    .default_value("capture.csv")))
+    .get_matches();
+
+    let (sender, receiver) = open(&matches);
+
+    match matches.subcommand() {
+        ("throughput", Some(sub)) => {
+            let count = sub.value_of("count").unwrap_or("1000").parse::<u32>().unwrap();
+            throughput(Connection::new(sender, receiver), count);
+        },
+        ("baud", Some(sub)) => {
+            let current = matches.value_of("serial-baud").unwrap_or("115200").parse::<u32>().unwrap();
+            let baud_rate = sub.value_of("rate").unwrap().parse::<u32>().unwrap();
+            set_baud_rate(Connection::new(sender, receiver), current, baud_rate, sub.is_present("persist"));
+        },
+        ("sample-rate", Some(_)) => sample_rate(Connection::new(sender, receiver)),
+        ("encoders", Some(_)) => {
+            let (encoders, motors) = encoders(&mut Connection::new(sender, receiver));
+            for encoder in 0..encoders {
+                println!("Encoder {}{}", encoder, if encoder < motors { " with a motor" } else { "" });
+            }
+        },
+        ("diagnose", Some(sub)) => diagnose(Connection::new(sender, receiver), sub.is_present("compare-trigger")),
+        ("calibration", Some(sub)) => {
+            let only = sub.value_of("encoder").map(|encoder| encoder.parse::<u8>().unwrap());
+            calibrate(Connection::new(sender, receiver), only, sub);
+        },
+        ("home", Some(sub)) => {
+            let encoder = sub.value_of("encoder").unwrap().parse::<u8>().unwrap();
+            let duty = sub.value_of("duty").unwrap().parse::<f32>().unwrap();
+            let timeout = sub.value_of("timeout").unwrap().parse::<f32>().unwrap();
+            home(Connection::new(sender, receiver), encoder, duty, (timeout * 1000.0) as u32);
+        },
+        ("compare", Some(sub)) => {
+            let encoder = sub.value_of("encoder").unwrap().parse::<u8>().unwrap();
+            let interval = sub.value_of("interval").unwrap().parse::<u64>().unwrap();
+            let count = sub.value_of("count").map(|count| count.parse::<u32>().unwrap());
+            compare(Connection::new(sender, receiver), encoder, Duration::from_millis(interval), count);
+        },
+        ("reference-config", Some(sub)) => reference_config(Connection::new(sender, receiver), sub),
+        ("motor-config", Some(sub)) => {
+            let encoder = sub.value_of("encoder").unwrap().parse::<u8>().unwrap();
+            motor_config(Connection::new(sender, receiver), encoder, sub);
+        },
+        ("shaping", Some(sub)) => {
+            let encoder = sub.value_of("encoder").unwrap().parse::<u8>().unwrap();
+            shaping(Connection::new(sender, receiver), encoder, sub);
+        },
+        ("pwm", Some(sub)) => pwm(Connection::new(sender, receiver), sub),
+        ("sample-trigger", Some(sub)) => sample_trigger(Connection::new(sender, receiver), sub),
+        ("telemetry", Some(sub)) => {
+            let interval = sub.value_of("interval").unwrap().parse::<u64>().unwrap();
+            let count = sub.value_of("count").map(|count| count.parse::<u32>().unwrap());
+            telemetry(Connection::new(sender, receiver), Duration::from_millis(interval), count);
+        },
+        ("supply", Some(sub)) => supply(Connection::new(sender, receiver), sub),
+        ("protection", Some(sub)) => protection(Connection::new(sender, receiver), sub),
+        ("servo", Some(sub)) => {
+            let servo_number = sub.value_of("servo").unwrap().parse::<u8>().unwrap();
+            servo(Connection::new(sender, receiver), servo_number, sub);
+        },
+        ("power", Some(sub)) => power(Connection::new(sender, receiver), sub),
+        ("power-button", Some(sub)) => {
+            let delay = sub.value_of("delay").unwrap().parse::<f32>().unwrap();
+            power_button(Connection::new(sender, receiver), Duration::from_secs_f32(delay), sub.value_of("command").unwrap());
+        },
+        ("imu", Some(sub)) => {
+            let interval = sub.value_of("interval").unwrap().parse::<u64>().unwrap();
+            let count = sub.value_of("count").map(|count| count.parse::<u32>().unwrap());
+            imu(Connection::new(sender, receiver), Duration::from_millis(interval), count);
+        },
+        ("heading", Some(sub)) => heading(Connection::new(sender, receiver), sub),
+        ("i2c", Some(sub)) => {
+            let address = parse_byte(sub.value_of("address").unwrap());
+            let write: Vec<u8> = sub.values_of("write").map_or(Vec::new(), |values| values.map(parse_byte).collect());
+            let read = sub.value_of("read").unwrap().parse::<u8>().unwrap();
+            i2c(Connection::new(sender, receiver), address, &write, read);
+        },
+        ("estop", Some(sub)) => estop(Connection::new(sender, receiver), sub),
+        ("info", Some(_)) => device_info(Connection::new(sender, receiver)),
+        ("events", Some(_)) => events(Connection::new(sender, receiver)),
+        ("capture", Some(sub)) => {
+            let trigger = capture::trigger(
+                sub.value_of("trigger").unwrap(),
+                sub.value_of("channel").unwrap().parse::<u8>().unwrap(),
+                sub.value_of("level").unwrap().parse::<u16>().unwrap()).unwrap();
+            let pre_trigger = sub.value_of("pre-trigger").unwrap().parse::<u16>().unwrap();
+            capture(Connection::new(sender, receiver), trigger, pre_trigger, sub.value_of("output").unwrap());
+        },
+        _ => ping(sender, receiver),
+    }
 }
@@ -0,0 +1,328 @@
+use libm::{ asinf, cosf, fabsf, sinf, sqrtf };
+use core::f32::consts::PI;
+use protocol::Calibration;
+
+// Only every DECIMATE'th sample goes into the fit, which is plenty to trace
+// the ellipse and keeps the sums cheap without an FPU
+const DECIMATE: u32 = 16;
+// Points in each fit: about 0.8s at 10kHz
+const POINTS: u32 = 512;
+// Centre and scale of the ADC range, to keep the sums near 1 in f32
+const CENTRE: f32 = 2048.0;
+const SCALE: f32 = 2048.0;
+// Fits that come out outside these are thrown away, and so are calibrations
+// from the host
+const MIN_AMPLITUDE: f32 = 50.0;
+const MAX_PHASE: f32 = 45.0;
+
+/// Corrects a pair of sin/cos signals for offset, gain and quadrature phase
+/// error, giving the sine and cosine of the encoder's phase, each from
+/// -1 to 1. The A input is the sine, and the correction takes its phase error
+/// out relative to B.
+#[derive(Clone, Copy)]
+pub struct Correction {
+    calibration: Calibration,
+    sin_phase: f32,
+    cos_phase: f32,
+}
+
+impl Correction {
+    /// None if the calibration isn't one a fit could give, as it would make
+    /// nonsense or infinities of the signals.
+    pub fn new(calibration: Calibration) -> Option<Self> {
+        if !plausible(&calibration) {
+            return None;
+        }
+        let phase = calibration.phase_error * PI / 180.0;
+        Some(Correction {
+            calibration,
+            sin_phase: sinf(phase),
+            cos_phase: cosf(phase),
+        })
+    }
+
+    pub fn calibration(&self) -> Calibration {
+        self.calibration
+    }
+
+    pub fn apply(&self, values: (f32, f32)) -> (f32, f32) {
+        let p = (values.0 - self.calibration.offset_a) / self.calibration.amplitude_a;
+        let q = (values.1 - self.calibration.offset_b) / self.calibration.amplitude_b;
+        // p = sin(t + e), q = cos(t), so sin(t) = (p - q sin(e)) / cos(e)
+        ((p - q * self.sin_phase) / self.cos_phase, q)
+    }
+}
+
+/// Least squares fit of the conic A x² + B xy + C y² + D x + E y = 1 to the
+/// (A, B) Lissajous figure, which for a pair of sin/cos signals is an ellipse
+/// whose centre, axes and tilt give the offsets, amplitudes and phase error.
+/// Unlike the moments, this doesn't care how fast or evenly the encoder
+/// turns, as long as it goes most of the way round the ellipse.
+pub struct EllipseFit {
+    // The normal equations: the sums of f fᵀ and of f, with f the feature
+    // vector (x², xy, y², x, y)
+    products: [[f32; 5]; 5],
+    sums: [f32; 5],
+    // Extent of the points, to tell whether they go round far enough
+    min: (f32, f32),
+    max: (f32, f32),
+    samples: u32,
+    points: u32,
+}
+
+impl EllipseFit {
+    pub fn new() -> Self {
+        EllipseFit {
+            products: [[0.0; 5]; 5],
+            sums: [0.0; 5],
+            min: (1.0, 1.0),
+            max: (-1.0, -1.0),
+            samples: 0,
+            points: 0,
+        }
+    }
+
+    /// Adds a raw pair of samples, returning a new calibration whenever
+    /// there have been enough to fit one that looks sensible.
+    pub fn update(&mut self, values: (f32, f32)) -> Option<Calibration> {
+        self.samples += 1;
+        if !self.samples.is_multiple_of(DECIMATE) {
+            return None;
+        }
+
+        let x = (values.0 - CENTRE) / SCALE;
+        let y = (values.1 - CENTRE) / SCALE;
+        let features = [x * x, x * y, y * y, x, y];
+        for i in 0..5 {
+            for j in i..5 {
+                self.products[i][j] += features[i] * features[j];
+            }
+            self.sums[i] += features[i];
+        }
+        self.min = (self.min.0.min(x), self.min.1.min(y));
+        self.max = (self.max.0.max(x), self.max.1.max(y));
+        self.points += 1;
+
+        if self.points < POINTS {
+            return None;
+        }
+        let calibration = self.fit();
+        *self = EllipseFit::new();
+        calibration
+    }
+
+    fn fit(&self) -> Option<Calibration> {
+        let products = self.products;
+        let mut matrix = products;
+        for (i, row) in matrix.iter_mut().enumerate() {
+            for (j, value) in row.iter_mut().enumerate().take(i) {
+                *value = products[j][i];
+            }
+        }
+        let [a, b, c, d, e] = solve(matrix, self.sums)?;
+
+        // Centre, where the gradient is zero
+        let determinant = 4.0 * a * c - b * b;
+        if determinant <= 0.0 {
+            // A hyperbola or a parabola: the points are nowhere near round
+            return None;
+        }
+        let x0 = (b * e - 2.0 * c * d) / determinant;
+        let y0 = (b * d - 2.0 * a * e) / determinant;
+        // Moved to the centre the conic is A u² + B uv + C v² = g
+        let g = 1.0 - (d * x0 + e * y0) / 2.0;
+        if g <= 0.0 || a <= 0.0 || c <= 0.0 {
+            return None;
+        }
+
+        // With p = u / amplitude_a = sin(t + e) and q = v / amplitude_b =
+        // cos(t), p² + q² - 2 sin(e) pq = cos²(e)
+        let (a, b, c) = (a / g, b / g, c / g);
+        let sin_phase = -b / (2.0 * sqrtf(a * c));
+        if fabsf(sin_phase) >= 1.0 {
+            return None;
+        }
+        let cos_phase = sqrtf(1.0 - sin_phase * sin_phase);
+        let amplitude_a = 1.0 / (cos_phase * sqrtf(a));
+        let amplitude_b = 1.0 / (cos_phase * sqrtf(c));
+
+        // The points must cover at least half of each axis
+        if self.max.0 - self.min.0 < amplitude_a || self.max.1 - self.min.1 < amplitude_b {
+            return None;
+        }
+
+        let calibration = Calibration {
+            offset_a: x0 * SCALE + CENTRE,
+            offset_b: y0 * SCALE + CENTRE,
+            amplitude_a: amplitude_a * SCALE,
+            amplitude_b: amplitude_b * SCALE,
+            phase_error: asinf(sin_phase) * 180.0 / PI,
+        };
+        if !plausible(&calibration) {
+            return None;
+        }
+        Some(calibration)
+    }
+}
+
+impl Default for EllipseFit {
+    fn default() -> Self {
+        EllipseFit::new()
+    }
+}
+
+// NaNs fail every comparison, so they're caught here too
+fn plausible(calibration: &Calibration) -> bool {
+    calibration.offset_a.is_finite()
+        && calibration.offset_b.is_finite()
+        && calibration.amplitude_a.is_finite()
+        && calibration.amplitude_b.is_finite()
+        && calibration.amplitude_a >= MIN_AMPLITUDE
+        && calibration.amplitude_b >= MIN_AMPLITUDE
+        && fabsf(calibration.phase_error) <= MAX_PHASE
+}
+
+// Gaussian elimination with partial pivoting. None if the matrix is close to
+// singular, which is what you get if the encoder hasn't moved.
+fn solve(mut matrix: [[f32; 5]; 5], mut vector: [f32; 5]) -> Option<[f32; 5]> {
+    for column in 0..5 {
+        let mut pivot = column;
+        for row in column + 1..5 {
+            if fabsf(matrix[row][column]) > fabsf(matrix[pivot][column]) {
+                pivot = row;
+            }
+        }
+        if fabsf(matrix[pivot][column]) < 1e-6 {
+            return None;
+        }
+        matrix.swap(column, pivot);
+        vector.swap(column, pivot);
+
+        for row in column + 1..5 {
+            let factor = matrix[row][column] / matrix[column][column];
+            let pivot_row = matrix[column];
+            for (value, pivot) in matrix[row][column..].iter_mut().zip(&pivot_row[column..]) {
+                *value -= factor * pivot;
+            }
+            vector[row] -= factor * vector[column];
+        }
+    }
+
+    let mut solution = [0.0; 5];
+    for row in (0..5).rev() {
+        let mut sum = vector[row];
+        for k in row + 1..5 {
+            sum -= matrix[row][k] * solution[k];
+        }
+        solution[row] = sum / matrix[row][row];
+    }
+    Some(solution)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const TRUE: Calibration = Calibration {
+        offset_a: 2000.0,
+        offset_b: 2100.0,
+        amplitude_a: 900.0,
+        amplitude_b: 700.0,
+        phase_error: 8.0,
+    };
+
+    // The raw signals for the encoder at `t` radians
+    fn signals(calibration: &Calibration, t: f32) -> (f32, f32) {
+        let phase = calibration.phase_error * PI / 180.0;
+        (calibration.offset_a + calibration.amplitude_a * sinf(t + phase),
+            calibration.offset_b + calibration.amplitude_b * cosf(t))
+    }
+
+    // Feeds in enough samples for a fit, turning `turns` times
+    fn fit(turns: f32, signals: impl Fn(f32) -> (f32, f32)) -> Option<Calibration> {
+        let mut fit = EllipseFit::new();
+        let samples = DECIMATE * POINTS;
+        let mut calibration = None;
+        for i in 0..samples {
+            let t = i as f32 / samples as f32 * turns * 2.0 * PI;
+            calibration = calibration.or(fit.update(signals(t)));
+        }
+        calibration
+    }
+
+    fn close(a: f32, b: f32, tolerance: f32) -> bool {
+        fabsf(a - b) <= tolerance
+    }
+
+    #[test]
+    fn solves_a_linear_system() {
+        let mut matrix = [[0.0; 5]; 5];
+        for i in 0..5 {
+            matrix[i][i] = 2.0;
+            matrix[i][(i + 1) % 5] = 1.0;
+        }
+        let expected = [1.0, -2.0, 3.0, 0.5, -1.5];
+        let mut vector = [0.0; 5];
+        for i in 0..5 {
+            for j in 0..5 {
+                vector[i] += matrix[i][j] * expected[j];
+            }
+        }
+        let solution = solve(matrix, vector).unwrap();
+        for (got, want) in solution.iter().zip(expected.iter()) {
+            assert!(close(*got, *want, 1e-4), "{:?}", solution);
+        }
+    }
+
+    #[test]
+    fn singular_system_has_no_solution() {
+        let mut matrix = [[1.0; 5]; 5];
+        matrix[0] = [0.0; 5];
+        assert_eq!(solve(matrix, [1.0; 5]), None);
+    }
+
+    #[test]
+    fn fits_the_signals() {
+        let calibration = fit(3.0, |t| signals(&TRUE, t)).unwrap();
+        assert!(close(calibration.offset_a, TRUE.offset_a, 5.0), "{:?}", calibration);
+        assert!(close(calibration.offset_b, TRUE.offset_b, 5.0), "{:?}", calibration);
+        assert!(close(calibration.amplitude_a, TRUE.amplitude_a, 5.0), "{:?}", calibration);
+        assert!(close(calibration.amplitude_b, TRUE.amplitude_b, 5.0), "{:?}", calibration);
+        assert!(close(calibration.phase_error, TRUE.phase_error, 0.5), "{:?}", calibration);
+    }
+
+    #[test]
+    fn no_fit_while_still() {
+        assert_eq!(fit(0.0, |t| signals(&TRUE, t)), None);
+    }
+
+    #[test]
+    fn no_fit_of_a_small_arc() {
+        assert_eq!(fit(0.1, |t| signals(&TRUE, t)), None);
+    }
+
+    #[test]
+    fn no_fit_without_amplitude() {
+        let flat = Calibration { amplitude_a: 0.0, amplitude_b: 0.0, ..TRUE };
+        assert_eq!(fit(3.0, |t| signals(&flat, t)), None);
+    }
+
+    #[test]
+    fn correction_undoes_the_errors() {
+        let correction = Correction::new(TRUE).unwrap();
+        for i in 0..16 {
+            let t = i as f32 * PI / 8.0;
+            let (sin, cos) = correction.apply(signals(&TRUE, t));
+            assert!(close(sin, sinf(t), 1e-3) && close(cos, cosf(t), 1e-3), "{} {} {}", t, sin, cos);
+        }
+    }
+
+    #[test]
+    fn implausible_calibrations_are_refused() {
+        assert!(Correction::new(Calibration { amplitude_a: 0.0, ..TRUE }).is_none());
+        assert!(Correction::new(Calibration { amplitude_b: -700.0, ..TRUE }).is_none());
+        assert!(Correction::new(Calibration { offset_a: f32::NAN, ..TRUE }).is_none());
+        assert!(Correction::new(Calibration { amplitude_a: f32::INFINITY, ..TRUE }).is_none());
+        assert!(Correction::new(Calibration { phase_error: 60.0, ..TRUE }).is_none());
+    }
+}
@@ -0,0 +1,310 @@
+use core::cmp::{ min, max };
+use core::f32::consts::PI;
+use libm::atan2f;
+use protocol::{ Calibration, EncoderHealth };
+use crate::health::SignalMonitor;
+use crate::calibration::{ Correction, EllipseFit };
+
+pub trait Avg {
+    fn avg(a: Self, b: Self) -> Self;
+}
+
+pub trait Sample: Ord + Copy + Avg + Into<f32> { }
+
+impl Avg for u16 {
+    fn avg(a: u16, b: u16) -> u16 { (a+b)/2 }
+}
+
+impl Sample for u16 { }
+
+struct MinMax<S: Sample>  {
+    min: S,
+    max: S,
+    zero: S,
+    // Until there's been a sample, zero is only a guess, and the extremes
+    // start from the first one wherever it is
+    seeded: bool,
+}
+
+impl <S: Sample> MinMax<S> {
+
+    pub fn new(zero: S) -> Self {
+        MinMax { min: zero, max: zero, zero, seeded: false }
+    }
+
+    pub fn update(&mut self, value: S) -> bool {
+        if !self.seeded {
+            self.min = value;
+            self.max = value;
+            self.zero = value;
+            self.seeded = true;
+        } else if self.max < value || self.min > value {
+            self.min = min(self.min, value);
+            self.max = max(self.max, value);
+            self.zero = Avg::avg(self.min, self.max);
+        }
+
+        value > self.zero
+    }
+
+    // Where value is between min and max, from -1 to 1
+    pub fn normalise(&self, value: S) -> f32 {
+        let (min, max, zero): (f32, f32, f32) = (self.min.into(), self.max.into(), self.zero.into());
+        if max <= min {
+            return 0.0;
+        }
+        (value.into() - zero) * 2.0 / (max - min)
+    }
+}
+
+pub struct AnalogRotaryEncoder<S: Sample> {
+    in1: MinMax<S>,
+    in2: MinMax<S>,
+    counter: u64,
+    in1_prev_value: bool,
+    // Whole cycles, counted at the rising edges of in1
+    cycles: i64,
+    last: (S, S),
+    samples_since_edge: u32,
+    // Samples between the last two edges, negative going backwards, 0 if
+    // we haven't seen two yet
+    period: i32,
+    // How long after the sample trigger this encoder's pair is converted, in
+    // sample periods
+    delay: f32,
+    monitor: SignalMonitor,
+    fit: EllipseFit,
+    // Until there's a calibration, the inputs are scaled by their extremes
+    correction: Option<Correction>,
+    frozen: bool,
+    // Where position() counts from
+    origin: f32,
+    index_active: bool,
+    // The position at the last index edge, until it's cleared
+    index: Option<f32>,
+    // 1, or -1 if the encoder counts the wrong way
+    direction: f32,
+    // A and B are the wrong way round
+    swapped: bool,
+}
+
+impl <S: Sample> AnalogRotaryEncoder<S> {
+    /// `zero` is where the inputs are taken to sit before the first sample,
+    /// the middle of the ADC's range
+    pub fn new(zero: S, delay: f32) -> Self {
+        AnalogRotaryEncoder {
+            in1: MinMax::new(zero),
+            in2: MinMax::new(zero),
+            counter: 0,
+            in1_prev_value: false,
+            cycles: 0,
+            last: (zero, zero),
+            samples_since_edge: 0,
+            period: 0,
+            delay,
+            monitor: SignalMonitor::new(),
+            fit: EllipseFit::new(),
+            correction: None,
+            frozen: false,
+            origin: 0.0,
+            index_active: false,
+            index: None,
+            direction: 1.0,
+            swapped: false,
+        }
+    }
+
+    pub fn update(&mut self, values: (S, S)) {
+        let values = match self.swapped {
+            false => values,
+            true => (values.1, values.0),
+        };
+        self.counter += 1;
+        self.last = values;
+        let raw = (values.0.into(), values.1.into());
+        self.monitor.update(raw.0, raw.1);
+        if let Some(calibration) = self.fit.update(raw) {
+            if !self.frozen {
+                self.correction = Correction::new(calibration);
+            }
+        }
+        self.samples_since_edge = self.samples_since_edge.saturating_add(1);
+        self.in1.update(values.0);
+        self.in2.update(values.1);
+        let (sin, cos) = self.normalise(values);
+        let in1_next_value = sin > 0.0;
+        if !self.in1_prev_value && in1_next_value {
+            let step = match cos > 0.0 { true => 1, false => -1 };
+            self.cycles += step;
+            self.period = step as i32 * self.samples_since_edge as i32;
+            self.samples_since_edge = 0;
+        }
+    
+        self.in1_prev_value = in1_next_value;
+    }
+
+    /// Cycles per sample period, from the time between the last two edges,
+    /// or since the last edge if that's longer.
+    pub fn velocity(&self) -> f32 {
+        if self.period == 0 {
+            return 0.0;
+        }
+        let samples = max(self.period.unsigned_abs(), self.samples_since_edge);
+        self.direction * self.period.signum() as f32 / samples as f32
+    }
+
+    /// The position in cycles, interpolated between edges from the phase of
+    /// the last pair of samples. The pair is taken at the same instant, but
+    /// later than the sample trigger by `delay`, so that is taken back out
+    /// to give the position at the trigger, the same instant as every other
+    /// encoder.
+    pub fn position(&self) -> f32 {
+        let (a, b) = self.normalise(self.last);
+        // 0 at the rising edge of in1, where the cycle count changes
+        let mut fraction = atan2f(a, b) / (2.0 * PI);
+        if fraction < 0.0 {
+            fraction += 1.0;
+        }
+        self.direction * (self.cycles as f32 + fraction) - self.velocity() * self.delay - self.origin
+    }
+
+    /// Latches the position when the index pulse starts. Call after
+    /// `update` with the index level from the same sample.
+    pub fn update_index(&mut self, active: bool) {
+        if active && !self.index_active {
+            self.index = Some(self.position());
+        }
+        self.index_active = active;
+    }
+
+    /// The position when the index last started, since `clear_index`
+    pub fn index(&self) -> Option<f32> {
+        self.index
+    }
+
+    pub fn clear_index(&mut self) {
+        self.index = None;
+    }
+
+    /// Makes `position` the new 0
+    pub fn set_origin(&mut self, position: f32) {
+        self.origin += position;
+        self.index = self.index.map(|index| index - position);
+    }
+
+    pub fn health(&self) -> EncoderHealth {
+        self.monitor.health()
+    }
+
+    pub fn calibration(&self) -> Option<Calibration> {
+        self.correction.map(|correction| correction.calibration())
+    }
+
+    /// Returns false, leaving the calibration as it was, if it isn't one a
+    /// fit could have given
+    pub fn set_calibration(&mut self, calibration: Option<Calibration>) -> bool {
+        match calibration {
+            Some(calibration) => match Correction::new(calibration) {
+                Some(correction) => self.correction = Some(correction),
+                None => return false,
+            },
+            None => self.correction = None,
+        }
+        true
+    }
+
+    pub fn frozen(&self) -> bool {
+        self.frozen
+    }
+
+    /// While frozen, new fits are thrown away rather than replacing the
+    /// calibration.
+    pub fn freeze(&mut self, frozen: bool) {
+        self.frozen = frozen;
+    }
+
+    pub fn reversed(&self) -> bool {
+        self.direction < 0.0
+    }
+
+    /// Counts the other way. The position jumps, so it will want homing
+    /// again.
+    pub fn set_reversed(&mut self, reversed: bool) {
+        self.direction = if reversed { -1.0 } else { 1.0 };
+    }
+
+    pub fn swapped(&self) -> bool {
+        self.swapped
+    }
+
+    /// Swaps the A and B inputs over. The calibration and the extremes are
+    /// for the old way round, so they're started again.
+    pub fn set_swapped(&mut self, swapped: bool) {
+        if swapped != self.swapped {
+            self.swapped = swapped;
+            let zero = Avg::avg(self.in1.zero, self.in2.zero);
+            self.in1 = MinMax::new(zero);
+            self.in2 = MinMax::new(zero);
+            self.correction = None;
+            self.fit = EllipseFit::new();
+            self.monitor = SignalMonitor::new();
+        }
+    }
+
+    // The inputs as the sine and cosine of the phase, each from -1 to 1
+    fn normalise(&self, values: (S, S)) -> (f32, f32) {
+        match self.correction {
+            Some(correction) => correction.apply((values.0.into(), values.1.into())),
+            None => (self.in1.normalise(values.0), self.in2.normalise(values.1)),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use libm::{ cosf, fabsf, sinf };
+
+    // Samples in a turn of the encoder
+    const TURN: u32 = 64;
+
+    // A = sin(t) and B = cos(t), as the 12 bit ADC gives them, for turns
+    // from `start`
+    fn turn(encoder: &mut AnalogRotaryEncoder<u16>, centre: f32, amplitude: f32, start: f32, turns: f32) {
+        let samples = (turns * TURN as f32) as u32;
+        for i in 0..samples {
+            let t = (start + i as f32 / TURN as f32) * 2.0 * PI;
+            encoder.update(((centre + amplitude * sinf(t)) as u16, (centre + amplitude * cosf(t)) as u16));
+        }
+    }
+
+    fn follows(centre: f32, amplitude: f32) {
+        let mut encoder = AnalogRotaryEncoder::new(2048, 0.0);
+        // A turn to find the extremes
+        turn(&mut encoder, centre, amplitude, 0.0, 1.25);
+        let start = encoder.position();
+        turn(&mut encoder, centre, amplitude, 1.25, 2.0);
+        let fraction = start - (start as i32) as f32;
+        assert!(fabsf(fraction - 0.25) < 0.02, "{}", start);
+        assert!(fabsf(encoder.position() - start - 2.0) < 0.02, "{}", encoder.position() - start);
+        assert!(fabsf(encoder.velocity() - 1.0 / TURN as f32) < 0.001, "{}", encoder.velocity());
+    }
+
+    #[test]
+    fn follows_signals_around_mid_scale() {
+        follows(2048.0, 1500.0);
+    }
+
+    #[test]
+    fn follows_signals_away_from_mid_scale() {
+        follows(3000.0, 800.0);
+        follows(1000.0, 600.0);
+    }
+
+    #[test]
+    fn nothing_before_a_sample() {
+        let encoder = AnalogRotaryEncoder::<u16>::new(2048, 0.0);
+        assert_eq!(encoder.velocity(), 0.0);
+        assert!(encoder.position().is_finite());
+    }
+}
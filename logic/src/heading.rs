@@ -0,0 +1,255 @@
+use libm::fabsf;
+use protocol::{ Heading, HeadingConfig };
+
+// The control period, in seconds
+const PERIOD: f32 = crate::CONTROL_PERIOD_MS as f32 / 1000.0;
+const DEGREES_PER_RADIAN: f32 = 57.295_78;
+// The wheels count as still when neither moves further than this in a
+// period, in cycles...
+const STILL: f32 = 0.001;
+// ...and the gyro's bias is only learned from readings slower than this, in
+// degrees a second, in case the robot's been picked up and turned
+const MAX_BIAS: f32 = 5.0;
+// Each still period moves the bias this far towards the reading
+const BIAS_SMOOTHING: f32 = 0.01;
+// Anything past these, in metres, is a mistake rather than a robot
+const MAX_METRES_PER_CYCLE: f32 = 1.0;
+const MIN_TRACK_WIDTH: f32 = 0.01;
+const MAX_TRACK_WIDTH: f32 = 10.0;
+
+/// Fuses the heading from the wheels of a differential drive with the
+/// gyro's, as `HeadingConfig` describes.
+pub struct HeadingFilter {
+    config: HeadingConfig,
+    heading: Heading,
+    // The left and right wheels' positions last period, in cycles
+    wheels: Option<(f32, f32)>,
+}
+
+impl HeadingFilter {
+    pub fn new() -> Self {
+        HeadingFilter {
+            config: HeadingConfig::default(),
+            heading: Heading::default(),
+            wheels: None,
+        }
+    }
+
+    pub fn config(&self) -> HeadingConfig {
+        self.config
+    }
+
+    /// Returns false if it doesn't make sense. The track width can be 0 for
+    /// no odometry.
+    pub fn configure(&mut self, config: HeadingConfig) -> bool {
+        let track_width = config.track_width == 0.0
+            || (MIN_TRACK_WIDTH..=MAX_TRACK_WIDTH).contains(&config.track_width);
+        if !(config.metres_per_cycle.is_finite()
+            && config.track_width.is_finite()
+            && config.gyro_weight.is_finite()
+            && (0.0..=MAX_METRES_PER_CYCLE).contains(&config.metres_per_cycle)
+            && track_width
+            && (0.0..=1.0).contains(&config.gyro_weight)) {
+            return false;
+        }
+        self.config = config;
+        true
+    }
+
+    pub fn heading(&self) -> Heading {
+        self.heading
+    }
+
+    /// Starts again from `heading`, keeping the gyro's bias
+    pub fn set(&mut self, heading: f32) {
+        self.heading = Heading {
+            heading,
+            odometry: heading,
+            gyro: heading,
+            gyro_bias: self.heading.gyro_bias,
+        };
+    }
+
+    /// Call every control period with the gyro's Z rotation, in degrees a
+    /// second, and the left and right wheels' positions, in cycles, if
+    /// there are any.
+    pub fn update(&mut self, gyro: Option<f32>, wheels: Option<(f32, f32)>) {
+        let c = self.config;
+        let moved = match (wheels, self.wheels) {
+            (Some((left, right)), Some((last_left, last_right))) => Some((left - last_left, right - last_right)),
+            _ => None,
+        };
+        self.wheels = wheels;
+
+        let h = &mut self.heading;
+        let still = moved.is_some_and(|(left, right)| fabsf(left) < STILL && fabsf(right) < STILL);
+        if let Some(rate) = gyro {
+            if still && fabsf(rate) < MAX_BIAS {
+                h.gyro_bias += (rate - h.gyro_bias) * BIAS_SMOOTHING;
+            }
+        }
+        let turned = gyro.map(|rate| (rate - h.gyro_bias) * PERIOD);
+
+        let odometry = c.metres_per_cycle > 0.0 && c.track_width > 0.0;
+        if let (Some((left, right)), true) = (moved, odometry) {
+            h.odometry += (right - left) * c.metres_per_cycle / c.track_width * DEGREES_PER_RADIAN;
+        }
+        if let Some(turned) = turned {
+            h.gyro += turned;
+        }
+
+        h.heading = match (turned, odometry && wheels.is_some()) {
+            (Some(turned), true) => {
+                let heading = h.heading + turned;
+                heading + (h.odometry - heading) * (1.0 - c.gyro_weight)
+            },
+            (Some(turned), false) => h.heading + turned,
+            (None, true) => h.odometry,
+            (None, false) => h.heading,
+        };
+    }
+}
+
+impl Default for HeadingFilter {
+    fn default() -> Self {
+        HeadingFilter::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const CONFIG: HeadingConfig = HeadingConfig {
+        metres_per_cycle: 0.1,
+        track_width: 0.5,
+        gyro_weight: 0.98,
+    };
+    // Periods in a second
+    const SECOND: u32 = (1.0 / PERIOD) as u32;
+
+    fn filter(config: HeadingConfig) -> HeadingFilter {
+        let mut filter = HeadingFilter::new();
+        assert!(filter.configure(config));
+        filter
+    }
+
+    // How far each wheel goes the other way for the wheels to turn the
+    // robot `degrees`
+    fn wheel_cycles(degrees: f32) -> f32 {
+        degrees / DEGREES_PER_RADIAN * CONFIG.track_width / CONFIG.metres_per_cycle / 2.0
+    }
+
+    fn close(a: f32, b: f32) -> bool {
+        fabsf(a - b) < 0.1
+    }
+
+    #[test]
+    fn follows_the_gyro_alone() {
+        let mut filter = filter(CONFIG);
+        for _ in 0..SECOND {
+            filter.update(Some(90.0), None);
+        }
+        let heading = filter.heading();
+        assert!(close(heading.heading, 90.0) && close(heading.gyro, 90.0), "{:?}", heading);
+        assert_eq!(heading.odometry, 0.0);
+    }
+
+    #[test]
+    fn follows_the_wheels_alone() {
+        let mut filter = filter(CONFIG);
+        let step = wheel_cycles(90.0) / SECOND as f32;
+        for i in 0..=SECOND {
+            let turned = i as f32 * step;
+            filter.update(None, Some((-turned, turned)));
+        }
+        let heading = filter.heading();
+        assert!(close(heading.heading, 90.0) && close(heading.odometry, 90.0), "{:?}", heading);
+    }
+
+    #[test]
+    fn the_wheels_pull_the_gyro_round() {
+        let mut filter = filter(CONFIG);
+        let turned = wheel_cycles(90.0);
+        filter.update(Some(0.0), Some((0.0, 0.0)));
+        filter.update(Some(0.0), Some((-turned, turned)));
+        // Mostly the gyro's, to start with...
+        assert!(filter.heading().heading < 5.0, "{:?}", filter.heading());
+        for _ in 0..SECOND * 5 {
+            filter.update(Some(0.0), Some((-turned, turned)));
+        }
+        // ...but the wheels win in the end
+        assert!(close(filter.heading().heading, 90.0), "{:?}", filter.heading());
+    }
+
+    #[test]
+    fn learns_the_gyro_bias_standing_still() {
+        let mut filter = filter(CONFIG);
+        for _ in 0..SECOND * 10 {
+            filter.update(Some(1.0), Some((0.0, 0.0)));
+        }
+        let heading = filter.heading();
+        assert!(close(heading.gyro_bias, 1.0), "{:?}", heading);
+        // So it's stopped drifting
+        let gyro = heading.gyro;
+        filter.update(Some(1.0), Some((0.0, 0.0)));
+        assert!(fabsf(filter.heading().gyro - gyro) < 0.001);
+    }
+
+    #[test]
+    fn doesnt_learn_the_bias_while_moving_or_turning_fast() {
+        let mut filter = filter(CONFIG);
+        for i in 0..SECOND {
+            filter.update(Some(1.0), Some((i as f32, i as f32)));
+        }
+        for _ in 0..SECOND {
+            filter.update(Some(2.0 * MAX_BIAS), Some((0.0, 0.0)));
+        }
+        assert_eq!(filter.heading().gyro_bias, 0.0);
+    }
+
+    #[test]
+    fn setting_it_keeps_the_bias() {
+        let mut filter = filter(CONFIG);
+        for _ in 0..SECOND * 10 {
+            filter.update(Some(1.0), Some((0.0, 0.0)));
+        }
+        let bias = filter.heading().gyro_bias;
+        filter.set(45.0);
+        assert_eq!(filter.heading(), Heading { heading: 45.0, odometry: 45.0, gyro: 45.0, gyro_bias: bias });
+    }
+
+    #[test]
+    fn refuses_configs_that_make_no_sense() {
+        let mut filter = HeadingFilter::new();
+        assert!(!filter.configure(HeadingConfig { gyro_weight: 1.5, ..CONFIG }));
+        assert!(!filter.configure(HeadingConfig { track_width: -0.5, ..CONFIG }));
+        assert!(!filter.configure(HeadingConfig { metres_per_cycle: f32::NAN, ..CONFIG }));
+        assert_eq!(filter.config(), HeadingConfig::default());
+    }
+
+    #[test]
+    fn refuses_configs_that_arent_finite_or_are_absurd() {
+        let mut filter = HeadingFilter::new();
+        for value in [f32::INFINITY, f32::NEG_INFINITY, f32::NAN].iter() {
+            assert!(!filter.configure(HeadingConfig { metres_per_cycle: *value, ..CONFIG }));
+            assert!(!filter.configure(HeadingConfig { track_width: *value, ..CONFIG }));
+            assert!(!filter.configure(HeadingConfig { gyro_weight: *value, ..CONFIG }));
+        }
+        assert!(!filter.configure(HeadingConfig { metres_per_cycle: 100.0, ..CONFIG }));
+        assert!(!filter.configure(HeadingConfig { track_width: 1e-30, ..CONFIG }));
+        assert!(!filter.configure(HeadingConfig { track_width: 100.0, ..CONFIG }));
+        assert!(!filter.configure(HeadingConfig { gyro_weight: -0.1, ..CONFIG }));
+        assert_eq!(filter.config(), HeadingConfig::default());
+    }
+
+    #[test]
+    fn takes_the_ends_of_the_ranges() {
+        let mut filter = HeadingFilter::new();
+        for gyro_weight in [0.0, 1.0].iter() {
+            assert!(filter.configure(HeadingConfig { gyro_weight: *gyro_weight, ..CONFIG }));
+        }
+        // Without the wheels' geometry
+        assert!(filter.configure(HeadingConfig { metres_per_cycle: 0.0, track_width: 0.0, ..CONFIG }));
+    }
+}
@@ -0,0 +1,283 @@
+use libm::{ asinf, sqrtf };
+use core::f32::consts::PI;
+use protocol::{ ChannelHealth, EncoderHealth, SignalFault };
+
+/// Only every this many samples are measured, to keep the work in the
+/// sampling interrupt down
+const DECIMATION: u32 = 8;
+/// Measured samples per measurement: about 0.4s at 10kHz
+const WINDOW: u32 = 512;
+// The ADC is 12 bit
+const FULL_SCALE: f32 = 4095.0;
+// Within this many counts of either end counts as stuck there
+const RAIL: f32 = 32.0;
+// Peak amplitude below which the interpolation gets too coarse to trust
+const LOW_AMPLITUDE: f32 = 200.0;
+
+// Sums over the window, taken relative to the last window's mean so they
+// don't lose precision in f32
+struct Channel {
+    shift: f32,
+    sum: f32,
+    squares: f32,
+    min: f32,
+    max: f32,
+    // Squared second differences of consecutive samples, which leave out
+    // anything that changes slowly next to the sample rate
+    roughness: f32,
+}
+
+impl Channel {
+    fn new(shift: f32) -> Self {
+        Channel {
+            shift,
+            sum: 0.0,
+            squares: 0.0,
+            min: FULL_SCALE,
+            max: 0.0,
+            roughness: 0.0,
+        }
+    }
+
+    // Takes the two samples before this one too. Returns the value relative
+    // to the shift.
+    fn update(&mut self, value: f32, history: (f32, f32)) -> f32 {
+        let x = value - self.shift;
+        self.sum += x;
+        self.squares += x * x;
+        self.min = self.min.min(value);
+        self.max = self.max.max(value);
+        let second = value - 2.0 * history.1 + history.0;
+        self.roughness += second * second;
+        x
+    }
+
+    fn mean(&self, n: f32) -> f32 {
+        self.sum / n
+    }
+
+    fn variance(&self, n: f32) -> f32 {
+        (self.squares / n - self.mean(n) * self.mean(n)).max(0.0)
+    }
+
+    fn health(&self, n: f32) -> ChannelHealth {
+        let amplitude = sqrtf(2.0 * self.variance(n));
+        let fault = if self.max < RAIL || self.min > FULL_SCALE - RAIL {
+            SignalFault::Disconnected
+        } else if amplitude < LOW_AMPLITUDE {
+            SignalFault::LowAmplitude
+        } else {
+            SignalFault::None
+        };
+        ChannelHealth {
+            offset: self.shift + self.mean(n),
+            amplitude,
+            // White noise of variance v gives second differences of 6v
+            noise: sqrtf(self.roughness / n / 6.0),
+            fault,
+        }
+    }
+}
+
+/// Measures the signal quality of an encoder's pair of inputs over a window
+/// of samples, keeping the result of the last complete window.
+pub struct SignalMonitor {
+    a: Channel,
+    b: Channel,
+    products: f32,
+    count: u32,
+    // Samples since the last measured one
+    skipped: u32,
+    // The two samples before this one, for the second differences
+    history: ((f32, f32), (f32, f32)),
+    // The first sample sets where the sums are taken from
+    started: bool,
+    health: EncoderHealth,
+}
+
+impl SignalMonitor {
+    pub fn new() -> Self {
+        let unknown = ChannelHealth {
+            offset: 0.0,
+            amplitude: 0.0,
+            noise: 0.0,
+            fault: SignalFault::LowAmplitude,
+        };
+        SignalMonitor {
+            a: Channel::new(0.0),
+            b: Channel::new(0.0),
+            products: 0.0,
+            count: 0,
+            skipped: 0,
+            history: ((0.0, 0.0), (0.0, 0.0)),
+            started: false,
+            health: EncoderHealth {
+                a: unknown,
+                b: unknown,
+                amplitude_mismatch: 0.0,
+                phase_error: 0.0,
+                samples: 0,
+            },
+        }
+    }
+
+    pub fn update(&mut self, a: f32, b: f32) {
+        if !self.started {
+            self.a = Channel::new(a);
+            self.b = Channel::new(b);
+            self.history = ((a, b), (a, b));
+            self.started = true;
+        }
+        let (earlier, previous) = self.history;
+        self.history = (previous, (a, b));
+        self.skipped += 1;
+        if self.skipped < DECIMATION {
+            return;
+        }
+        self.skipped = 0;
+
+        let (a, b) = (
+            self.a.update(a, (earlier.0, previous.0)),
+            self.b.update(b, (earlier.1, previous.1)));
+        self.products += a * b;
+        self.count += 1;
+        if self.count == WINDOW {
+            self.measure();
+        }
+    }
+
+    pub fn health(&self) -> EncoderHealth {
+        self.health
+    }
+
+    fn measure(&mut self) {
+        let n = self.count as f32;
+        let (a, b) = (self.a.health(n), self.b.health(n));
+
+        // With A = sin(t + e) and B = cos(t), as the calibration has them,
+        // the correlation is sin(e)
+        let covariance = self.products / n - self.a.mean(n) * self.b.mean(n);
+        let deviations = sqrtf(self.a.variance(n) * self.b.variance(n));
+        let correlation = if deviations > 0.0 { covariance / deviations } else { 0.0 };
+        let mean_amplitude = (a.amplitude + b.amplitude) / 2.0;
+
+        self.health = EncoderHealth {
+            a,
+            b,
+            amplitude_mismatch: if mean_amplitude > 0.0 {
+                (a.amplitude - b.amplitude) / mean_amplitude
+            } else {
+                0.0
+            },
+            phase_error: asinf(correlation.clamp(-1.0, 1.0)) * 180.0 / PI,
+            samples: self.count * DECIMATION,
+        };
+
+        self.a = Channel::new(a.offset);
+        self.b = Channel::new(b.offset);
+        self.products = 0.0;
+        self.count = 0;
+    }
+}
+
+impl Default for SignalMonitor {
+    fn default() -> Self {
+        SignalMonitor::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::calibration::EllipseFit;
+    use libm::{ cosf, fabsf, sinf };
+
+    const CENTRE: f32 = 2048.0;
+    // Samples in a turn of the encoder, so a window is a whole number of them
+    const TURN: u32 = 256;
+
+    // A = sin(t + phase) and B = cos(t), in ADC counts, for sample i
+    fn signals(amplitude: f32, phase: f32, i: u32) -> (f32, f32) {
+        let t = i as f32 * 2.0 * PI / TURN as f32;
+        (CENTRE + amplitude * sinf(t + phase * PI / 180.0), CENTRE + amplitude * cosf(t))
+    }
+
+    // A window's worth of signals
+    fn monitor(amplitude: f32, phase: f32) -> SignalMonitor {
+        let mut monitor = SignalMonitor::new();
+        for i in 0..WINDOW * DECIMATION {
+            let (a, b) = signals(amplitude, phase, i);
+            monitor.update(a, b);
+        }
+        monitor
+    }
+
+    #[test]
+    fn measures_clean_signals() {
+        let health = monitor(1000.0, 0.0).health();
+        assert_eq!(health.samples, WINDOW * DECIMATION);
+        for channel in [health.a, health.b].iter() {
+            assert!(fabsf(channel.offset - CENTRE) < 5.0, "{:?}", channel);
+            assert!(fabsf(channel.amplitude - 1000.0) < 10.0, "{:?}", channel);
+            assert!(channel.noise < 1.0, "{:?}", channel);
+            assert_eq!(channel.fault, SignalFault::None);
+        }
+        assert!(fabsf(health.amplitude_mismatch) < 0.01);
+        assert!(fabsf(health.phase_error) < 1.0);
+    }
+
+    #[test]
+    fn measures_phase_error() {
+        let health = monitor(1000.0, 10.0).health();
+        assert!(fabsf(health.phase_error - 10.0) < 1.0, "{}", health.phase_error);
+    }
+
+    #[test]
+    fn phase_error_agrees_with_the_fit() {
+        for phase in [10.0, -10.0].iter() {
+            let mut monitor = SignalMonitor::new();
+            let mut fit = EllipseFit::new();
+            let mut calibration = None;
+            let mut i = 0;
+            while calibration.is_none() {
+                let (a, b) = signals(1000.0, *phase, i);
+                monitor.update(a, b);
+                calibration = fit.update((a, b));
+                i += 1;
+            }
+            let health = monitor.health();
+            assert!(fabsf(health.phase_error - phase) < 1.0, "{}", health.phase_error);
+            assert!(fabsf(calibration.unwrap().phase_error - phase) < 1.0, "{:?}", calibration);
+        }
+    }
+
+    #[test]
+    fn nothing_until_a_window_is_full() {
+        let mut monitor = SignalMonitor::new();
+        for _ in 0..WINDOW * DECIMATION - 1 {
+            monitor.update(CENTRE, CENTRE);
+        }
+        assert_eq!(monitor.health().samples, 0);
+    }
+
+    #[test]
+    fn zero_amplitude_is_low_without_nans() {
+        let health = monitor(0.0, 0.0).health();
+        assert_eq!(health.a.fault, SignalFault::LowAmplitude);
+        assert_eq!(health.b.fault, SignalFault::LowAmplitude);
+        assert_eq!(health.a.amplitude, 0.0);
+        assert_eq!(health.amplitude_mismatch, 0.0);
+        assert_eq!(health.phase_error, 0.0);
+    }
+
+    #[test]
+    fn input_at_a_rail_is_disconnected() {
+        let mut monitor = SignalMonitor::new();
+        for _ in 0..WINDOW * DECIMATION {
+            monitor.update(0.0, FULL_SCALE);
+        }
+        let health = monitor.health();
+        assert_eq!(health.a.fault, SignalFault::Disconnected);
+        assert_eq!(health.b.fault, SignalFault::Disconnected);
+    }
+}
@@ -0,0 +1,192 @@
+use embedded_hal::blocking::i2c::{ Write, WriteRead };
+use protocol::ImuReading;
+
+use crate::CONTROL_PERIOD_MS;
+
+// It's at 0x68, or 0x69 with AD0 pulled up
+const ADDRESSES: [u8; 2] = [0x68, 0x69];
+
+const CONFIG: u8 = 0x1a;
+const GYRO_CONFIG: u8 = 0x1b;
+const ACCEL_CONFIG: u8 = 0x1c;
+const ACCEL_XOUT_H: u8 = 0x3b;
+const PWR_MGMT_1: u8 = 0x6b;
+const WHO_AM_I: u8 = 0x75;
+
+// The MPU-6050, and the MPU-6500 and MPU-9250, which have the same registers
+// for all we use
+const IDENTITIES: [u8; 4] = [0x68, 0x70, 0x71, 0x73];
+
+// With the full scales set in `new`: 4g and 500 degrees a second
+const ACCEL_PER_G: f32 = 8192.0;
+const GYRO_PER_DEGREE: f32 = 65.5;
+
+// After this many readings fail in a row it's taken to have gone, and only
+// tried again every RETRY_MS, so a dead bus doesn't hold up every control
+// period with its timeouts
+const MAX_ERRORS: u32 = 10;
+const RETRY_MS: u32 = 3_000;
+
+/// An MPU-6050 on the expansion bus. It's read every control period, and the
+/// last reading kept for whoever asks.
+pub struct Imu {
+    address: u8,
+    reading: ImuReading,
+    // Readings that failed in a row
+    errors: u32,
+    // Control periods until it's tried again, once it's gone
+    retry_in: u32,
+}
+
+impl Imu {
+    /// Looks for the IMU at both its addresses and sets it going, or gives
+    /// None if it isn't there.
+    pub fn new<I, E>(bus: &mut I) -> Option<Self>
+    where I: Write<Error = E> + WriteRead<Error = E> {
+        let address = ADDRESSES.iter().copied().find(|address| {
+            let mut identity = [0];
+            bus.write_read(*address, &[WHO_AM_I], &mut identity).is_ok()
+                && IDENTITIES.contains(&identity[0])
+        })?;
+        let setup = [
+            // Awake, clocked from the X gyro
+            [PWR_MGMT_1, 0x01],
+            // The 44Hz low pass filter, as we read at 100Hz
+            [CONFIG, 0x03],
+            [GYRO_CONFIG, 0x08],
+            [ACCEL_CONFIG, 0x08],
+        ];
+        for write in setup.iter() {
+            bus.write(address, write).ok()?;
+        }
+        Some(Imu {
+            address,
+            reading: ImuReading::default(),
+            errors: 0,
+            retry_in: 0,
+        })
+    }
+
+    /// Takes a new reading, returning it if it worked. Once it's gone, most
+    /// calls don't try.
+    pub fn update<I, E>(&mut self, bus: &mut I) -> Option<ImuReading>
+    where I: WriteRead<Error = E> {
+        if self.retry_in > 0 {
+            self.retry_in -= 1;
+            return None;
+        }
+        let mut raw = [0; 14];
+        if bus.write_read(self.address, &[ACCEL_XOUT_H], &mut raw).is_err() {
+            self.errors = self.errors.saturating_add(1);
+            if self.errors >= MAX_ERRORS {
+                self.retry_in = RETRY_MS / CONTROL_PERIOD_MS;
+            }
+            return None;
+        }
+        self.errors = 0;
+
+        // Big endian accelerations, temperature, then rotations
+        let word = |i: usize| i16::from_be_bytes([raw[2 * i], raw[2 * i + 1]]) as f32;
+        self.reading = ImuReading {
+            accel: [word(0) / ACCEL_PER_G, word(1) / ACCEL_PER_G, word(2) / ACCEL_PER_G],
+            gyro: [word(4) / GYRO_PER_DEGREE, word(5) / GYRO_PER_DEGREE, word(6) / GYRO_PER_DEGREE],
+            temperature: word(3) / 340.0 + 36.53,
+        };
+        Some(self.reading)
+    }
+
+    /// The last reading that worked
+    pub fn reading(&self) -> ImuReading {
+        self.reading
+    }
+
+    /// Whether the last reading failed, or it's gone
+    pub fn failing(&self) -> bool {
+        self.errors > 0
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // An IMU that answers, or a bus that doesn't
+    struct Bus {
+        working: bool,
+        reading: [u8; 14],
+        attempts: u32,
+    }
+
+    impl Write for Bus {
+        type Error = ();
+
+        fn write(&mut self, _address: u8, _bytes: &[u8]) -> Result<(), ()> {
+            if self.working { Ok(()) } else { Err(()) }
+        }
+    }
+
+    impl WriteRead for Bus {
+        type Error = ();
+
+        fn write_read(&mut self, _address: u8, bytes: &[u8], buffer: &mut [u8]) -> Result<(), ()> {
+            self.attempts += 1;
+            if !self.working {
+                return Err(());
+            }
+            match bytes[0] {
+                WHO_AM_I => buffer[0] = IDENTITIES[0],
+                _ => buffer.copy_from_slice(&self.reading),
+            }
+            Ok(())
+        }
+    }
+
+    fn bus() -> Bus {
+        Bus { working: true, reading: [0; 14], attempts: 0 }
+    }
+
+    #[test]
+    fn scales_the_reading() {
+        let mut bus = bus();
+        let mut imu = Imu::new(&mut bus).unwrap();
+        // 1g down Z, 0 for the temperature, and 10 degrees a second round Z
+        bus.reading[4..6].copy_from_slice(&8192i16.to_be_bytes());
+        bus.reading[12..14].copy_from_slice(&655i16.to_be_bytes());
+        let reading = imu.update(&mut bus).unwrap();
+        assert_eq!(reading.accel, [0.0, 0.0, 1.0]);
+        assert_eq!(reading.gyro, [0.0, 0.0, 10.0]);
+        assert_eq!(reading.temperature, 36.53);
+        assert_eq!(imu.reading(), reading);
+        assert!(!imu.failing());
+    }
+
+    #[test]
+    fn not_there() {
+        let mut bus = Bus { working: false, ..bus() };
+        assert!(Imu::new(&mut bus).is_none());
+    }
+
+    #[test]
+    fn backs_off_when_it_goes() {
+        let mut bus = bus();
+        let mut imu = Imu::new(&mut bus).unwrap();
+        bus.working = false;
+        bus.attempts = 0;
+        for _ in 0..MAX_ERRORS {
+            assert_eq!(imu.update(&mut bus), None);
+        }
+        assert_eq!(bus.attempts, MAX_ERRORS);
+        assert!(imu.failing());
+
+        // It's left alone for a while...
+        bus.working = true;
+        for _ in 0..RETRY_MS / CONTROL_PERIOD_MS {
+            assert_eq!(imu.update(&mut bus), None);
+        }
+        assert_eq!(bus.attempts, MAX_ERRORS);
+        assert!(imu.failing());
+        // ...then tried again
+        assert!(imu.update(&mut bus).is_some());
+        assert!(!imu.failing());
+    }
+}
@@ -0,0 +1,23 @@
+//! The parts of the microcontroller that don't touch the hardware: signal
+//! processing, output shaping, protection, the radio's fragmentation and the
+//! settings' encoding. They're kept apart so they can be built and tested on
+//! the host.
+#![deny(unsafe_code)]
+#![deny(warnings)]
+#![cfg_attr(not(test), no_std)]
+
+pub mod health;
+pub mod calibration;
+pub mod encoder;
+pub mod shaping;
+pub mod supply;
+pub mod protection;
+pub mod power;
+pub mod imu;
+pub mod heading;
+pub mod packet;
+pub mod settings;
+
+/// How often the motor outputs take a step towards their duty, in ms.
+/// Anything else done every control period works its timing out from this.
+pub const CONTROL_PERIOD_MS: u32 = 10;
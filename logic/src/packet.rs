@@ -0,0 +1,307 @@
+use core::cmp::min;
+use heapless::Vec;
+use nb::Error::WouldBlock;
+use protocol::{ RADIO_PACKET, RADIO_HEADER };
+
+/// A link that moves whole packets of at most `mtu()` bytes, like a packet
+/// radio.
+pub trait Packets {
+    type Error;
+
+    fn mtu(&self) -> usize;
+    fn send(&mut self, packet: &[u8]) -> nb::Result<(), Self::Error>;
+    fn recv(&mut self, packet: &mut [u8]) -> nb::Result<usize, Self::Error>;
+}
+
+/// Carries the COBS byte stream over a packet link by cutting it into
+/// fragments. The frame delimiters travel in the stream, so the fragments
+/// don't need to line up with frames, but each one has a sequence number so
+/// a lost fragment drops the frame it was part of rather than corrupting the
+/// next one.
+pub struct PacketLink<P> {
+    pub packets: P,
+    // A fragment taken from the response queue that hasn't been sent yet
+    pending: Vec<u8, RADIO_PACKET>,
+    tx_sequence: u8,
+    rx_sequence: Option<u8>,
+    // Fragments given up on without an acknowledgement
+    tx_lost: u32,
+    // Fragments that couldn't be read from the link
+    rx_lost: u32,
+}
+
+impl <P> PacketLink<P> {
+    pub fn new(packets: P) -> Self {
+        PacketLink {
+            packets,
+            pending: Vec::new(),
+            tx_sequence: 0,
+            rx_sequence: None,
+            tx_lost: 0,
+            rx_lost: 0,
+        }
+    }
+}
+
+impl <P> PacketLink<P>
+where P: Packets {
+    /// Passes whatever has been received to `enqueue`, a byte at a time,
+    /// which says whether it had room for it. Returns true if anything was
+    /// queued.
+    pub fn receive_into<F>(&mut self, mut enqueue: F) -> bool
+    where F: FnMut(u8) -> bool {
+        let mut read = false;
+        let mut packet = [0u8; RADIO_PACKET];
+        loop {
+            match self.packets.recv(&mut packet) {
+                Ok(len) if len >= RADIO_HEADER => {
+                    let sequence = packet[0];
+                    let count = min(packet[1] as usize, len - RADIO_HEADER);
+                    if self.rx_sequence.is_some_and(|expected| expected != sequence) {
+                        // Terminate the partial frame, so it fails to decode
+                        // and is thrown away
+                        read |= enqueue(0);
+                    }
+                    self.rx_sequence = Some(sequence.wrapping_add(1));
+
+                    for byte in &packet[RADIO_HEADER..RADIO_HEADER + count] {
+                        // A full queue loses the rest of the fragment; the
+                        // COBS decode of that frame will fail and be dropped.
+                        read |= enqueue(*byte);
+                    }
+                },
+                Ok(_) => {},
+                Err(WouldBlock) => break,
+                // Try again on the next poll. If a fragment was lost, the
+                // next one's sequence number shows it.
+                Err(_) => {
+                    self.rx_lost += 1;
+                    break;
+                },
+            }
+        }
+        read
+    }
+
+    /// Sends what `dequeue` gives it, until it runs out or the link would
+    /// block.
+    pub fn transmit_from<F>(&mut self, mut dequeue: F)
+    where F: FnMut() -> Option<u8> {
+        let mtu = min(self.packets.mtu(), RADIO_PACKET);
+        loop {
+            if self.pending.is_empty() {
+                self.pending.push(self.tx_sequence).unwrap();
+                self.pending.push(0).unwrap();
+                while self.pending.len() < mtu {
+                    match dequeue() {
+                        Some(byte) => self.pending.push(byte).unwrap(),
+                        None => break,
+                    }
+                }
+                if self.pending.len() == RADIO_HEADER {
+                    self.pending.clear();
+                    break;
+                }
+                self.pending[1] = (self.pending.len() - RADIO_HEADER) as u8;
+            }
+
+            match self.packets.send(&self.pending[..]) {
+                Ok(()) => {},
+                Err(WouldBlock) => break,
+                Err(_) => self.tx_lost += 1,
+            }
+            // Sent, or given up on; either way the receiver can tell from
+            // the sequence number.
+            self.pending.clear();
+            self.tx_sequence = self.tx_sequence.wrapping_add(1);
+        }
+    }
+
+    /// Fragments given up on without an acknowledgement
+    pub fn tx_lost(&self) -> u32 {
+        self.tx_lost
+    }
+
+    /// Fragments that couldn't be read from the link
+    pub fn rx_lost(&self) -> u32 {
+        self.rx_lost
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Packets sent, for the other end to receive
+    struct Air {
+        packets: [(usize, [u8; RADIO_PACKET]); 8],
+        sent: usize,
+        received: usize,
+        blocked: bool,
+        failing: bool,
+    }
+
+    impl Air {
+        fn new() -> Self {
+            Air {
+                packets: [(0, [0; RADIO_PACKET]); 8],
+                sent: 0,
+                received: 0,
+                blocked: false,
+                failing: false,
+            }
+        }
+
+        fn packet(&self, i: usize) -> &[u8] {
+            &self.packets[i].1[..self.packets[i].0]
+        }
+
+        fn add(&mut self, packet: &[u8]) {
+            self.packets[self.sent].0 = packet.len();
+            self.packets[self.sent].1[..packet.len()].copy_from_slice(packet);
+            self.sent += 1;
+        }
+    }
+
+    impl Packets for Air {
+        type Error = ();
+
+        fn mtu(&self) -> usize {
+            RADIO_PACKET
+        }
+
+        fn send(&mut self, packet: &[u8]) -> nb::Result<(), ()> {
+            if self.blocked {
+                return Err(WouldBlock);
+            }
+            if self.failing {
+                return Err(nb::Error::Other(()));
+            }
+            self.add(packet);
+            Ok(())
+        }
+
+        fn recv(&mut self, packet: &mut [u8]) -> nb::Result<usize, ()> {
+            if self.failing {
+                return Err(nb::Error::Other(()));
+            }
+            if self.received == self.sent {
+                return Err(WouldBlock);
+            }
+            let len = {
+                let next = self.packet(self.received);
+                packet[..next.len()].copy_from_slice(next);
+                next.len()
+            };
+            self.received += 1;
+            Ok(len)
+        }
+    }
+
+    // Sends `stream` from one link over the air
+    fn send(link: &mut PacketLink<Air>, stream: &[u8]) {
+        let mut bytes = stream.iter();
+        link.transmit_from(|| bytes.next().copied());
+    }
+
+    // What a link receiving what's on the air would make of it
+    fn receive(air: Air) -> Vec<u8, 256> {
+        let mut link = PacketLink::new(air);
+        let mut stream = Vec::new();
+        link.receive_into(|byte| stream.push(byte).is_ok());
+        stream
+    }
+
+    fn stream() -> [u8; 100] {
+        let mut stream = [0; 100];
+        for (i, byte) in stream.iter_mut().enumerate() {
+            *byte = i as u8 + 1;
+        }
+        stream
+    }
+
+    const PAYLOAD: usize = RADIO_PACKET - RADIO_HEADER;
+
+    #[test]
+    fn cuts_the_stream_into_numbered_fragments() {
+        let mut link = PacketLink::new(Air::new());
+        send(&mut link, &stream());
+        let air = &link.packets;
+        assert_eq!(air.sent, 100_usize.div_ceil(PAYLOAD));
+        for i in 0..air.sent {
+            let packet = air.packet(i);
+            assert_eq!(packet[0], i as u8);
+            assert_eq!(packet[1] as usize, packet.len() - RADIO_HEADER);
+        }
+        assert_eq!(&receive(link.packets)[..], &stream()[..]);
+    }
+
+    #[test]
+    fn sends_nothing_for_nothing() {
+        let mut link = PacketLink::new(Air::new());
+        send(&mut link, &[]);
+        assert_eq!(link.packets.sent, 0);
+    }
+
+    #[test]
+    fn a_lost_fragment_ends_the_frame() {
+        let mut link = PacketLink::new(Air::new());
+        send(&mut link, &stream());
+        let mut air = Air::new();
+        for i in (0..link.packets.sent).filter(|i| *i != 1) {
+            air.add(link.packets.packet(i));
+        }
+        let received = receive(air);
+
+        let mut expected: Vec<u8, 256> = Vec::from_slice(&stream()[..PAYLOAD]).unwrap();
+        expected.push(0).unwrap();
+        expected.extend_from_slice(&stream()[2 * PAYLOAD..]).unwrap();
+        assert_eq!(received, expected);
+    }
+
+    #[test]
+    fn keeps_a_blocked_fragment_for_later() {
+        let mut link = PacketLink::new(Air::new());
+        link.packets.blocked = true;
+        send(&mut link, &stream()[..10]);
+        assert_eq!(link.packets.sent, 0);
+        link.packets.blocked = false;
+        send(&mut link, &[]);
+        assert_eq!(&receive(link.packets)[..], &stream()[..10]);
+    }
+
+    #[test]
+    fn counts_fragments_that_fail() {
+        let mut link = PacketLink::new(Air::new());
+        link.packets.failing = true;
+        send(&mut link, &stream());
+        assert_eq!(link.tx_lost(), 4);
+        // The next one goes with the sequence moved on
+        link.packets.failing = false;
+        send(&mut link, &[1]);
+        assert_eq!(link.packets.packet(0)[0], 4);
+    }
+
+    #[test]
+    fn counts_fragments_that_cant_be_read() {
+        let mut link = PacketLink::new(Air::new());
+        send(&mut link, &stream()[..10]);
+        let mut receiver = PacketLink::new(link.packets);
+        receiver.packets.failing = true;
+        let mut received = Vec::<u8, 256>::new();
+        assert!(!receiver.receive_into(|byte| received.push(byte).is_ok()));
+        assert_eq!(receiver.rx_lost(), 1);
+        // What's still there is read once the link recovers
+        receiver.packets.failing = false;
+        assert!(receiver.receive_into(|byte| received.push(byte).is_ok()));
+        assert_eq!(&received[..], &stream()[..10]);
+    }
+
+    #[test]
+    fn short_packets_are_ignored() {
+        let mut air = Air::new();
+        air.add(&[0]);
+        air.add(&[0, 2, 7, 8]);
+        assert_eq!(&receive(air)[..], &[7, 8]);
+    }
+}
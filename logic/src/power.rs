@@ -0,0 +1,152 @@
+use protocol::Event;
+
+use crate::CONTROL_PERIOD_MS as PERIOD_MS;
+
+// Control periods the button has to read the same before it counts
+const DEBOUNCE: u8 = 3;
+// Holding the button this long cuts the power straight away, in case the
+// host has hung
+const HOLD_MS: u32 = 4_000;
+
+/// The power button, debounced, apart from its pin
+pub struct Button {
+    // The debounced button, and how many periods the raw one has disagreed
+    // with it for
+    pressed: bool,
+    changing: u8,
+    // The press that turned the board on doesn't count, so nothing does
+    // until the button has been let go
+    armed: bool,
+    // Periods the button has been down for
+    held: u32,
+}
+
+impl Button {
+    pub fn new() -> Self {
+        Button {
+            pressed: true,
+            changing: 0,
+            armed: false,
+            held: 0,
+        }
+    }
+
+    /// Whether it's down, debounced
+    pub fn pressed(&self) -> bool {
+        self.pressed
+    }
+
+    /// Call every control period with the raw button. Says when it's been
+    /// pressed, and whether it's been held down long enough to cut the power.
+    pub fn update(&mut self, down: bool) -> (Option<Event>, bool) {
+        let mut event = None;
+        if down == self.pressed {
+            self.changing = 0;
+        } else {
+            self.changing += 1;
+            if self.changing >= DEBOUNCE {
+                self.changing = 0;
+                self.pressed = down;
+                self.held = 0;
+                if !down {
+                    self.armed = true;
+                } else if self.armed {
+                    event = Some(Event::PowerButton);
+                }
+            }
+        }
+
+        let mut held = false;
+        if self.pressed && self.armed {
+            self.held = self.held.saturating_add(1);
+            held = self.held.saturating_mul(PERIOD_MS) >= HOLD_MS;
+        }
+        (event, held)
+    }
+}
+
+impl Default for Button {
+    fn default() -> Self {
+        Button::new()
+    }
+}
+
+/// Control periods in `delay_ms`, rounded up, without overflowing for the
+/// longest delays
+pub fn periods(delay_ms: u32) -> u32 {
+    delay_ms.div_ceil(PERIOD_MS)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Feeds the raw button in for `periods`, counting the presses and
+    // whether it was held long enough
+    fn press(button: &mut Button, down: bool, periods: u32) -> (u32, bool) {
+        let (mut presses, mut held) = (0, false);
+        for _ in 0..periods {
+            let (event, long) = button.update(down);
+            presses += event.is_some() as u32;
+            held |= long;
+        }
+        (presses, held)
+    }
+
+    fn armed() -> Button {
+        let mut button = Button::new();
+        press(&mut button, false, DEBOUNCE as u32);
+        button
+    }
+
+    #[test]
+    fn the_press_that_turned_it_on_doesnt_count() {
+        let mut button = Button::new();
+        assert_eq!(press(&mut button, true, HOLD_MS / PERIOD_MS * 2), (0, false));
+        assert!(button.pressed);
+        press(&mut button, false, DEBOUNCE as u32);
+        assert!(!button.pressed);
+    }
+
+    #[test]
+    fn a_press_counts_once_debounced() {
+        let mut button = armed();
+        assert_eq!(press(&mut button, true, DEBOUNCE as u32 - 1), (0, false));
+        assert!(!button.pressed);
+        assert_eq!(press(&mut button, true, 1), (1, false));
+        assert!(button.pressed);
+        assert_eq!(press(&mut button, true, 10), (0, false));
+    }
+
+    #[test]
+    fn bouncing_is_ignored() {
+        let mut button = armed();
+        for _ in 0..10 {
+            assert_eq!(press(&mut button, true, DEBOUNCE as u32 - 1), (0, false));
+            assert_eq!(press(&mut button, false, 1), (0, false));
+        }
+        assert!(!button.pressed);
+    }
+
+    #[test]
+    fn holding_it_down_cuts_the_power() {
+        let mut button = armed();
+        press(&mut button, true, DEBOUNCE as u32);
+        assert_eq!(press(&mut button, true, HOLD_MS / PERIOD_MS - 2), (0, false));
+        assert_eq!(press(&mut button, true, 1), (0, true));
+    }
+
+    #[test]
+    fn delays_round_up_to_a_period() {
+        assert_eq!(periods(0), 0);
+        assert_eq!(periods(1), 1);
+        assert_eq!(periods(PERIOD_MS), 1);
+        assert_eq!(periods(PERIOD_MS + 1), 2);
+    }
+
+    #[test]
+    fn the_longest_delays_dont_overflow() {
+        assert_eq!(periods(u32::MAX), u32::MAX / PERIOD_MS + 1);
+        assert_eq!(periods(u32::MAX).saturating_mul(PERIOD_MS), u32::MAX);
+    }
+}
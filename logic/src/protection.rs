@@ -0,0 +1,232 @@
+use libm::fabsf;
+use protocol::{ Event, MotorProtection };
+
+use crate::CONTROL_PERIOD_MS as PERIOD_MS;
+
+// Volts at the pin for a full scale reading
+const REFERENCE: f32 = 3.3;
+const FULL_SCALE: f32 = 4095.0;
+// Each update moves the filtered current this far towards the reading
+const SMOOTHING: f32 = 0.3;
+// How far the duty's scale moves each control period for the current being
+// all of the limit over, or under, it
+const LIMIT_GAIN: f32 = 0.02;
+
+/// Limits the motors' current, if there's a sensor, and frees any motor that
+/// stalls.
+pub struct Protection {
+    config: MotorProtection,
+    // Filtered, in amps
+    current: f32,
+    // What the duty is multiplied by to keep under the current limit
+    limit: f32,
+    // Control periods each motor has looked stalled for
+    stalled: [u32; protocol::MAX_ENCODERS],
+}
+
+impl Protection {
+    pub fn new() -> Self {
+        Protection {
+            config: MotorProtection::default(),
+            current: 0.0,
+            limit: 1.0,
+            stalled: [0; protocol::MAX_ENCODERS],
+        }
+    }
+
+    pub fn config(&self) -> MotorProtection {
+        self.config
+    }
+
+    /// Returns false if it doesn't make sense
+    pub fn configure(&mut self, config: MotorProtection) -> bool {
+        if !(config.amps_per_volt.is_finite()
+            && config.zero_current.is_finite()
+            && config.current_limit.is_finite()
+            && config.amps_per_volt >= 0.0
+            && config.current_limit >= 0.0
+            && config.stall_duty > 0.0
+            && config.stall_velocity >= 0.0) {
+            return false;
+        }
+        self.config = config;
+        self.limit = 1.0;
+        true
+    }
+
+    /// In amps, 0 without a sensor
+    pub fn current(&self) -> f32 {
+        self.current
+    }
+
+    /// Call every control period with the raw ADC reading
+    pub fn update_current(&mut self, raw: u16) {
+        let c = &self.config;
+        if c.amps_per_volt == 0.0 {
+            self.current = 0.0;
+            return;
+        }
+        let reading = (raw as f32 * REFERENCE / FULL_SCALE - c.zero_current) * c.amps_per_volt;
+        self.current += (reading - self.current) * SMOOTHING;
+
+        // Winds the scale down while it's over the limit and back up while
+        // it's under, so it settles where the current is at the limit rather
+        // than cutting it back and overshooting each period
+        if c.current_limit > 0.0 {
+            let error = (c.current_limit - self.current) / c.current_limit;
+            self.limit = (self.limit + error * LIMIT_GAIN).clamp(0.0, 1.0);
+        } else {
+            self.limit = 1.0;
+        }
+    }
+
+    /// What the duty should be multiplied by to keep under the current limit
+    pub fn duty_scale(&self) -> f32 {
+        self.limit
+    }
+
+    /// Call every control period for each motor, after the outputs have been
+    /// stepped, with its duty and how fast its encoder is turning in cycles a
+    /// second. Says if it's been stalled for too long, and should be freed.
+    pub fn check(&mut self, index: usize, duty: f32, velocity: f32) -> Option<Event> {
+        let c = self.config;
+        if c.stall_timeout_ms == 0 || fabsf(duty) < c.stall_duty || velocity >= c.stall_velocity {
+            self.stalled[index] = 0;
+            return None;
+        }
+
+        self.stalled[index] += 1;
+        if self.stalled[index] * PERIOD_MS < c.stall_timeout_ms {
+            return None;
+        }
+        self.stalled[index] = 0;
+        Some(Event::Stall {
+            encoder: index as u8,
+            duty,
+            current: self.current,
+        })
+    }
+}
+
+impl Default for Protection {
+    fn default() -> Self {
+        Protection::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const CONFIG: MotorProtection = MotorProtection {
+        amps_per_volt: 2.0,
+        zero_current: 0.5,
+        current_limit: 1.0,
+        stall_duty: 0.5,
+        stall_velocity: 0.1,
+        stall_timeout_ms: 1000,
+    };
+
+    fn raw(config: &MotorProtection, amps: f32) -> u16 {
+        ((amps / config.amps_per_volt + config.zero_current) / REFERENCE * FULL_SCALE + 0.5) as u16
+    }
+
+    fn protection(config: MotorProtection) -> Protection {
+        let mut protection = Protection::new();
+        assert!(protection.configure(config));
+        protection
+    }
+
+    // A motor that draws `stall` amps at full duty, run for `periods`,
+    // giving the scale each period
+    fn run(protection: &mut Protection, stall: f32, periods: usize) -> [f32; 1000] {
+        let mut scales = [0.0; 1000];
+        for scale in scales.iter_mut().take(periods) {
+            let amps = stall * protection.duty_scale();
+            protection.update_current(raw(&CONFIG, amps));
+            *scale = protection.duty_scale();
+        }
+        scales
+    }
+
+    #[test]
+    fn no_sensor_no_limit() {
+        let mut protection = protection(MotorProtection::default());
+        protection.update_current(4095);
+        assert_eq!(protection.current(), 0.0);
+        assert_eq!(protection.duty_scale(), 1.0);
+    }
+
+    #[test]
+    fn measures_the_current() {
+        let mut protection = protection(CONFIG);
+        run(&mut protection, 0.8, 100);
+        assert!(fabsf(protection.current() - 0.8) < 0.01, "{}", protection.current());
+        assert_eq!(protection.duty_scale(), 1.0);
+    }
+
+    #[test]
+    fn settles_at_the_limit_without_oscillating() {
+        let mut protection = protection(CONFIG);
+        let scales = run(&mut protection, 3.0, 1000);
+        assert!(fabsf(protection.current() - 1.0) < 0.05, "{}", protection.current());
+        // Once it's got there it stays there
+        let settled = &scales[900..];
+        let (low, high) = settled.iter().fold((1.0f32, 0.0f32), |(low, high), scale| (low.min(*scale), high.max(*scale)));
+        assert!(high - low < 0.01, "{} {}", low, high);
+        assert!(fabsf(high - 1.0 / 3.0) < 0.02, "{}", high);
+    }
+
+    #[test]
+    fn lets_the_duty_back_up() {
+        let mut protection = protection(CONFIG);
+        run(&mut protection, 3.0, 1000);
+        run(&mut protection, 0.0, 200);
+        assert_eq!(protection.duty_scale(), 1.0);
+    }
+
+    #[test]
+    fn no_limit_leaves_the_duty_alone() {
+        let mut protection = protection(MotorProtection { current_limit: 0.0, ..CONFIG });
+        run(&mut protection, 3.0, 100);
+        assert_eq!(protection.duty_scale(), 1.0);
+    }
+
+    #[test]
+    fn reports_a_stall_once_it_times_out() {
+        let mut protection = protection(CONFIG);
+        let periods = CONFIG.stall_timeout_ms / PERIOD_MS;
+        for _ in 1..periods {
+            assert_eq!(protection.check(1, -0.8, 0.0), None);
+        }
+        match protection.check(1, -0.8, 0.0) {
+            Some(Event::Stall { encoder: 1, duty, .. }) => assert_eq!(duty, -0.8),
+            event => panic!("{:?}", event),
+        }
+        // It starts counting again
+        assert_eq!(protection.check(1, -0.8, 0.0), None);
+    }
+
+    #[test]
+    fn moving_or_barely_driven_isnt_stalled() {
+        let mut protection = protection(CONFIG);
+        let periods = CONFIG.stall_timeout_ms / PERIOD_MS;
+        for i in 0..periods * 2 {
+            // Either keeps it from timing out
+            let (duty, velocity) = if i % 2 == 0 { (1.0, 1.0) } else { (0.1, 0.0) };
+            assert_eq!(protection.check(0, duty, velocity), None);
+        }
+    }
+
+    #[test]
+    fn refuses_configs_that_make_no_sense() {
+        let mut protection = Protection::new();
+        assert!(!protection.configure(MotorProtection { amps_per_volt: -1.0, ..CONFIG }));
+        assert!(!protection.configure(MotorProtection { current_limit: f32::NAN, ..CONFIG }));
+        assert!(!protection.configure(MotorProtection { current_limit: f32::INFINITY, ..CONFIG }));
+        assert!(!protection.configure(MotorProtection { amps_per_volt: f32::INFINITY, ..CONFIG }));
+        assert!(!protection.configure(MotorProtection { zero_current: f32::NAN, ..CONFIG }));
+        assert!(!protection.configure(MotorProtection { stall_duty: 0.0, ..CONFIG }));
+        assert_eq!(protection.config(), MotorProtection::default());
+    }
+}
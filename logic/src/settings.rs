@@ -0,0 +1,112 @@
+use serde::{ Serialize, Deserialize };
+use protocol::{
+    EStopConfig, HeadingConfig, MotorConfig, MotorProtection, OutputShaping, PwmConfig, ReferenceConfig, SampleTrigger,
+    ServoConfig, SupplyConfig, MAX_ENCODERS, MAX_SERVOS,
+};
+
+/// What's set aside for them in flash
+pub const SETTINGS_SIZE: usize = 256;
+
+/// Everything that survives a reset. It's written as a length followed by
+/// the postcard encoding, so erased flash, or settings from a build with a
+/// different layout, just give the defaults.
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq)]
+pub struct Settings {
+    pub baud_rate: u32,
+    /// By encoder
+    pub motors: [MotorConfig; MAX_ENCODERS],
+    /// By encoder, for the ones with motors
+    pub shaping: [OutputShaping; MAX_ENCODERS],
+    pub pwm: PwmConfig,
+    pub sample_trigger: SampleTrigger,
+    pub supply: SupplyConfig,
+    pub protection: MotorProtection,
+    pub servos: [ServoConfig; MAX_SERVOS],
+    pub heading: HeadingConfig,
+    pub estop: EStopConfig,
+    pub reference: ReferenceConfig,
+}
+
+impl Default for Settings {
+    fn default() -> Self {
+        Settings {
+            baud_rate: 115_200,
+            motors: [MotorConfig::default(); MAX_ENCODERS],
+            shaping: [OutputShaping::default(); MAX_ENCODERS],
+            pwm: PwmConfig::default(),
+            sample_trigger: SampleTrigger::default(),
+            supply: SupplyConfig::default(),
+            protection: MotorProtection::default(),
+            servos: [ServoConfig::default(); MAX_SERVOS],
+            heading: HeadingConfig::default(),
+            estop: EStopConfig::default(),
+            reference: ReferenceConfig::default(),
+        }
+    }
+}
+
+/// Returns how much of `buf` needs writing
+pub fn encode(settings: &Settings, buf: &mut [u8; SETTINGS_SIZE]) -> usize {
+    let len = postcard::to_slice(settings, &mut buf[2..]).unwrap().len();
+    buf[..2].copy_from_slice(&(len as u16).to_le_bytes());
+    // Flash is written a half-word at a time
+    (len + 3) & !1
+}
+
+/// Erased flash, or anything else that isn't settings, gives the defaults
+pub fn decode(stored: &[u8]) -> Settings {
+    let len = u16::from_le_bytes([stored[0], stored[1]]) as usize;
+    if len + 2 > stored.len() {
+        return Settings::default();
+    }
+    postcard::from_bytes(&stored[2..len + 2]).unwrap_or_default()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn round_trip(settings: &Settings) -> Settings {
+        let mut buf = [0xffu8; SETTINGS_SIZE];
+        let written = encode(settings, &mut buf);
+        assert!(written <= SETTINGS_SIZE);
+        assert_eq!(written % 2, 0);
+        decode(&buf)
+    }
+
+    #[test]
+    fn defaults_round_trip() {
+        assert_eq!(round_trip(&Settings::default()), Settings::default());
+    }
+
+    #[test]
+    fn changes_round_trip() {
+        let mut settings = Settings { baud_rate: 1_000_000, ..Settings::default() };
+        settings.motors[1].invert_output = true;
+        settings.shaping[0].max_step = 0.05;
+        settings.pwm.frequency = 20_000;
+        settings.supply.nominal = 12.0;
+        settings.protection.current_limit = 2.5;
+        settings.servos[3].max_angle = -90.0;
+        settings.heading.gyro_weight = 0.5;
+        settings.estop.enabled = true;
+        settings.reference.reverse = true;
+        assert_eq!(round_trip(&settings), settings);
+    }
+
+    #[test]
+    fn erased_flash_gives_the_defaults() {
+        assert_eq!(decode(&[0xff; SETTINGS_SIZE]), Settings::default());
+    }
+
+    #[test]
+    fn a_different_layout_gives_the_defaults() {
+        let mut buf = [0xffu8; SETTINGS_SIZE];
+        encode(&Settings::default(), &mut buf);
+        // As if a build with fewer settings had saved them
+        buf[0] -= 4;
+        assert_eq!(decode(&buf), Settings::default());
+        // Or with a length that runs off the end
+        assert_eq!(decode(&[200, 0, 1, 2, 3]), Settings::default());
+    }
+}
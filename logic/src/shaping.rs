@@ -0,0 +1,218 @@
+use libm::fabsf;
+use protocol::OutputShaping;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Mode {
+    Free, Brake
+}
+
+/// What a motor's output should be set to
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Output {
+    Free,
+    Brake,
+    Drive(f32, Mode),
+}
+
+/// Shapes the duty asked of a motor, as its `OutputShaping` says, and moves
+/// the output towards it a step each control period.
+pub struct Shaper {
+    shaping: OutputShaping,
+    // None while the motor is freed or braked
+    target: Option<(f32, Mode)>,
+    // Where the output is now, after shaping
+    duty: f32,
+    // What the output was last set to
+    output: Output,
+}
+
+impl Shaper {
+    pub fn new() -> Self {
+        Shaper {
+            shaping: OutputShaping::default(),
+            target: None,
+            duty: 0.0,
+            output: Output::Free,
+        }
+    }
+
+    pub fn shaping(&self) -> OutputShaping {
+        self.shaping
+    }
+
+    /// Returns false if the shaping doesn't make sense
+    pub fn set_shaping(&mut self, shaping: OutputShaping) -> bool {
+        if !(shaping.max_step > 0.0
+            && shaping.min_duty >= 0.0
+            && shaping.min_duty < shaping.max_duty
+            && shaping.max_duty <= 1.0) {
+            return false;
+        }
+        self.shaping = shaping;
+        true
+    }
+
+    pub fn drive(&mut self, duty: f32, mode: Mode) {
+        self.target = Some((duty, mode));
+    }
+
+    /// Where the output is now, 0 while it's freed or braked
+    pub fn duty(&self) -> f32 {
+        self.duty
+    }
+
+    /// What the output was last set to, for setting it again when the
+    /// range of the duty changes under it
+    pub fn output(&self) -> Output {
+        self.output
+    }
+
+    /// For when the output has been freed or braked straight away
+    pub fn stop(&mut self, mode: Mode) {
+        self.target = None;
+        self.duty = 0.0;
+        self.output = match mode {
+            Mode::Free => Output::Free,
+            Mode::Brake => Output::Brake,
+        };
+    }
+
+    /// Call every control period. `scale` is what the supply says the duty
+    /// should be multiplied by, None if the motor should be freed. Says what
+    /// the output should be set to, if it's being driven.
+    pub fn step(&mut self, scale: Option<f32>) -> Option<Output> {
+        let (duty, mode) = self.target?;
+        let scale = match scale {
+            Some(scale) if scale.is_finite() => scale,
+            _ => {
+                // It starts again from nothing when it's let go
+                self.duty = 0.0;
+                self.output = Output::Free;
+                return Some(self.output);
+            },
+        };
+        let max_duty = self.shaping.max_duty;
+        let target = (self.shaped(duty) * scale).max(-max_duty).min(max_duty);
+        let max_step = self.shaping.max_step;
+        self.duty = target.max(self.duty - max_step).min(self.duty + max_step);
+        self.output = Output::Drive(self.duty, mode);
+        Some(self.output)
+    }
+
+    fn shaped(&self, duty: f32) -> f32 {
+        let magnitude = fabsf(duty).min(1.0);
+        if magnitude == 0.0 {
+            return 0.0;
+        }
+        let magnitude = self.shaping.min_duty
+            + magnitude * (self.shaping.max_duty - self.shaping.min_duty);
+        if duty < 0.0 { -magnitude } else { magnitude }
+    }
+}
+
+impl Default for Shaper {
+    fn default() -> Self {
+        Shaper::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn shaper(max_step: f32, min_duty: f32, max_duty: f32) -> Shaper {
+        let mut shaper = Shaper::new();
+        assert!(shaper.set_shaping(OutputShaping { max_step, min_duty, max_duty }));
+        shaper
+    }
+
+    fn close(output: Option<Output>, expected: f32) -> bool {
+        match output {
+            Some(Output::Drive(duty, _)) => fabsf(duty - expected) < 1e-6,
+            _ => false,
+        }
+    }
+
+    #[test]
+    fn steps_towards_the_duty() {
+        let mut shaper = shaper(0.25, 0.0, 1.0);
+        shaper.drive(0.6, Mode::Free);
+        for expected in [0.25, 0.5, 0.6, 0.6].iter() {
+            let output = shaper.step(Some(1.0));
+            assert!(close(output, *expected), "{:?}", output);
+        }
+        shaper.drive(-1.0, Mode::Brake);
+        let output = shaper.step(Some(1.0));
+        assert!(close(output, 0.35), "{:?}", output);
+        assert!(matches!(output, Some(Output::Drive(_, Mode::Brake))));
+    }
+
+    #[test]
+    fn offsets_and_clamps_the_duty() {
+        let mut shaper = shaper(2.0, 0.2, 0.8);
+        for (duty, expected) in [(0.5, 0.5), (0.0, 0.0), (-1.0, -0.8), (0.25, 0.35)].iter() {
+            shaper.drive(*duty, Mode::Free);
+            let output = shaper.step(Some(1.0));
+            assert!(close(output, *expected), "{} {:?}", duty, output);
+        }
+    }
+
+    #[test]
+    fn scales_for_the_supply_within_the_clamp() {
+        let mut shaper = shaper(2.0, 0.0, 0.8);
+        // 0.6 of the way to the 0.8 at full duty
+        shaper.drive(0.6, Mode::Free);
+        assert!(close(shaper.step(Some(0.5)), 0.24));
+        assert!(close(shaper.step(Some(2.0)), 0.8));
+    }
+
+    #[test]
+    fn frees_the_motor_without_a_scale() {
+        let mut shaper = shaper(0.5, 0.0, 1.0);
+        shaper.drive(1.0, Mode::Free);
+        shaper.step(Some(1.0));
+        for scale in [None, Some(f32::NAN), Some(f32::INFINITY)].iter() {
+            assert_eq!(shaper.step(*scale), Some(Output::Free));
+            assert_eq!(shaper.duty(), 0.0);
+        }
+        // It starts again from nothing
+        assert!(close(shaper.step(Some(1.0)), 0.5));
+    }
+
+    #[test]
+    fn leaves_a_stopped_motor_alone() {
+        let mut shaper = shaper(0.5, 0.0, 1.0);
+        assert_eq!(shaper.step(Some(1.0)), None);
+        shaper.drive(1.0, Mode::Free);
+        shaper.step(Some(1.0));
+        shaper.stop(Mode::Free);
+        assert_eq!(shaper.step(Some(1.0)), None);
+        assert_eq!(shaper.duty(), 0.0);
+    }
+
+    #[test]
+    fn remembers_the_output() {
+        let mut shaper = shaper(0.5, 0.0, 1.0);
+        assert_eq!(shaper.output(), Output::Free);
+        shaper.drive(-1.0, Mode::Brake);
+        shaper.step(Some(1.0));
+        assert_eq!(shaper.output(), Output::Drive(-0.5, Mode::Brake));
+        shaper.step(None);
+        assert_eq!(shaper.output(), Output::Free);
+        shaper.stop(Mode::Brake);
+        assert_eq!(shaper.output(), Output::Brake);
+        assert_eq!(shaper.step(Some(1.0)), None);
+        assert_eq!(shaper.output(), Output::Brake);
+    }
+
+    #[test]
+    fn refuses_shaping_that_makes_no_sense() {
+        let mut shaper = Shaper::new();
+        let default = OutputShaping::default();
+        assert!(!shaper.set_shaping(OutputShaping { max_step: 0.0, ..default }));
+        assert!(!shaper.set_shaping(OutputShaping { max_step: f32::NAN, ..default }));
+        assert!(!shaper.set_shaping(OutputShaping { min_duty: 0.9, max_duty: 0.5, ..default }));
+        assert!(!shaper.set_shaping(OutputShaping { max_duty: 1.5, ..default }));
+        assert_eq!(shaper.shaping(), default);
+    }
+}
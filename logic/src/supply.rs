@@ -0,0 +1,229 @@
+use protocol::{ SupplyConfig, SupplyState };
+
+// Volts at the pin for a full scale reading
+const REFERENCE: f32 = 3.3;
+const FULL_SCALE: f32 = 4095.0;
+// Each update moves the filtered voltage this far towards the reading:
+// a time constant of about 0.1s at the control period
+const SMOOTHING: f32 = 0.1;
+// How far back inside a limit the voltage has to come before the motors
+// are let go again, in volts
+const HYSTERESIS: f32 = 0.2;
+// The most the compensation will multiply the duty by, however far the
+// supply has dropped
+const MAX_COMPENSATION: f32 = 2.0;
+
+/// The motor supply, measured on PA4 through a divider. It decides how much
+/// of the duty asked for the motors get: none outside the limits, less
+/// when it's getting low, and more or less to make up for the supply being
+/// off its nominal voltage.
+pub struct Supply {
+    config: SupplyConfig,
+    // Filtered, in volts; None until the first reading
+    voltage: Option<f32>,
+    state: SupplyState,
+}
+
+impl Supply {
+    pub fn new() -> Self {
+        Supply {
+            config: SupplyConfig::default(),
+            voltage: None,
+            state: SupplyState::Normal,
+        }
+    }
+
+    pub fn config(&self) -> SupplyConfig {
+        self.config
+    }
+
+    /// Returns false if the limits don't make sense. Compensating needs an
+    /// undervoltage limit, so the duty isn't scaled up without end as the
+    /// supply goes.
+    pub fn configure(&mut self, config: SupplyConfig) -> bool {
+        if !(config.divider > 0.0 && config.divider.is_finite()
+            && config.undervoltage >= 0.0
+            && config.derate >= config.undervoltage
+            && config.overvoltage > config.derate && config.overvoltage.is_finite()
+            && config.nominal >= 0.0 && config.nominal.is_finite())
+            || (config.nominal > 0.0 && config.undervoltage == 0.0) {
+            return false;
+        }
+        self.config = config;
+        true
+    }
+
+    /// In volts, 0 before the first reading
+    pub fn voltage(&self) -> f32 {
+        self.voltage.unwrap_or(0.0)
+    }
+
+    pub fn state(&self) -> SupplyState {
+        self.state
+    }
+
+    /// Call every control period with the raw ADC reading
+    pub fn update(&mut self, raw: u16) {
+        let reading = raw as f32 * REFERENCE / FULL_SCALE * self.config.divider;
+        let voltage = match self.voltage {
+            Some(voltage) => voltage + (reading - voltage) * SMOOTHING,
+            None => reading,
+        };
+        self.voltage = Some(voltage);
+
+        let c = &self.config;
+        self.state = match self.state {
+            SupplyState::Undervoltage if voltage < c.undervoltage + HYSTERESIS =>
+                SupplyState::Undervoltage,
+            SupplyState::Overvoltage if voltage > c.overvoltage - HYSTERESIS =>
+                SupplyState::Overvoltage,
+            _ if voltage < c.undervoltage => SupplyState::Undervoltage,
+            _ if voltage > c.overvoltage => SupplyState::Overvoltage,
+            _ if voltage < c.derate => SupplyState::Derated,
+            _ => SupplyState::Normal,
+        };
+    }
+
+    /// What the duty asked of the motors should be multiplied by, or None if
+    /// they should be freed
+    pub fn duty_scale(&self) -> Option<f32> {
+        let voltage = self.voltage?;
+        if voltage <= 0.0 {
+            return None;
+        }
+        let c = &self.config;
+        let derating = match self.state {
+            SupplyState::Undervoltage | SupplyState::Overvoltage => return None,
+            SupplyState::Derated => (voltage - c.undervoltage) / (c.derate - c.undervoltage),
+            SupplyState::Normal => 1.0,
+        };
+        let compensation = match c.nominal {
+            nominal if nominal > 0.0 => (nominal / voltage).min(MAX_COMPENSATION),
+            _ => 1.0,
+        };
+        let scale = derating * compensation;
+        if !scale.is_finite() {
+            return None;
+        }
+        Some(scale)
+    }
+}
+
+impl Default for Supply {
+    fn default() -> Self {
+        Supply::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use libm::fabsf;
+
+    const CONFIG: SupplyConfig = SupplyConfig {
+        divider: 4.0,
+        undervoltage: 9.0,
+        derate: 10.0,
+        overvoltage: 14.0,
+        nominal: 0.0,
+    };
+
+    fn raw(config: &SupplyConfig, volts: f32) -> u16 {
+        (volts / config.divider / REFERENCE * FULL_SCALE + 0.5) as u16
+    }
+
+    // Long enough for the filter to get there
+    fn settle(supply: &mut Supply, volts: f32) {
+        let raw = raw(&supply.config(), volts);
+        for _ in 0..200 {
+            supply.update(raw);
+        }
+    }
+
+    fn supply(config: SupplyConfig) -> Supply {
+        let mut supply = Supply::new();
+        assert!(supply.configure(config));
+        supply
+    }
+
+    fn close(scale: Option<f32>, expected: f32) -> bool {
+        scale.is_some_and(|scale| fabsf(scale - expected) < 0.01)
+    }
+
+    #[test]
+    fn frees_the_motors_before_a_reading() {
+        assert_eq!(Supply::new().duty_scale(), None);
+    }
+
+    #[test]
+    fn leaves_the_duty_alone_in_range() {
+        let mut supply = supply(CONFIG);
+        settle(&mut supply, 12.0);
+        assert!(fabsf(supply.voltage() - 12.0) < 0.01);
+        assert_eq!(supply.state(), SupplyState::Normal);
+        assert!(close(supply.duty_scale(), 1.0));
+    }
+
+    #[test]
+    fn derates_towards_undervoltage() {
+        let mut supply = supply(CONFIG);
+        settle(&mut supply, 9.5);
+        assert_eq!(supply.state(), SupplyState::Derated);
+        assert!(close(supply.duty_scale(), 0.5));
+    }
+
+    #[test]
+    fn frees_the_motors_outside_the_limits() {
+        let mut supply = supply(CONFIG);
+        settle(&mut supply, 8.0);
+        assert_eq!(supply.state(), SupplyState::Undervoltage);
+        assert_eq!(supply.duty_scale(), None);
+        // Not quite far enough back
+        settle(&mut supply, 9.1);
+        assert_eq!(supply.state(), SupplyState::Undervoltage);
+        settle(&mut supply, 12.0);
+        assert_eq!(supply.state(), SupplyState::Normal);
+
+        settle(&mut supply, 15.0);
+        assert_eq!(supply.state(), SupplyState::Overvoltage);
+        assert_eq!(supply.duty_scale(), None);
+    }
+
+    #[test]
+    fn compensates_for_the_supply() {
+        let mut supply = supply(SupplyConfig { nominal: 12.0, ..CONFIG });
+        settle(&mut supply, 13.0);
+        assert!(close(supply.duty_scale(), 12.0 / 13.0));
+        settle(&mut supply, 11.0);
+        assert!(close(supply.duty_scale(), 12.0 / 11.0));
+    }
+
+    #[test]
+    fn compensation_is_capped() {
+        let config = SupplyConfig { undervoltage: 1.0, derate: 1.0, nominal: 12.0, ..CONFIG };
+        let mut supply = supply(config);
+        settle(&mut supply, 3.0);
+        assert!(close(supply.duty_scale(), MAX_COMPENSATION));
+    }
+
+    #[test]
+    fn no_supply_frees_the_motors() {
+        let mut supply = supply(SupplyConfig::default());
+        supply.update(0);
+        assert_eq!(supply.voltage(), 0.0);
+        assert_eq!(supply.duty_scale(), None);
+    }
+
+    #[test]
+    fn refuses_configs_that_make_no_sense() {
+        let mut supply = Supply::new();
+        assert!(!supply.configure(SupplyConfig { divider: 0.0, ..CONFIG }));
+        assert!(!supply.configure(SupplyConfig { derate: 8.0, ..CONFIG }));
+        assert!(!supply.configure(SupplyConfig { overvoltage: 10.0, ..CONFIG }));
+        assert!(!supply.configure(SupplyConfig { nominal: f32::NAN, ..CONFIG }));
+        assert!(!supply.configure(SupplyConfig { overvoltage: f32::INFINITY, ..CONFIG }));
+        // Compensation with nothing to stop it as the supply goes
+        assert!(!supply.configure(SupplyConfig { undervoltage: 0.0, derate: 0.0, nominal: 12.0, ..CONFIG }));
+        assert_eq!(supply.config(), SupplyConfig::default());
+    }
+}
@@ -1,6 +1,7 @@
 /* Linker script for the STM32F103C8T6 */
 MEMORY
 {
-  FLASH : ORIGIN = 0x08000000, LENGTH = 64K
+  /* The last 1K page of flash holds the settings */
+  FLASH : ORIGIN = 0x08000000, LENGTH = 63K
   RAM : ORIGIN = 0x20000000, LENGTH = 20K
 }
@@ -0,0 +1,154 @@
+use protocol::{
+    CaptureSample, CaptureState, CaptureTrigger,
+    CAPTURE_CHANNELS, CAPTURE_CHUNK, CAPTURE_LENGTH,
+};
+
+const LENGTH: usize = CAPTURE_LENGTH as usize;
+
+pub type CaptureBuffer = [CaptureSample; LENGTH];
+
+/// Records the raw encoder inputs like an oscilloscope: once armed, samples go
+/// round a ring buffer until `pre_trigger` of them are in and the trigger
+/// fires, then the rest of the buffer is filled and it stops.
+pub struct Capture {
+    buffer: &'static mut CaptureBuffer,
+    state: CaptureState,
+    trigger: CaptureTrigger,
+    pre_trigger: usize,
+    // Where the next sample goes
+    position: usize,
+    // Samples kept from before the trigger, up to `pre_trigger`
+    filled: usize,
+    // Samples still to record after the trigger
+    remaining: usize,
+    // Where the capture starts once it's complete
+    start: usize,
+    previous: Option<CaptureSample>,
+}
+
+impl Capture {
+    pub fn new(buffer: &'static mut CaptureBuffer) -> Self {
+        Capture {
+            buffer: buffer,
+            state: CaptureState::Idle,
+            trigger: CaptureTrigger::Immediate,
+            pre_trigger: 0,
+            position: 0,
+            filled: 0,
+            remaining: 0,
+            start: 0,
+            previous: None,
+        }
+    }
+
+    /// Starts a new capture, throwing away any previous one. Returns false if
+    /// the trigger's channel or the pre-trigger length is out of range.
+    pub fn arm(&mut self, trigger: CaptureTrigger, pre_trigger: u16) -> bool {
+        let channel = match trigger {
+            CaptureTrigger::Immediate => 0,
+            CaptureTrigger::Rising { channel, .. } => channel,
+            CaptureTrigger::Falling { channel, .. } => channel,
+            CaptureTrigger::Glitch { channel, .. } => channel,
+        };
+        if channel as usize >= CAPTURE_CHANNELS || pre_trigger >= CAPTURE_LENGTH {
+            return false;
+        }
+
+        self.state = CaptureState::Armed;
+        self.trigger = trigger;
+        self.pre_trigger = pre_trigger as usize;
+        self.position = 0;
+        self.filled = 0;
+        self.previous = None;
+        true
+    }
+
+    pub fn state(&self) -> CaptureState {
+        self.state
+    }
+
+    /// Samples recorded so far
+    pub fn length(&self) -> u16 {
+        match self.state {
+            CaptureState::Idle => 0,
+            CaptureState::Armed => self.filled as u16,
+            CaptureState::Triggered => (LENGTH - self.remaining) as u16,
+            CaptureState::Complete => CAPTURE_LENGTH,
+        }
+    }
+
+    /// Records a scan of (A, B) pairs, one for each encoder
+    pub fn record(&mut self, scan: &[(u16, u16)]) {
+        let mut sample = [0; CAPTURE_CHANNELS];
+        for (channels, pair) in sample.chunks_mut(2).zip(scan) {
+            channels[0] = pair.0;
+            channels[1] = pair.1;
+        }
+
+        if self.state == CaptureState::Armed
+            && self.filled == self.pre_trigger
+            && self.triggered(&sample) {
+            self.state = CaptureState::Triggered;
+            self.start = (self.position + LENGTH - self.filled) % LENGTH;
+            self.remaining = LENGTH - self.filled;
+        }
+
+        match self.state {
+            CaptureState::Armed => {
+                self.store(sample);
+                self.filled = (self.filled + 1).min(self.pre_trigger);
+            },
+            CaptureState::Triggered => {
+                self.store(sample);
+                self.remaining -= 1;
+                if self.remaining == 0 {
+                    self.state = CaptureState::Complete;
+                }
+            },
+            CaptureState::Idle | CaptureState::Complete => (),
+        }
+        self.previous = Some(sample);
+    }
+
+    /// Copies out up to `CAPTURE_CHUNK` samples from `offset` into a complete
+    /// capture, returning how many there were.
+    pub fn read(&self, offset: u16, samples: &mut [CaptureSample; CAPTURE_CHUNK]) -> Option<usize> {
+        if self.state != CaptureState::Complete || offset >= CAPTURE_LENGTH {
+            return None;
+        }
+        let count = (LENGTH - offset as usize).min(CAPTURE_CHUNK);
+        for (i, sample) in samples.iter_mut().take(count).enumerate() {
+            *sample = self.buffer[(self.start + offset as usize + i) % LENGTH];
+        }
+        Some(count)
+    }
+
+    fn store(&mut self, sample: CaptureSample) {
+        self.buffer[self.position] = sample;
+        self.position = (self.position + 1) % LENGTH;
+    }
+
+    fn triggered(&self, sample: &CaptureSample) -> bool {
+        let previous = match (self.trigger, self.previous) {
+            (CaptureTrigger::Immediate, _) => return true,
+            (_, None) => return false,
+            (_, Some(previous)) => previous,
+        };
+        match self.trigger {
+            CaptureTrigger::Immediate => true,
+            CaptureTrigger::Rising { channel, level } => {
+                let channel = channel as usize;
+                previous[channel] < level && sample[channel] >= level
+            },
+            CaptureTrigger::Falling { channel, level } => {
+                let channel = channel as usize;
+                previous[channel] >= level && sample[channel] < level
+            },
+            CaptureTrigger::Glitch { channel, threshold } => {
+                let channel = channel as usize;
+                let (a, b) = (previous[channel], sample[channel]);
+                a.max(b) - a.min(b) > threshold
+            },
+        }
+    }
+}
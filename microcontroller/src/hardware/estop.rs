@@ -0,0 +1,161 @@
+use embedded_hal::digital::v2::InputPin;
+use stm32f1xx_hal::{
+    gpio::{ ExtiPin, Floating, Input, PullDown, PullUp },
+    gpio::gpioa::{ CRH, PA8 },
+};
+use rtfm::cyccnt::Instant;
+use protocol::{ EStopConfig, EStopStatus };
+
+use super::motor::Mode;
+
+// Longer than this and it's not much of an emergency stop, in ms
+const MAX_DEBOUNCE: u16 = 1_000;
+
+/// The pin is handed over floating, and pulled towards the active level
+/// once it's configured
+pub type EStopPin = PA8<Input<Floating>>;
+
+// The pin, with its pull as it is now
+enum Pin {
+    PulledUp(PA8<Input<PullUp>>),
+    PulledDown(PA8<Input<PullDown>>),
+}
+
+impl Pin {
+    fn pulled(self, up: bool, crh: &mut CRH) -> Self {
+        match (self, up) {
+            (Pin::PulledDown(pin), true) => Pin::PulledUp(pin.into_pull_up_input(crh)),
+            (Pin::PulledUp(pin), false) => Pin::PulledDown(pin.into_pull_down_input(crh)),
+            (pin, _) => pin,
+        }
+    }
+
+    fn is_high(&self) -> bool {
+        match self {
+            Pin::PulledUp(pin) => pin.is_high().unwrap_or(true),
+            Pin::PulledDown(pin) => pin.is_high().unwrap_or(true),
+        }
+    }
+
+    fn clear_interrupt_pending_bit(&mut self) {
+        match self {
+            Pin::PulledUp(pin) => pin.clear_interrupt_pending_bit(),
+            Pin::PulledDown(pin) => pin.clear_interrupt_pending_bit(),
+        }
+    }
+}
+
+/// The emergency stop input, with an interrupt on both edges. It latches
+/// once the input's been active for the debounce time, and stays latched
+/// until it's cleared.
+pub struct EStop {
+    // Only ever None while its pull is being changed
+    pin: Option<Pin>,
+    // Nothing else needs GPIOA's CRH once the pins are set up
+    crh: CRH,
+    config: EStopConfig,
+    // Cycles of the system clock in a ms
+    cycles_per_ms: u32,
+    // When the input last changed
+    changed: Instant,
+    latched: bool,
+    // How the motors should be stopped, once it's latched
+    mode: Mode,
+    // Latched since the control loop last looked
+    unreported: bool,
+}
+
+impl EStop {
+    /// `pin` must already be an interrupt source on both edges
+    pub fn new(pin: EStopPin, mut crh: CRH, sysclk: u32) -> Self {
+        let mut estop = EStop {
+            pin: Some(Pin::PulledUp(pin.into_pull_up_input(&mut crh))),
+            crh: crh,
+            config: EStopConfig::default(),
+            cycles_per_ms: sysclk / 1000,
+            changed: Instant::now(),
+            latched: false,
+            mode: Mode::Free,
+            unreported: false,
+        };
+        estop.configure(EStopConfig::default());
+        estop
+    }
+
+    pub fn config(&self) -> EStopConfig {
+        self.config
+    }
+
+    /// Returns false if the debounce is too long
+    pub fn configure(&mut self, config: EStopConfig) -> bool {
+        if config.debounce_ms > MAX_DEBOUNCE {
+            return false;
+        }
+        // Towards the active level, so a broken wire stops the motors
+        let crh = &mut self.crh;
+        self.pin = self.pin.take().map(|pin| pin.pulled(config.active_high, crh));
+        self.config = config;
+        self.changed = Instant::now();
+        true
+    }
+
+    /// Whether the input is stopping the motors now
+    pub fn active(&self) -> bool {
+        self.config.enabled && self.pin.as_ref().map_or(true, Pin::is_high) == self.config.active_high
+    }
+
+    pub fn status(&self) -> EStopStatus {
+        EStopStatus {
+            active: self.active(),
+            latched: self.latched,
+        }
+    }
+
+    /// Call from the pin's interrupt. Returns true if the input has just
+    /// gone active, so it should be checked on again once the debounce time
+    /// is up.
+    pub fn edge(&mut self) -> bool {
+        if let Some(pin) = self.pin.as_mut() {
+            pin.clear_interrupt_pending_bit();
+        }
+        self.changed = Instant::now();
+        self.active() && !self.latched
+    }
+
+    /// Latches if the input's been active for the debounce time. Returns
+    /// true if it's only just latched.
+    pub fn check(&mut self) -> bool {
+        let debounce = self.config.debounce_ms as u32 * self.cycles_per_ms;
+        if self.latched || !self.active() || self.changed.elapsed().as_cycles() < debounce {
+            return false;
+        }
+        self.latched = true;
+        self.mode = if self.config.brake { Mode::Brake } else { Mode::Free };
+        self.unreported = true;
+        true
+    }
+
+    /// How the motors should be stopped, while it's latched
+    pub fn halt(&self) -> Option<Mode> {
+        match self.latched {
+            true => Some(self.mode),
+            false => None,
+        }
+    }
+
+    /// Returns false, and stays latched, if the input is still active
+    pub fn clear(&mut self) -> bool {
+        if self.active() {
+            return false;
+        }
+        self.latched = false;
+        true
+    }
+
+    /// Whether it's latched since this was last asked
+    pub fn take_unreported(&mut self) -> bool {
+        let unreported = self.unreported;
+        self.unreported = false;
+        unreported
+    }
+}
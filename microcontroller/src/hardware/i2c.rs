@@ -0,0 +1,29 @@
+use embedded_hal::blocking::i2c::{ Read, Write, WriteRead };
+use stm32f1xx_hal::{
+    gpio::{ Alternate, OpenDrain },
+    gpio::gpiob::{ PB10, PB11 },
+    i2c::{ self, BlockingI2c },
+    pac::I2C2,
+};
+
+/// The expansion bus, on PB10 (SCL) and PB11 (SDA)
+pub type I2cBus = BlockingI2c<I2C2, (PB10<Alternate<OpenDrain>>, PB11<Alternate<OpenDrain>>)>;
+
+pub const FREQUENCY: u32 = 400_000;
+// Timeouts, in microseconds, so a missing device or a bus with no pull-ups
+// gives an error rather than hanging
+pub const START_TIMEOUT: u32 = 1_000;
+pub const START_RETRIES: u8 = 10;
+pub const ADDRESS_TIMEOUT: u32 = 1_000;
+pub const DATA_TIMEOUT: u32 = 1_000;
+
+/// Writes `write` to the device at `address`, then reads into `read`, with
+/// a repeated start in between if there's both.
+pub fn transfer(bus: &mut I2cBus, address: u8, write: &[u8], read: &mut [u8]) -> Result<(), i2c::Error> {
+    match (write.is_empty(), read.is_empty()) {
+        (false, true) => bus.write(address, write),
+        (true, false) => bus.read(address, read),
+        (false, false) => bus.write_read(address, write, read),
+        (true, true) => Ok(()),
+    }
+}
@@ -1,159 +1,332 @@
-use core::{ 
-    u16,
-    option::{ Option },
-    mem::{ replace }
-};
-
-
 mod motor;
+// Only built into the board with the `reference` feature
+#[cfg(feature = "reference")]
+pub mod reference;
+// Without it there's never a reference decoder
+#[cfg(not(feature = "reference"))]
+pub mod reference {
+    use protocol::{ ReferenceComparison, ReferenceConfig };
+
+    pub enum Reference {}
+
+    impl Reference {
+        pub fn encoder(&self) -> usize { match *self {} }
+        pub fn reset(&mut self, _encoder: usize) { match *self {} }
+        pub fn config(&self) -> ReferenceConfig { match *self {} }
+        pub fn configure(&mut self, _config: ReferenceConfig) -> bool { match *self {} }
+        pub fn update(&mut self, _analog: f32) { match *self {} }
+        pub fn comparison(&self) -> ReferenceComparison { match *self {} }
+    }
+}
+// Only built into boards with the `servos` feature
+#[cfg(feature = "servos")]
+pub mod servo;
+// Without it there are never any servos
+#[cfg(not(feature = "servos"))]
+pub mod servo {
+    use protocol::ServoConfig;
+
+    pub enum Servos {}
+
+    impl Servos {
+        pub fn config(&self, _servo: usize) -> Option<ServoConfig> { match *self {} }
+        pub fn configure(&mut self, _servo: usize, _config: ServoConfig) -> bool { match *self {} }
+        pub fn angle(&self, _servo: usize) -> Option<f32> { match *self {} }
+        pub fn set(&mut self, _servo: usize, _angle: Option<f32>) -> bool { match *self {} }
+    }
+}
+pub mod sampling;
+pub mod pwm;
+pub mod protection;
+pub mod power;
+pub mod i2c;
+pub use logic::{ supply, imu, heading };
+pub mod estop;
+pub mod watchdog;
+#[cfg(feature = "usb")]
+mod usb;
+#[cfg(feature = "radio")]
+mod rf24;
+#[cfg(not(any(feature = "usb", feature = "radio", feature = "usart-interrupt")))]
+mod serial_dma;
+
+#[cfg(any(
+    all(feature = "usb", feature = "radio"),
+    all(feature = "usb", feature = "usart-interrupt"),
+    all(feature = "radio", feature = "usart-interrupt")))]
+compile_error!("Only one of the usb, radio and usart-interrupt command links can be used");
+
+#[cfg(all(feature = "pwm-dir", feature = "pwm-two-dir"))]
+compile_error!("Only one of the pwm-dir and pwm-two-dir motor drivers can be used");
+#[cfg(all(feature = "sensing", any(feature = "pwm-dir", feature = "pwm-two-dir")))]
+compile_error!("The sensing board has no motor drivers");
+#[cfg(all(feature = "servos", feature = "reference"))]
+compile_error!("The servos and the reference encoder both need TIM4, and PB6 and PB7");
+#[cfg(all(feature = "radio", feature = "pwm-two-dir"))]
+compile_error!("The pwm-two-dir drivers' second direction inputs on PB12 and PB13 are the radio's pins");
+
+// The board configuration. By default it's a differential drive: two motors
+// on TIM3, each with an encoder. With the `sensing` feature it's only an
+// encoder interface, and the motor and spare pins are five encoders' worth of
+// analog inputs.
+//
+// The motor drivers take two PWM inputs each by default (DRV8833 style). With
+// `pwm-dir` they take a PWM and a direction input (Cytron MDD style), and with
+// `pwm-two-dir` a PWM and two direction inputs (TB6612 or L298 style).
 
 use stm32f1::stm32f103;
 
 use stm32f1xx_hal::{
     prelude::*,
-    adc::{self, Adc, AdcDma, Scan, SetChannels },
-    dma::{ Transfer, W},
-    gpio::{ Alternate, Floating, Input, PushPull, Analog },
+    adc,
+    dma::DmaExt,
+    gpio::{ Analog, Edge, ExtiPin },
     gpio::gpioa::{ 
         PA0, // Quadrature ADC 
         PA1, // Quadrature ADC
         PA2, // Quadrature ADC
         PA3,  // Quadrature ADC
-        // PA4, // * Voltage
-        // PA5, // * Other ADC
-        // PA6, // * Motor PWM, TIM3
-        // PA7, // * Motor PWM, TIM3
-        // PA8, // * Other ADC | TIM1 CH1
-        PA9, // Serial Tx USART1
-        PA10, // Serial Tx USART1
-        // PA11, // * USB-
-        // PA12, // * USB+
-        // PA15, // * Power (SWIN)
+        PA4, // Supply voltage ADC | Quadrature ADC (sensing)
+        PA5, // Motor current ADC | Quadrature ADC (sensing)
+        // PA6, // * Motor PWM, TIM3 | Quadrature ADC (sensing)
+        // PA7, // * Motor PWM, TIM3 | Motor direction (pwm-dir, pwm-two-dir) | Quadrature ADC (sensing)
+        // PA8, // E-stop input, EXTI8 (no ADC on the F103; TIM1 CH1 paces the ADC without using the pin)
+        // PA9, // * Serial Tx USART1
+        // PA10, // * Serial Rx USART1
+        // PA11, // USB- (with the usb feature)
+        // PA12, // USB+ (with the usb feature)
+        // PA15, // * Power button (SWIN)
     },
     gpio::gpiob::{ 
-        // PB0, // * Motor PWM, TIM3
-        // PB1, // * Motor PWM, TIM3
-        // PB3, // * Power (SWOUT)
-        // PB4, // * RF24 CE
-        // PB5, // * RF24 CSN
-        // PB6, // * Servo TIM4 CH1, I2C1
-        // PB7, // * Servo TIM4 CH2, I2C1
-        // PB8, // * Servo TIM4 CH3
-        // PB9, // * Servo TIM4 CH4
-        // PB10, // * I2C for expansion I2C2
-        // PB11, // * I2C for expansion I2C2
-        // PB12, // * RF24 IRQ
-        PB13, // SCLK - RF24
-        PB14, // MISO - RF24
-        PB15, // MOSI - RF24
+        // PB0, // * Motor PWM, TIM3 | Quadrature ADC (sensing)
+        // PB1, // * Motor PWM, TIM3 | Motor direction (pwm-dir, pwm-two-dir) | Quadrature ADC (sensing)
+        // PB3, // * Power hold (SWOUT)
+        // PB4, // * RF24 CE (radio)
+        // PB5, // * RF24 CSN (radio)
+        // PB6, // * I2C1 | Servo TIM4 CH1 (servos) | Reference encoder A, TIM4 (reference)
+        // PB7, // * I2C1 | Servo TIM4 CH2 (servos) | Reference encoder B, TIM4 (reference)
+        // PB8, // * Servo TIM4 CH3 (servos)
+        // PB9, // * Servo TIM4 CH4 (servos)
+        // PB10, // * I2C for expansion I2C2 (SCL)
+        // PB11, // * I2C for expansion I2C2 (SDA)
+        // PB12, // * RF24 IRQ (radio) | Motor direction (pwm-two-dir)
+        // PB13, // * SCLK - RF24 (radio) | Motor direction (pwm-two-dir)
+        // PB14, // * MISO - RF24 (radio)
+        // PB15, // * MOSI - RF24 (radio)
     },
     pac,
-    pwm::{ PwmChannel, C1, C2, C3, C4 },
-    serial::{self, Serial},
-    spi::{ Spi },
-    stm32::{ TIM3, ADC1, SPI2 },
-    timer::{Tim3NoRemap, Timer},
+    timer::Timer,
+};
+// Pin modes only some boards and command links name
+#[cfg(not(feature = "usb"))]
+use stm32f1xx_hal::gpio::{ Alternate, Floating };
+#[cfg(any(not(feature = "usb"), not(feature = "sensing")))]
+use stm32f1xx_hal::gpio::Input;
+#[cfg(any(not(feature = "usb"), feature = "pwm-dir", feature = "pwm-two-dir"))]
+use stm32f1xx_hal::gpio::PushPull;
+#[cfg(any(feature = "radio", feature = "pwm-dir", feature = "pwm-two-dir"))]
+use stm32f1xx_hal::gpio::Output;
+// The nRF24L01 on SPI2, when the radio is the command link
+#[cfg(feature = "radio")]
+use stm32f1xx_hal::{
+    gpio::PullUp,
+    gpio::gpiob::{ PB4, PB5, PB14, PB15 },
+    spi::{ self, Spi, Mode as SpiMode, Phase, Polarity },
+    stm32::SPI2,
+};
+#[cfg(any(feature = "radio", feature = "pwm-two-dir"))]
+use stm32f1xx_hal::gpio::gpiob::{ PB12, PB13 };
+// USART1, unless the command link is USB or the radio
+#[cfg(not(any(feature = "usb", feature = "radio")))]
+use stm32f1xx_hal::{
+    gpio::gpioa::{ PA9, PA10 },
+    serial::{ self, Serial },
+};
+#[cfg(not(feature = "sensing"))]
+use stm32f1xx_hal::{
+    gpio::PullDown,
+    gpio::gpioc::{
+        // PC13, // LED
+        PC14, // Encoder 0 index
+        PC15, // Encoder 1 index
+    },
+    pwm::{ PwmChannel, C1, C3 },
+    stm32::TIM3,
+    timer::Tim3NoRemap,
+};
+#[cfg(not(any(feature = "sensing", feature = "pwm-dir", feature = "pwm-two-dir")))]
+use stm32f1xx_hal::pwm::{ C2, C4 };
+#[cfg(any(feature = "sensing", feature = "pwm-dir", feature = "pwm-two-dir"))]
+use stm32f1xx_hal::{
+    gpio::gpioa::PA7,
+    gpio::gpiob::PB1,
+};
+#[cfg(feature = "sensing")]
+use stm32f1xx_hal::{
+    gpio::gpioa::PA6,
+    gpio::gpiob::PB0,
 };
 use cortex_m::{ singleton};
+#[cfg(feature = "usb")]
+use stm32f1xx_hal::usb::{ Peripheral, UsbBus, UsbBusType };
+#[cfg(feature = "usb")]
+use usb_device::bus::UsbBusAllocator;
+
+#[cfg(feature = "usart-interrupt")]
+use crate::rpc::serial::SerialLink;
+#[cfg(feature = "radio")]
+use crate::rpc::packet::{ Packets, PacketLink };
 
+use crate::settings::{ Settings, SettingsStore };
+
+pub use motor::Mode;
 use motor::{ 
-    Differential, 
-    DcMotor, 
-    TwoPinDcMotorOut, 
+    Axes,
     AnalogRotaryEncoder, 
-    DifferentialQuadratureAnalogInput,
-    DifferentialQuadratureSamples
+    Shaper,
  };
-
+#[cfg(not(feature = "sensing"))]
+use motor::{ DifferentialOutputs, DigitalIndexes };
+#[cfg(not(any(feature = "sensing", feature = "pwm-dir", feature = "pwm-two-dir")))]
+use motor::TwoPinDcMotorOut;
+#[cfg(feature = "pwm-dir")]
+use motor::PwmDirDcMotorOut;
+#[cfg(feature = "pwm-two-dir")]
+use motor::PwmTwoDirDcMotorOut;
+
+#[cfg(not(any(feature = "usb", feature = "radio")))]
 type CommandUsart = stm32f103::USART1;
+#[cfg(not(any(feature = "usb", feature = "radio")))]
 type CommandSerial = Serial<CommandUsart, (PA9<Alternate<PushPull>>, PA10<Input<Floating>>)>;
+#[cfg(feature = "usart-interrupt")]
 pub type CommandTx = serial::Tx<CommandUsart>;
+#[cfg(feature = "usart-interrupt")]
 pub type CommandRx = serial::Rx<CommandUsart>;
 
-type RF24Spi = Spi<SPI2, (
-    PB13<Alternate<PushPull>>, 
-    PB14<Alternate<Input<Floating>>>, 
-    PB15<Alternate<PushPull>>)>;
-
-type LeftMotor = TwoPinDcMotorOut<PwmChannel<TIM3, C1>, PwmChannel<TIM3, C2>>;
-type RightMotor = TwoPinDcMotorOut<PwmChannel<TIM3, C3>, PwmChannel<TIM3, C4>>;
-
-impl motor::Avg for u16 { 
-    fn avg(a: u16, b: u16) -> u16 { (a+b)/2 }
+#[cfg(feature = "usart-interrupt")]
+pub type CommandLink = SerialLink<CommandTx, CommandRx>;
+#[cfg(not(any(feature = "usb", feature = "radio", feature = "usart-interrupt")))]
+pub type CommandLink = serial_dma::DmaSerialLink;
+#[cfg(not(any(feature = "usb", feature = "radio")))]
+pub const COMMAND_INTERRUPT: stm32f103::Interrupt = stm32f103::Interrupt::USART1;
+
+/// Whether USART1 can run at `baud_rate` from a clock of `pclk`. The divider
+/// has 12 bits of mantissa, and needs to be at least 1.
+#[cfg(not(any(feature = "usb", feature = "radio")))]
+pub fn baud_rate_possible(pclk: u32, baud_rate: u32) -> bool {
+    baud_rate != 0 && baud_rate <= pclk / 16 && pclk / baud_rate < 16 * 4096
 }
 
-impl motor::Sample for u16 { }
+#[cfg(feature = "usb")]
+pub type CommandLink = usb::UsbLink;
+#[cfg(feature = "usb")]
+pub const COMMAND_INTERRUPT: stm32f103::Interrupt = stm32f103::Interrupt::USB_LP_CAN_RX0;
 
-impl SetChannels<QuadratureAdcPins> for Adc<ADC1> {
-    fn set_samples(&mut self) {
-        self.set_channel_sample_time(0, adc::SampleTime::T_28);
-        self.set_channel_sample_time(1, adc::SampleTime::T_28);
-        self.set_channel_sample_time(2, adc::SampleTime::T_28);
-        self.set_channel_sample_time(3, adc::SampleTime::T_28);
-    }
-
-    fn set_sequence(&mut self) {
-        self.set_regular_sequence(&[0, 1, 2, 3]);
-    }
-}
+#[cfg(feature = "radio")]
+pub type CommandLink = PacketLink<Radio>;
+#[cfg(feature = "radio")]
+pub const COMMAND_INTERRUPT: stm32f103::Interrupt = stm32f103::Interrupt::EXTI15_10;
 
-pub struct DifferentialQuadratureIdle<PINS> {
-    buf: &'static mut [u16; 4],
-    adc_dma: AdcDma<PINS, Scan>,
-}
+#[cfg(feature = "radio")]
+type RF24Spi = Spi<SPI2, (
+    PB13<Alternate<PushPull>>, 
+    PB14<Input<Floating>>, 
+    PB15<Alternate<PushPull>>)>;
 
-pub struct DifferentialQuadratureScanning<PINS> {
-    transfer: Transfer<W, &'static mut [u16; 4], AdcDma<PINS, Scan>>,
+/// The nRF24L01 on SPI2, with its IRQ line on EXTI12
+#[cfg(feature = "radio")]
+pub struct Radio {
+    rf24: rf24::Rf24<RF24Spi, PB4<Output<PushPull>>, PB5<Output<PushPull>>>,
+    irq: PB12<Input<PullUp>>,
 }
 
-pub enum DifferentialQuadratureScan<PINS> {
-    None,
-    Idle(DifferentialQuadratureIdle<PINS>),
-    Scanning(DifferentialQuadratureScanning<PINS>)
-}
+#[cfg(feature = "radio")]
+impl Packets for Radio {
+    type Error = rf24::Error<spi::Error>;
 
-impl <PINS> DifferentialQuadratureAnalogInput<u16> for DifferentialQuadratureScan<PINS> {
-    // This checks to see if there is a transfer already in progress, and then if
-    // that transfer is done
-    fn read_nb(&mut self) -> Option<DifferentialQuadratureSamples<u16>> {
-        let state = replace(self, Self::None);
-        match state {
-            Self::None => None,
-
-            Self::Idle(idle) => {
-                *self = Self::Scanning( 
-                    DifferentialQuadratureScanning { transfer: idle.adc_dma.read(idle.buf), });
-                None
-            },
+    fn mtu(&self) -> usize { self.rf24.mtu() }
 
-            Self::Scanning(scanning) if scanning.transfer.is_done() => {
-                let (buf, adc_dma) = scanning.transfer.wait();
-                let samples = DifferentialQuadratureSamples {
-                    left: (buf[0], buf[1]),
-                    right: (buf[2], buf[3])
-                };
-                *self = Self::Scanning(
-                    DifferentialQuadratureScanning { transfer: adc_dma.read(buf) });
-                Some(samples)
-            },
+    fn send(&mut self, packet: &[u8]) -> nb::Result<(), Self::Error> {
+        self.rf24.send(packet)
+    }
 
-            Self::Scanning(scanning) => {
-                *self = Self::Scanning( 
-                    DifferentialQuadratureScanning { transfer: scanning.transfer });
-                None
-            }
-        }
+    fn recv(&mut self, packet: &mut [u8]) -> nb::Result<usize, Self::Error> {
+        self.irq.clear_interrupt_pending_bit();
+        self.rf24.recv(packet)
     }
 }
 
+#[cfg(not(any(feature = "sensing", feature = "pwm-dir", feature = "pwm-two-dir")))]
+type LeftMotor = TwoPinDcMotorOut<PwmChannel<TIM3, C1>, PwmChannel<TIM3, C2>>;
+#[cfg(not(any(feature = "sensing", feature = "pwm-dir", feature = "pwm-two-dir")))]
+type RightMotor = TwoPinDcMotorOut<PwmChannel<TIM3, C3>, PwmChannel<TIM3, C4>>;
+// The PWM on TIM3 CH1 and CH3, and the direction on PA7 and PB1
+#[cfg(feature = "pwm-dir")]
+type LeftMotor = PwmDirDcMotorOut<PwmChannel<TIM3, C1>, PA7<Output<PushPull>>>;
+#[cfg(feature = "pwm-dir")]
+type RightMotor = PwmDirDcMotorOut<PwmChannel<TIM3, C3>, PB1<Output<PushPull>>>;
+// As pwm-dir, with the second direction inputs on PB12 and PB13
+#[cfg(feature = "pwm-two-dir")]
+type LeftMotor = PwmTwoDirDcMotorOut<PwmChannel<TIM3, C1>, PA7<Output<PushPull>>, PB12<Output<PushPull>>>;
+#[cfg(feature = "pwm-two-dir")]
+type RightMotor = PwmTwoDirDcMotorOut<PwmChannel<TIM3, C3>, PB1<Output<PushPull>>, PB13<Output<PushPull>>>;
+
+#[cfg(not(feature = "sensing"))]
+type MotorOuts = DifferentialOutputs<LeftMotor, RightMotor>;
+#[cfg(feature = "sensing")]
+type MotorOuts = ();
+
+pub type Sampler = sampling::Sampler<QuadratureAdcPins>;
+// Digital index pulses on the default board; the sensing board has no spare
+// pins for them
+#[cfg(not(feature = "sensing"))]
+type Indexes = DigitalIndexes<PC14<Input<PullDown>>, PC15<Input<PullDown>>>;
+#[cfg(feature = "sensing")]
+type Indexes = ();
+
+pub type Motors = Axes<u16, MotorOuts, Indexes>;
+pub type Encoder = motor::AnalogRotaryEncoder<u16>;
+
+// With the supply voltage and the motor current after the encoders
+#[cfg(not(feature = "sensing"))]
+pub struct QuadratureAdcPins(
+    PA0<Analog>, PA1<Analog>, PA2<Analog>, PA3<Analog>, PA4<Analog>, PA5<Analog>);
+#[cfg(feature = "sensing")]
+pub struct QuadratureAdcPins(
+    PA0<Analog>, PA1<Analog>, PA2<Analog>, PA3<Analog>, PA4<Analog>,
+    PA5<Analog>, PA6<Analog>, PA7<Analog>, PB0<Analog>, PB1<Analog>);
+
+// The ADC channels of each encoder's A and B inputs, then the auxiliary ones
+#[cfg(not(feature = "sensing"))]
+const A_CHANNELS: [u8; sampling::RANKS] = [0, 2, 4];
+#[cfg(not(feature = "sensing"))]
+const B_CHANNELS: [u8; sampling::RANKS] = [1, 3, 5];
+#[cfg(feature = "sensing")]
+const A_CHANNELS: [u8; sampling::RANKS] = [0, 2, 4, 6, 8];
+#[cfg(feature = "sensing")]
+const B_CHANNELS: [u8; sampling::RANKS] = [1, 3, 5, 7, 9];
+
+
+pub struct Hardware {
+    pub command_link: CommandLink,
+    pub motors: Motors,
+    pub reference: Option<reference::Reference>,
+    pub servos: Option<servo::Servos>,
+    pub power: power::Power,
+    pub i2c: i2c::I2cBus,
+    pub imu: Option<imu::Imu>,
+    pub heading: heading::HeadingFilter,
+    pub estop: estop::EStop,
+    pub watchdog: watchdog::Watchdog,
+    pub pwm: Option<pwm::PwmTimer>,
+    pub supply: Option<supply::Supply>,
+    pub protection: Option<protection::Protection>,
+    pub sampler: Sampler,
+    pub settings_store: SettingsStore,
+    pub settings: Settings,
+}
 
-pub type Quadrature = DifferentialQuadratureScan<QuadratureAdcPins>;
-pub type Motors = Differential<LeftMotor, RightMotor, u16, Quadrature>;
-
-pub struct QuadratureAdcPins(PA0<Analog>, PA1<Analog>, PA2<Analog>, PA3<Analog>);
-
-
-pub fn hardware<'a>() -> (CommandSerial, Motors) {
+pub fn hardware() -> Hardware {
     // Get access to the device specific peripherals from the peripheral access crate
     let peripherals = pac::Peripherals::take().unwrap();
     // Take ownership over the raw flash and rcc devices and convert them into the corresponding
@@ -163,7 +336,16 @@ pub fn hardware<'a>() -> (CommandSerial, Motors) {
 
     // Freeze the configuration of all the clocks in the system and store the frozen frequencies in
     // `clocks`
-    let clocks = rcc.cfgr.use_hse(8.mhz()).freeze(&mut flash.acr);
+    // 72MHz gives the USB peripheral its 48MHz and lets the USART run fast
+    let clocks = rcc.cfgr
+        .use_hse(8.mhz())
+        .sysclk(72.mhz())
+        .pclk1(36.mhz())
+        .adcclk(12.mhz())
+        .freeze(&mut flash.acr);
+
+    let mut settings_store = SettingsStore::new(flash);
+    let settings = settings_store.load();
 
     // Prepare the alternate function I/O registers
     let mut afio = peripherals.AFIO.constrain(&mut rcc.apb2);
@@ -171,56 +353,367 @@ pub fn hardware<'a>() -> (CommandSerial, Motors) {
     // Prepare the GPIO peripherals
     let mut gpioa = peripherals.GPIOA.split(&mut rcc.apb2);
     let mut gpiob = peripherals.GPIOB.split(&mut rcc.apb2);
+    #[cfg(not(feature = "sensing"))]
+    let mut gpioc = peripherals.GPIOC.split(&mut rcc.apb2);
+
+    // PB3, PB4 and PA15 are JTAG pins until we take them back; PB4 is only
+    // the radio's
+    #[cfg(feature = "radio")]
+    let (pa15, pb3, pb4) = afio.mapr.disable_jtag(gpioa.pa15, gpiob.pb3, gpiob.pb4);
+    #[cfg(not(feature = "radio"))]
+    let (pa15, pb3, _) = afio.mapr.disable_jtag(gpioa.pa15, gpiob.pb3, gpiob.pb4);
+
+    // Keep the power on, now we're up
+    let power = power::Power::new(
+        pa15.into_pull_up_input(&mut gpioa.crh),
+        pb3.into_push_pull_output(&mut gpiob.crl));
+
+    let dma1 = peripherals.DMA1.split(&mut rcc.ahb);
+
+    // USART1. A saved baud rate it can't do would leave no way to talk to
+    // it, so that gets the default.
+    #[cfg(not(any(feature = "usb", feature = "radio")))]
+    let settings = if baud_rate_possible(clocks.pclk2().0, settings.baud_rate) {
+        settings
+    } else {
+        Settings { baud_rate: Settings::default().baud_rate, ..settings }
+    };
 
-    // USART1
-    let tx = gpioa.pa9.into_alternate_push_pull(&mut gpioa.crl);
-    let rx = gpioa.pa10;
-    
-    let command_serial = Serial::usart1(
-        peripherals.USART1,
-        (tx, rx),
-        &mut afio.mapr,
-        serial::Config::default().baudrate(115200.bps()),
-        clocks,
-        &mut rcc.apb2,
-    );
+    #[cfg(not(any(feature = "usb", feature = "radio")))]
+    let command_serial: CommandSerial = {
+        let tx = gpioa.pa9.into_alternate_push_pull(&mut gpioa.crh);
+        let rx = gpioa.pa10;
+
+        Serial::usart1(
+            peripherals.USART1,
+            (tx, rx),
+            &mut afio.mapr,
+            serial::Config::default().baudrate(settings.baud_rate.bps()),
+            clocks,
+            &mut rcc.apb2,
+        )
+    };
 
-    let motor_pwm_pins = (
-        gpioa.pa6.into_alternate_push_pull(&mut gpioa.crl),
-        gpioa.pa7.into_alternate_push_pull(&mut gpioa.crl),
-        gpiob.pb0.into_alternate_push_pull(&mut gpiob.crl),
-        gpiob.pb1.into_alternate_push_pull(&mut gpiob.crl),
-    );
+    #[cfg(feature = "usart-interrupt")]
+    let command_link = {
+        let (mut tx, mut rx) = command_serial.split();
+        rx.listen();
+        tx.listen();
+        SerialLink::new(tx, rx)
+    };
+
+    #[cfg(not(any(feature = "usb", feature = "radio", feature = "usart-interrupt")))]
+    let command_link = {
+        let (usart, pins) = command_serial.release();
+        serial_dma::DmaSerialLink::new(
+            usart,
+            pins,
+            clocks.pclk2().0,
+            settings.baud_rate,
+            dma1.5,
+            dma1.4,
+            singleton!(: [u8; serial_dma::RX_BUFFER] = [0; serial_dma::RX_BUFFER]).unwrap(),
+            singleton!(: [u8; serial_dma::TX_BUFFER] = [0; serial_dma::TX_BUFFER]).unwrap())
+    };
+
+    // USB CDC-ACM
+    #[cfg(feature = "usb")]
+    let command_link = {
+        assert!(clocks.usbclk_valid());
+
+        // Pull D+ low for a moment so the host sees us re-enumerate after a reset
+        let mut usb_dp = gpioa.pa12.into_push_pull_output(&mut gpioa.crh);
+        usb_dp.set_low().unwrap();
+        cortex_m::asm::delay(clocks.sysclk().0 / 100);
+
+        let usb = Peripheral {
+            usb: peripherals.USB,
+            pin_dm: gpioa.pa11,
+            pin_dp: usb_dp.into_floating_input(&mut gpioa.crh),
+        };
+        let bus: &'static UsbBusAllocator<UsbBusType> =
+            singleton!(: UsbBusAllocator<UsbBusType> = UsbBus::new(usb)).unwrap();
+        usb::UsbLink::new(bus)
+    };
 
-    let (c1, c2, c3, c4) = Timer::tim3(peripherals.TIM3, &clocks, &mut rcc.apb1)
-        .pwm::<Tim3NoRemap, _, _, _>(motor_pwm_pins, &mut afio.mapr, 10.khz()).split();
+    // nRF24L01 on SPI2
+    #[cfg(feature = "radio")]
+    let command_link = {
+        let pins = (
+            gpiob.pb13.into_alternate_push_pull(&mut gpiob.crh),
+            gpiob.pb14.into_floating_input(&mut gpiob.crh),
+            gpiob.pb15.into_alternate_push_pull(&mut gpiob.crh),
+        );
+        let mode = SpiMode { polarity: Polarity::IdleLow, phase: Phase::CaptureOnFirstTransition };
+        let spi = Spi::spi2(peripherals.SPI2, pins, mode, 8.mhz(), clocks, &mut rcc.apb1);
+        let ce = pb4.into_push_pull_output(&mut gpiob.crl);
+        let csn = gpiob.pb5.into_push_pull_output(&mut gpiob.crl);
+
+        let mut irq = gpiob.pb12.into_pull_up_input(&mut gpiob.crh);
+        irq.make_interrupt_source(&mut afio);
+        irq.trigger_on_edge(&peripherals.EXTI, Edge::FALLING);
+        irq.enable_interrupt(&peripherals.EXTI);
+
+        let mut rf24 = rf24::Rf24::new(spi, ce, csn, &rf24::Rf24Config::default()).unwrap();
+        cortex_m::asm::delay(clocks.sysclk().0 / 500);
+        rf24.listen().unwrap();
+        PacketLink::new(Radio { rf24: rf24, irq: irq })
+    };
 
-    let quadrature_adc = adc::Adc::adc1(peripherals.ADC1, &mut rcc.apb2, clocks);
-    let quadrature_channels = QuadratureAdcPins(
+    #[cfg(not(feature = "sensing"))]
+    let (motor_outs, quadrature_channels) = {
+        #[cfg(not(any(feature = "pwm-dir", feature = "pwm-two-dir")))]
+        let motor_outs = {
+            let motor_pwm_pins = (
+                gpioa.pa6.into_alternate_push_pull(&mut gpioa.crl),
+                gpioa.pa7.into_alternate_push_pull(&mut gpioa.crl),
+                gpiob.pb0.into_alternate_push_pull(&mut gpiob.crl),
+                gpiob.pb1.into_alternate_push_pull(&mut gpiob.crl),
+            );
+
+            let (c1, c2, c3, c4) = Timer::tim3(peripherals.TIM3, &clocks, &mut rcc.apb1)
+                .pwm::<Tim3NoRemap, _, _, _>(motor_pwm_pins, &mut afio.mapr, 10.khz()).split();
+
+            MotorOuts {
+                left: LeftMotor { out1: c1, out2: c2, inverted: false },
+                right: RightMotor { out1: c3, out2: c4, inverted: false },
+            }
+        };
+
+        #[cfg(any(feature = "pwm-dir", feature = "pwm-two-dir"))]
+        let (c1, c3) = {
+            let motor_pwm_pins = (
+                gpioa.pa6.into_alternate_push_pull(&mut gpioa.crl),
+                gpiob.pb0.into_alternate_push_pull(&mut gpiob.crl),
+            );
+            Timer::tim3(peripherals.TIM3, &clocks, &mut rcc.apb1)
+                .pwm::<Tim3NoRemap, _, _, _>(motor_pwm_pins, &mut afio.mapr, 10.khz()).split()
+        };
+
+        #[cfg(feature = "pwm-dir")]
+        let motor_outs = MotorOuts {
+            left: LeftMotor {
+                pwm: c1,
+                dir: gpioa.pa7.into_push_pull_output(&mut gpioa.crl),
+                inverted: false,
+            },
+            right: RightMotor {
+                pwm: c3,
+                dir: gpiob.pb1.into_push_pull_output(&mut gpiob.crl),
+                inverted: false,
+            },
+        };
+
+        #[cfg(feature = "pwm-two-dir")]
+        let motor_outs = MotorOuts {
+            left: LeftMotor {
+                pwm: c1,
+                in1: gpioa.pa7.into_push_pull_output(&mut gpioa.crl),
+                in2: gpiob.pb12.into_push_pull_output(&mut gpiob.crh),
+                inverted: false,
+            },
+            right: RightMotor {
+                pwm: c3,
+                in1: gpiob.pb1.into_push_pull_output(&mut gpiob.crl),
+                in2: gpiob.pb13.into_push_pull_output(&mut gpiob.crh),
+                inverted: false,
+            },
+        };
+        let quadrature_channels = QuadratureAdcPins(
+            gpioa.pa0.into_analog(&mut gpioa.crl),
+            gpioa.pa1.into_analog(&mut gpioa.crl),
+            gpioa.pa2.into_analog(&mut gpioa.crl),
+            gpioa.pa3.into_analog(&mut gpioa.crl),
+            gpioa.pa4.into_analog(&mut gpioa.crl),
+            gpioa.pa5.into_analog(&mut gpioa.crl),
+        );
+        (motor_outs, quadrature_channels)
+    };
+
+    // The HAL sets the PWM going at its default; this takes it from there
+    #[cfg(not(feature = "sensing"))]
+    let pwm = {
+        let mut pwm = pwm::PwmTimer::new(clocks.pclk1_tim().0);
+        if !pwm.set(settings.pwm) {
+            pwm.set(Default::default());
+        }
+        pwm.set_trigger(settings.sample_trigger);
+        Some(pwm)
+    };
+    #[cfg(feature = "sensing")]
+    let pwm: Option<pwm::PwmTimer> = None;
+
+    #[cfg(not(feature = "sensing"))]
+    let supply = {
+        let mut supply = supply::Supply::new();
+        supply.configure(settings.supply);
+        Some(supply)
+    };
+    #[cfg(feature = "sensing")]
+    let supply = None;
+
+    #[cfg(not(feature = "sensing"))]
+    let protection = {
+        let mut protection = protection::Protection::new();
+        protection.configure(settings.protection);
+        Some(protection)
+    };
+    #[cfg(feature = "sensing")]
+    let protection = None;
+
+    #[cfg(feature = "sensing")]
+    let (motor_outs, quadrature_channels) = ((), QuadratureAdcPins(
         gpioa.pa0.into_analog(&mut gpioa.crl),
         gpioa.pa1.into_analog(&mut gpioa.crl),
         gpioa.pa2.into_analog(&mut gpioa.crl),
-        gpioa.pa3.into_analog(&mut gpioa.crl)
-    );
+        gpioa.pa3.into_analog(&mut gpioa.crl),
+        gpioa.pa4.into_analog(&mut gpioa.crl),
+        gpioa.pa5.into_analog(&mut gpioa.crl),
+        gpioa.pa6.into_analog(&mut gpioa.crl),
+        gpioa.pa7.into_analog(&mut gpioa.crl),
+        gpiob.pb0.into_analog(&mut gpiob.crl),
+        gpiob.pb1.into_analog(&mut gpiob.crl),
+    ));
+
+    let quadrature_adc1 = adc::Adc::adc1(peripherals.ADC1, &mut rcc.apb2, clocks);
+    let quadrature_adc2 = adc::Adc::adc2(peripherals.ADC2, &mut rcc.apb2, clocks);
+
+    let buffer = singleton!(: [sampling::Block; 2] = [[0; sampling::BLOCK * sampling::RANKS]; 2]).unwrap();
+    let sample_timer = Timer::tim1(peripherals.TIM1, &clocks, &mut rcc.apb2)
+        .start_count_down(sampling::SAMPLE_RATE.hz())
+        .release();
+    let mut sampler = Sampler::new(
+        quadrature_adc1,
+        quadrature_adc2,
+        quadrature_channels,
+        &A_CHANNELS,
+        &B_CHANNELS,
+        dma1.1,
+        buffer,
+        sample_timer,
+        clocks.sysclk().0);
+    if let Some(pwm) = pwm.as_ref() {
+        sampler.set_trigger_offset(pwm.sample_offset());
+    }
+
+    #[cfg(not(feature = "sensing"))]
+    let indexes = DigitalIndexes(
+        gpioc.pc14.into_pull_down_input(&mut gpioc.crh),
+        gpioc.pc15.into_pull_down_input(&mut gpioc.crh));
+    #[cfg(feature = "sensing")]
+    let indexes = ();
+
+    let mut motors = Motors {
+        encoders: heapless::Vec::new(),
+        motors: motor_outs,
+        indexes: indexes,
+        shapers: heapless::Vec::new(),
+        halted: None,
+    };
+    for i in 0..sampling::ENCODERS {
+        motors.encoders.push(AnalogRotaryEncoder::new(sampling::MID_SCALE, sampling::delay(i))).ok();
+        motors.shapers.push(Shaper::new()).ok();
+        motors.configure(i, &settings.motors[i]);
+        motors.shape(i, settings.shaping[i]);
+    }
 
-    let dma_ch1 = peripherals.DMA1.split(&mut rcc.ahb).1;
-    let quadrature = Quadrature::Idle(DifferentialQuadratureIdle {
-            adc_dma: quadrature_adc.with_scan_dma(quadrature_channels, dma_ch1), 
-            buf: singleton!(: [u16; 4] = [0; 4]).unwrap(),
+    // A digital encoder counted by TIM4, to check encoder 0 against
+    #[cfg(feature = "reference")]
+    let reference = {
+        let qei = Timer::tim4(peripherals.TIM4, &clocks, &mut rcc.apb1)
+            .qei::<stm32f1xx_hal::timer::Tim4NoRemap, _>(
+                (gpiob.pb6, gpiob.pb7),
+                &mut afio.mapr,
+                stm32f1xx_hal::qei::QeiOptions::default());
+        let mut reference = reference::Reference::new(qei, 0);
+        if !reference.configure(settings.reference) {
+            reference.configure(Default::default());
         }
-    );
-
-    let motors = Motors {
-        left: DcMotor { 
-            out: LeftMotor { out1: c1, out2: c2 }, 
-            encoder: AnalogRotaryEncoder::new(u16::MAX/2),
-        }, 
-        right: DcMotor { 
-            out: RightMotor { out1: c3, out2: c4 },
-            encoder: AnalogRotaryEncoder::new(u16::MAX/2),
-        },
-        input: quadrature,
+        Some(reference)
+    };
+    #[cfg(not(feature = "reference"))]
+    let reference = None;
+
+    // The e-stop, interrupting on both edges so it can stop the motors
+    // between control periods
+    let estop = {
+        let mut pin = gpioa.pa8.into_floating_input(&mut gpioa.crh);
+        pin.make_interrupt_source(&mut afio);
+        pin.trigger_on_edge(&peripherals.EXTI, Edge::RISING_FALLING);
+        pin.enable_interrupt(&peripherals.EXTI);
+        let mut estop = estop::EStop::new(pin, gpioa.crh, clocks.sysclk().0);
+        if !estop.configure(settings.estop) {
+            estop.configure(Default::default());
+        }
+        estop
     };
 
-    return (command_serial, motors);
+    // The expansion bus, and the IMU on it if there is one
+    let mut expansion = stm32f1xx_hal::i2c::BlockingI2c::i2c2(
+        peripherals.I2C2,
+        (
+            gpiob.pb10.into_alternate_open_drain(&mut gpiob.crh),
+            gpiob.pb11.into_alternate_open_drain(&mut gpiob.crh),
+        ),
+        stm32f1xx_hal::i2c::Mode::Fast {
+            frequency: i2c::FREQUENCY.hz(),
+            duty_cycle: stm32f1xx_hal::i2c::DutyCycle::Ratio2to1,
+        },
+        clocks,
+        &mut rcc.apb1,
+        i2c::START_TIMEOUT,
+        i2c::START_RETRIES,
+        i2c::ADDRESS_TIMEOUT,
+        i2c::DATA_TIMEOUT);
+    // Give the IMU time to start up after the power comes on
+    cortex_m::asm::delay(clocks.sysclk().0 / 20);
+    let imu = imu::Imu::new(&mut expansion);
+
+    let mut heading = heading::HeadingFilter::new();
+    heading.configure(settings.heading);
+
+    // Hobby servos on TIM4, which the HAL sets going at 50Hz with every
+    // channel idle
+    #[cfg(feature = "servos")]
+    let servos = {
+        let pins = (
+            gpiob.pb6.into_alternate_push_pull(&mut gpiob.crl),
+            gpiob.pb7.into_alternate_push_pull(&mut gpiob.crl),
+            gpiob.pb8.into_alternate_push_pull(&mut gpiob.crh),
+            gpiob.pb9.into_alternate_push_pull(&mut gpiob.crh),
+        );
+        let outputs = Timer::tim4(peripherals.TIM4, &clocks, &mut rcc.apb1)
+            .pwm::<stm32f1xx_hal::timer::Tim4NoRemap, _, _, _>(
+                pins, &mut afio.mapr, servo::FREQUENCY.hz())
+            .split();
+        let mut servos = servo::Servos::new(outputs);
+        for (i, config) in settings.servos.iter().enumerate() {
+            servos.configure(i, *config);
+        }
+        Some(servos)
+    };
+    #[cfg(not(feature = "servos"))]
+    let servos = None;
+
+    // Last, so the rest of this doesn't have to feed it
+    let watchdog = watchdog::Watchdog::new(peripherals.IWDG, &peripherals.DBGMCU);
+
+    return Hardware {
+        command_link: command_link,
+        motors: motors,
+        reference: reference,
+        servos: servos,
+        power: power,
+        i2c: expansion,
+        imu: imu,
+        heading: heading,
+        estop: estop,
+        watchdog: watchdog,
+        pwm: pwm,
+        supply: supply,
+        protection: protection,
+        sampler: sampler,
+        settings_store: settings_store,
+        settings: settings,
+    };
 }
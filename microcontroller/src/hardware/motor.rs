@@ -1,45 +1,86 @@
 
 // use super::super::int_pid::IntPid;
-use core::cmp::{ min, max };
-use core::option::Option;
+#[cfg(any(feature = "pwm-dir", feature = "pwm-two-dir"))]
+use libm::fabsf;
+#[cfg(not(feature = "sensing"))]
 use embedded_hal::PwmPin;
+#[cfg(not(feature = "sensing"))]
+use embedded_hal::digital::v2::InputPin;
+#[cfg(any(feature = "pwm-dir", feature = "pwm-two-dir"))]
+use embedded_hal::digital::v2::OutputPin;
+use heapless::{ Vec, consts::U5 };
+use protocol::{ MotorConfig, OutputShaping };
+use logic::shaping::Output;
+pub use logic::shaping::{ Mode, Shaper };
+pub use logic::encoder::{ AnalogRotaryEncoder, Sample };
 // use stm32f1xx_hal::prelude::_embedded_hal_PwmPin as PwmPin;
 
-pub enum Mode {
-    Free, Brake
-}
-
 pub trait DcMotorOut {
     fn free(&mut self);
     fn brake(&mut self);
     fn drive(&mut self, duty: f32, mode: Mode);
+    /// Whether positive duty drives the motor backwards
+    fn inverted(&self) -> bool;
+    fn set_inverted(&mut self, inverted: bool);
 }
 
+// Only the drivers the board is built for are compiled in
+
+/// The default driver, with two PWM inputs, like the DRV8833
+#[cfg(not(any(feature = "sensing", feature = "pwm-dir", feature = "pwm-two-dir")))]
 pub struct TwoPinDcMotorOut<P1, P2>
 where P1: PwmPin, P2: PwmPin
 {
     pub out1: P1,
-    pub out2: P2
+    pub out2: P2,
+    // Swaps the pins over, for a motor mounted the other way round
+    pub inverted: bool,
 }
 
+#[cfg(not(any(feature = "sensing", feature = "pwm-dir", feature = "pwm-two-dir")))]
 pub trait DutyPair {
     fn set_duty(&mut self, duty1: f32, duty2: f32);
+    fn inverted(&self) -> bool;
+    fn set_inverted(&mut self, inverted: bool);
 }
 
+#[cfg(not(any(feature = "sensing", feature = "pwm-dir", feature = "pwm-two-dir")))]
 impl <P1, P2> DutyPair for TwoPinDcMotorOut<P1, P2>
 where 
     P1: PwmPin<Duty = u16>, 
     P2: PwmPin<Duty = u16>,
 {
     fn set_duty(&mut self, duty1: f32, duty2: f32) {
-        self.out1.set_duty((duty1*(self.out1.get_max_duty() as f32)) as u16);
-        self.out2.set_duty((duty2*(self.out2.get_max_duty() as f32)) as u16);
+        let (duty1, duty2) = match self.inverted {
+            false => (duty1, duty2),
+            true => (duty2, duty1),
+        };
+        // The range can change with the PWM frequency, so it's read every time
+        self.out1.set_duty(duty_of(&self.out1, duty1));
+        self.out2.set_duty(duty_of(&self.out2, duty2));
+    }
+
+    fn inverted(&self) -> bool {
+        self.inverted
+    }
+
+    fn set_inverted(&mut self, inverted: bool) {
+        self.inverted = inverted;
     }
 }
 
+#[cfg(not(any(feature = "sensing", feature = "pwm-dir", feature = "pwm-two-dir")))]
 impl <O> DcMotorOut for O
 where O: DutyPair
 {
+    fn inverted(&self) -> bool {
+        DutyPair::inverted(self)
+    }
+
+    fn set_inverted(&mut self, inverted: bool) {
+        DutyPair::set_inverted(self, inverted)
+    }
+
     fn free(&mut self) {
         self.set_duty(0.0, 0.0);
     }
@@ -58,117 +99,385 @@ where O: DutyPair
     }
 }
 
-pub trait Avg {
-    fn avg(a: Self, b: Self) -> Self;
+// The PWM compare value for `duty`, from 0 to 1, of the pin's range
+#[cfg(not(feature = "sensing"))]
+fn duty_of<P: PwmPin<Duty = u16>>(pin: &P, duty: f32) -> u16 {
+    (duty.max(0.0).min(1.0) * (pin.get_max_duty() as f32)) as u16
 }
 
-pub trait Sample: Ord + Copy + Avg { }
-
-struct MinMax<S: Sample>  {
-    min: S,
-    max: S,
-    zero: S,
+/// A driver with one PWM input for the speed and a direction input, like the
+/// Cytron MDD10. It has no way to coast: with the PWM low both outputs go to
+/// the same rail, so free and brake both stop the motor like that, and the
+/// mode of drive makes no difference.
+#[cfg(feature = "pwm-dir")]
+pub struct PwmDirDcMotorOut<P, D>
+where P: PwmPin, D: OutputPin
+{
+    pub pwm: P,
+    pub dir: D,
+    // Flips the direction input, for a motor mounted the other way round
+    pub inverted: bool,
 }
 
-impl <S: Sample> MinMax<S> {
+#[cfg(feature = "pwm-dir")]
+impl <P, D> DcMotorOut for PwmDirDcMotorOut<P, D>
+where
+    P: PwmPin<Duty = u16>,
+    D: OutputPin,
+{
+    fn free(&mut self) {
+        self.pwm.set_duty(0);
+    }
 
-    pub fn new(zero: S) -> Self {
-        MinMax { min: zero, max: zero, zero: zero }
+    fn brake(&mut self) {
+        self.pwm.set_duty(0);
     }
 
-    pub fn update(&mut self, value: S) -> bool {
-        if self.max < value || self.min > value {
-            self.min = min(self.min, value);
-            self.max = max(self.max, value);
-            self.zero = Avg::avg(self.min, self.max);
+    fn drive(&mut self, duty: f32, _mode: Mode) {
+        if (duty < 0.0) != self.inverted {
+            self.dir.set_high().ok();
+        } else {
+            self.dir.set_low().ok();
         }
+        self.pwm.set_duty(duty_of(&self.pwm, fabsf(duty)));
+    }
+
+    fn inverted(&self) -> bool {
+        self.inverted
+    }
 
-        value > self.zero
+    fn set_inverted(&mut self, inverted: bool) {
+        self.inverted = inverted;
     }
 }
 
-pub struct AnalogRotaryEncoder<S: Sample> {
-    in1: MinMax<S>,
-    in2: MinMax<S>,
-    counter: u64,
-    in1_prev_value: bool,
-    delta_r: i64,
+/// A driver with a PWM input and two direction inputs, like the TB6612 or an
+/// L298 with its enable as the PWM. Both direction inputs low lets the motor
+/// coast and both high brakes it. What happens while the PWM is low is up to
+/// the chip: the TB6612 brakes and the L298 coasts, so the mode of drive only
+/// matters at zero duty.
+#[cfg(feature = "pwm-two-dir")]
+pub struct PwmTwoDirDcMotorOut<P, D1, D2>
+where P: PwmPin, D1: OutputPin, D2: OutputPin
+{
+    pub pwm: P,
+    pub in1: D1,
+    pub in2: D2,
+    // Swaps the direction inputs over, for a motor mounted the other way round
+    pub inverted: bool,
 }
 
-impl <S: Sample> AnalogRotaryEncoder<S> {
-    pub fn new(zero: S) -> Self {
-        AnalogRotaryEncoder {
-            in1: MinMax::new(zero),
-            in2: MinMax::new(zero),
-            counter: 0,
-            in1_prev_value: false,
-            delta_r : 0,
-        }
+#[cfg(feature = "pwm-two-dir")]
+impl <P, D1, D2> PwmTwoDirDcMotorOut<P, D1, D2>
+where
+    P: PwmPin<Duty = u16>,
+    D1: OutputPin,
+    D2: OutputPin,
+{
+    fn set_inputs(&mut self, in1: bool, in2: bool) {
+        let (in1, in2) = match self.inverted {
+            false => (in1, in2),
+            true => (in2, in1),
+        };
+        match in1 {
+            true => self.in1.set_high().ok(),
+            false => self.in1.set_low().ok(),
+        };
+        match in2 {
+            true => self.in2.set_high().ok(),
+            false => self.in2.set_low().ok(),
+        };
     }
+}
 
-    pub fn update(&mut self, values: (S, S)) {
-        self.counter += 1;
-        let in1_next_value = self.in1.update(values.0);
-        if !self.in1_prev_value && in1_next_value {
-            let in2_value = self.in2.update(values.1);
-            self.delta_r += match in2_value { true => 1, false => -1 };
+#[cfg(feature = "pwm-two-dir")]
+impl <P, D1, D2> DcMotorOut for PwmTwoDirDcMotorOut<P, D1, D2>
+where
+    P: PwmPin<Duty = u16>,
+    D1: OutputPin,
+    D2: OutputPin,
+{
+    fn free(&mut self) {
+        self.pwm.set_duty(0);
+        self.set_inputs(false, false);
+    }
+
+    fn brake(&mut self) {
+        // The L298 only brakes with its enable high
+        self.set_inputs(true, true);
+        self.pwm.set_duty(self.pwm.get_max_duty());
+    }
+
+    fn drive(&mut self, duty: f32, mode: Mode) {
+        match (duty, mode) {
+            (duty, Mode::Free) if duty == 0.0 => self.free(),
+            (duty, Mode::Brake) if duty == 0.0 => self.brake(),
+            (duty, _) => {
+                self.set_inputs(duty > 0.0, duty < 0.0);
+                self.pwm.set_duty(duty_of(&self.pwm, fabsf(duty)));
+            },
         }
-    
-        self.in1_prev_value = in1_next_value;
     }
 
-    pub fn read(&mut self) -> i64 {
-        let delta = self.delta_r;
-        self.delta_r = 0;
-        return delta;
+    fn inverted(&self) -> bool {
+        self.inverted
     }
 
-    pub fn peek(& self) -> i64 {
-        return self.delta_r;
+    fn set_inverted(&mut self, inverted: bool) {
+        self.inverted = inverted;
     }
 }
 
-pub struct DcMotor<O, S: Sample>
-where O: DcMotorOut
-{
-    pub out: O,
-    pub encoder:  AnalogRotaryEncoder<S>
+// protocol::MAX_ENCODERS
+pub type MaxEncoders = U5;
+
+/// The motors a board drives, by index. Motor i goes with encoder i.
+pub trait MotorOutputs {
+    fn count(&self) -> usize;
+    fn motor(&mut self, index: usize) -> Option<&mut dyn DcMotorOut>;
 }
 
-pub struct DifferentialQuadratureSamples<S>
-where S: Sample
-{
-    pub left: (S, S),
-    pub right: (S, S)
+/// For boards that are only an encoder interface
+#[cfg(feature = "sensing")]
+impl MotorOutputs for () {
+    fn count(&self) -> usize { 0 }
+    fn motor(&mut self, _index: usize) -> Option<&mut dyn DcMotorOut> { None }
 }
 
-pub trait DifferentialQuadratureAnalogInput<S> 
-where S: Sample
+#[cfg(not(feature = "sensing"))]
+pub struct DifferentialOutputs<O1, O2>
+where O1: DcMotorOut, O2: DcMotorOut
 {
-    fn read_nb(&mut self) -> Option<DifferentialQuadratureSamples<S>>;
+    pub left: O1,
+    pub right: O2,
 }
 
-pub struct Differential<O1, O2, S, I>
-where O1: DcMotorOut, O2: DcMotorOut, S: Sample, I: DifferentialQuadratureAnalogInput<S>
+#[cfg(not(feature = "sensing"))]
+impl <O1, O2> MotorOutputs for DifferentialOutputs<O1, O2>
+where O1: DcMotorOut + 'static, O2: DcMotorOut + 'static {
+    fn count(&self) -> usize { 2 }
+
+    fn motor(&mut self, index: usize) -> Option<&mut dyn DcMotorOut> {
+        match index {
+            0 => Some(&mut self.left),
+            1 => Some(&mut self.right),
+            _ => None,
+        }
+    }
+}
+
+/// Where the encoders' index (Z) pulses come from, for the ones that have
+/// them.
+pub trait IndexInputs<S> {
+    fn present(&self, encoder: usize) -> bool;
+
+    /// Whether `encoder`'s index is active, given the scan of samples taken
+    /// at the same time. None if it doesn't have one.
+    fn active(&mut self, encoder: usize, scan: &[(S, S)]) -> Option<bool>;
+}
+
+/// For boards with no index inputs
+#[cfg(feature = "sensing")]
+impl <S> IndexInputs<S> for () {
+    fn present(&self, _encoder: usize) -> bool { false }
+    fn active(&mut self, _encoder: usize, _scan: &[(S, S)]) -> Option<bool> { None }
+}
+
+/// Index pulses on GPIO pins, active high, for the first encoders in order
+#[cfg(not(feature = "sensing"))]
+pub struct DigitalIndexes<P1, P2>(pub P1, pub P2);
+
+#[cfg(not(feature = "sensing"))]
+impl <S, P1, P2> IndexInputs<S> for DigitalIndexes<P1, P2>
+where P1: InputPin, P2: InputPin {
+    fn present(&self, encoder: usize) -> bool { encoder < 2 }
+
+    fn active(&mut self, encoder: usize, _scan: &[(S, S)]) -> Option<bool> {
+        match encoder {
+            0 => self.0.is_high().ok(),
+            1 => self.1.is_high().ok(),
+            _ => None,
+        }
+    }
+}
+
+fn set_output(motor: &mut dyn DcMotorOut, output: Output) {
+    match output {
+        Output::Drive(duty, mode) => motor.drive(duty, mode),
+        Output::Free => motor.free(),
+        Output::Brake => motor.brake(),
+    }
+}
+
+/// Up to `MAX_ENCODERS` encoders, the first few of which may have motors,
+/// and any of which may have index pulses. The motors are only driven
+/// through their shapers, one for each encoder.
+pub struct Axes<S, M, X>
+where S: Sample, M: MotorOutputs, X: IndexInputs<S>
 {
-    pub left: DcMotor<O1, S>,
-    pub right: DcMotor<O2, S>,
-    pub input: I
+    pub encoders: Vec<AnalogRotaryEncoder<S>, MaxEncoders>,
+    pub motors: M,
+    pub indexes: X,
+    pub shapers: Vec<Shaper, MaxEncoders>,
+    /// Set while every motor is held stopped, freed or braked, and can't be
+    /// driven
+    pub halted: Option<Mode>,
 }
 
-impl <O1, O2, S, I> Differential<O1, O2, S, I>
-where O1: DcMotorOut, O2: DcMotorOut, S: Sample, I: DifferentialQuadratureAnalogInput<S> {
-    fn update_encoders(&mut self, input: DifferentialQuadratureSamples<S>) {
-        self.left.encoder.update(input.left);
-        self.right.encoder.update(input.right);
+impl <S, M, X> Axes<S, M, X>
+where S: Sample, M: MotorOutputs, X: IndexInputs<S> {
+    /// A pair of samples for each encoder, in order
+    pub fn update(&mut self, samples: &[(S, S)]) {
+        for (i, (encoder, values)) in self.encoders.iter_mut().zip(samples).enumerate() {
+            encoder.update(*values);
+            if let Some(active) = self.indexes.active(i, samples) {
+                encoder.update_index(active);
+            }
+        }
+    }
+
+    pub fn has_index(&self, encoder: usize) -> bool {
+        self.indexes.present(encoder)
+    }
+
+    pub fn encoder(&mut self, index: usize) -> Option<&mut AnalogRotaryEncoder<S>> {
+        self.encoders.get_mut(index)
+    }
+
+    fn motor(&mut self, index: usize) -> Option<&mut dyn DcMotorOut> {
+        self.motors.motor(index)
+    }
+
+    /// Encoders 0 to this - 1 have motors
+    pub fn motor_count(&self) -> usize {
+        self.motors.count()
     }
 
-    pub fn update(&mut self) {
-        let result = self.input.read_nb();
-        match result {
-            Some(samples) => self.update_encoders(samples),
-            None => {}
+    /// Sets a motor going towards `duty`, from -1 to 1, over the next control
+    /// periods. Returns false if there's no such motor, or they're halted.
+    pub fn drive(&mut self, index: usize, duty: f32, mode: Mode) -> bool {
+        if index >= self.motors.count() || self.halted.is_some() {
+            return false;
+        }
+        match self.shapers.get_mut(index) {
+            Some(shaper) => {
+                shaper.drive(duty, mode);
+                true
+            },
+            None => false,
+        }
+    }
+
+    /// The duty a motor's output is at now
+    pub fn duty(&self, index: usize) -> f32 {
+        self.shapers.get(index).map_or(0.0, |shaper| shaper.duty())
+    }
+
+    /// Lets a motor coast, straight away
+    pub fn free(&mut self, index: usize) {
+        if let Some(shaper) = self.shapers.get_mut(index) {
+            shaper.stop(Mode::Free);
+        }
+        if let Some(motor) = self.motor(index) {
+            motor.free();
+        }
+    }
+
+    /// Brakes a motor, straight away
+    pub fn brake(&mut self, index: usize) {
+        if let Some(shaper) = self.shapers.get_mut(index) {
+            shaper.stop(Mode::Brake);
+        }
+        if let Some(motor) = self.motor(index) {
+            motor.brake();
         }
     }
-}
 
+    /// Stops every motor straight away, as `mode` says, and keeps them
+    /// stopped until `release`
+    pub fn halt(&mut self, mode: Mode) {
+        self.halted = Some(mode);
+        for index in 0..self.motors.count() {
+            match mode {
+                Mode::Free => self.free(index),
+                Mode::Brake => self.brake(index),
+            }
+        }
+    }
+
+    /// Lets the motors be driven again after `halt`. They stay stopped until
+    /// they are.
+    pub fn release(&mut self) {
+        self.halted = None;
+    }
+
+    /// Call every control period, with what the duty should be multiplied by
+    /// for the supply, or None if the motors should be freed
+    pub fn step_outputs(&mut self, scale: Option<f32>) {
+        if self.halted.is_some() {
+            return;
+        }
+        for (index, shaper) in self.shapers.iter_mut().enumerate() {
+            if let Some(motor) = self.motors.motor(index) {
+                if let Some(output) = shaper.step(scale) {
+                    set_output(motor, output);
+                }
+            }
+        }
+    }
+
+    /// Sets every output again as it was, or as the halt has it, for when
+    /// the range of the duty has changed under them
+    pub fn reapply(&mut self) {
+        if let Some(mode) = self.halted {
+            self.halt(mode);
+            return;
+        }
+        for (index, shaper) in self.shapers.iter().enumerate() {
+            if let Some(motor) = self.motors.motor(index) {
+                set_output(motor, shaper.output());
+            }
+        }
+    }
+
+    /// None if there's no such motor
+    pub fn shaping(&self, index: usize) -> Option<OutputShaping> {
+        if index >= self.motors.count() {
+            return None;
+        }
+        self.shapers.get(index).map(|shaper| shaper.shaping())
+    }
+
+    /// Returns false if there's no such motor or the shaping doesn't make sense
+    pub fn shape(&mut self, index: usize, shaping: OutputShaping) -> bool {
+        if index >= self.motors.count() {
+            return false;
+        }
+        self.shapers.get_mut(index).map_or(false, |shaper| shaper.set_shaping(shaping))
+    }
+
+    pub fn config(&mut self, index: usize) -> Option<MotorConfig> {
+        let invert_output = self.motor(index).map_or(false, |motor| motor.inverted());
+        let encoder = self.encoder(index)?;
+        Some(MotorConfig {
+            invert_output: invert_output,
+            reverse_encoder: encoder.reversed(),
+            swap_channels: encoder.swapped(),
+        })
+    }
+
+    /// Returns false if there's no such encoder
+    pub fn configure(&mut self, index: usize, config: &MotorConfig) -> bool {
+        match self.encoder(index) {
+            Some(encoder) => {
+                encoder.set_reversed(config.reverse_encoder);
+                encoder.set_swapped(config.swap_channels);
+            },
+            None => return false,
+        }
+        if let Some(motor) = self.motor(index) {
+            motor.set_inverted(config.invert_output);
+        }
+        true
+    }
+}
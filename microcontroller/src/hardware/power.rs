@@ -0,0 +1,67 @@
+use embedded_hal::digital::v2::{ InputPin, OutputPin };
+use stm32f1xx_hal::{
+    gpio::{ Input, Output, PullUp, PushPull },
+    gpio::gpioa::PA15,
+    gpio::gpiob::PB3,
+};
+use logic::power::{ Button, periods };
+use protocol::Event;
+
+use crate::CONTROL_PERIOD_MS as PERIOD_MS;
+
+pub type SwitchIn = PA15<Input<PullUp>>;
+pub type SwitchOut = PB3<Output<PushPull>>;
+
+/// The soft power switch. The button on SWIN pulls it low, and SWOUT has to
+/// be held high to keep the power on once the button that turned it on is
+/// let go. A press asks the host to shut down; it cuts the power itself
+/// when it's ready, with `power_off`.
+pub struct Power {
+    input: SwitchIn,
+    output: SwitchOut,
+    button: Button,
+    // Periods until the power is cut
+    off_in: Option<u32>,
+}
+
+impl Power {
+    pub fn new(input: SwitchIn, mut output: SwitchOut) -> Self {
+        output.set_high().ok();
+        Power {
+            input: input,
+            output: output,
+            button: Button::new(),
+            off_in: None,
+        }
+    }
+
+    pub fn button(&self) -> bool {
+        self.button.pressed()
+    }
+
+    /// In ms, if it's going to be
+    pub fn off_in(&self) -> Option<u32> {
+        self.off_in.map(|periods| periods.saturating_mul(PERIOD_MS))
+    }
+
+    /// Cuts the power after `delay_ms`, or with None doesn't after all
+    pub fn power_off(&mut self, delay_ms: Option<u32>) {
+        self.off_in = delay_ms.map(periods);
+    }
+
+    /// Call every control period. Says when the button's been pressed.
+    pub fn update(&mut self) -> Option<Event> {
+        let down = self.input.is_low().unwrap_or(false);
+        let (event, held) = self.button.update(down);
+        if held {
+            self.off_in = Some(0);
+        }
+        match self.off_in {
+            // If something else is powering the board, it carries on
+            Some(0) => { self.output.set_low().ok(); },
+            Some(periods) => self.off_in = Some(periods - 1),
+            None => (),
+        }
+        event
+    }
+}
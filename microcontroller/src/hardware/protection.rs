@@ -0,0 +1,24 @@
+use heapless::Vec;
+use libm::fabsf;
+use protocol::Event;
+
+pub use logic::protection::Protection;
+
+use super::Motors;
+use super::motor::MaxEncoders;
+use super::sampling::SAMPLE_RATE;
+
+/// Call every control period, after the outputs have been stepped. Frees any
+/// motor that's stalled for too long, and says which.
+pub fn check(protection: &mut Protection, motors: &mut Motors) -> Vec<Event, MaxEncoders> {
+    let mut events = Vec::new();
+    for index in 0..motors.motor_count().min(protocol::MAX_ENCODERS) {
+        let velocity = motors.encoder(index)
+            .map_or(0.0, |encoder| fabsf(encoder.velocity()) * SAMPLE_RATE as f32);
+        if let Some(event) = protection.check(index, motors.duty(index), velocity) {
+            motors.free(index);
+            events.push(event).ok();
+        }
+    }
+    events
+}
@@ -0,0 +1,170 @@
+use stm32f1xx_hal::pac::TIM3;
+use protocol::{ PwmConfig, SampleTrigger };
+use super::sampling::SAMPLE_RATE;
+
+const MIN_FREQUENCY: u32 = 1_000;
+const MAX_FREQUENCY: u32 = 25_000;
+
+/// Changes the frequency and alignment of the motor PWM on TIM3 once the HAL
+/// has set it up and split it into channels. The channels read the range of
+/// their duty from the timer every time they set one, so the motors' outputs
+/// need setting again after a change, with the motors locked throughout.
+///
+/// When the sampling is synchronised to it, the timer is reset by TIM1's
+/// update at the start of every sample period, which only leaves the PWM
+/// alone if it has a whole number of periods in between.
+pub struct PwmTimer {
+    // The timer's input clock
+    clock: u32,
+    config: PwmConfig,
+    trigger: SampleTrigger,
+}
+
+impl PwmTimer {
+    // The sensing board has no motor PWM, so never has one of these
+    #[cfg(not(feature = "sensing"))]
+    pub fn new(clock: u32) -> Self {
+        PwmTimer {
+            clock: clock,
+            config: PwmConfig::default(),
+            trigger: SampleTrigger::default(),
+        }
+    }
+
+    pub fn trigger(&self) -> SampleTrigger {
+        self.trigger
+    }
+
+    /// Returns false if the offset is out of range. The sampler's trigger
+    /// needs moving to `sample_offset()` afterwards.
+    pub fn set_trigger(&mut self, trigger: SampleTrigger) -> bool {
+        if !(trigger.offset >= 0.0 && trigger.offset < 1.0) {
+            return false;
+        }
+        self.trigger = trigger;
+        self.synchronise();
+        true
+    }
+
+    /// Whether the PWM is synchronised to the sampling
+    pub fn locked(&self) -> bool {
+        self.trigger.synchronised && self.config.frequency % SAMPLE_RATE == 0
+    }
+
+    /// Where in each sample period the ADC should be triggered, from 0 to 1:
+    /// at the offset into the first PWM period if it's locked, otherwise
+    /// halfway through, as the PWM could be anywhere.
+    pub fn sample_offset(&self) -> f32 {
+        match self.locked() {
+            true => self.trigger.offset * SAMPLE_RATE as f32 / self.config.frequency as f32,
+            false => 0.5,
+        }
+    }
+
+    /// As the timer really runs
+    pub fn config(&self) -> PwmConfig {
+        self.config
+    }
+
+    pub fn max_duty(&self) -> u16 {
+        self.timer().arr.read().bits() as u16
+    }
+
+    /// Returns false if the frequency is out of range. Otherwise the motors'
+    /// outputs have to be set again, as their duties were for the old range.
+    pub fn set(&mut self, config: PwmConfig) -> bool {
+        if config.frequency < MIN_FREQUENCY || config.frequency > MAX_FREQUENCY {
+            return false;
+        }
+        // Counting up and down takes twice as long
+        let counts = match config.centre_aligned {
+            false => self.clock / config.frequency,
+            true => self.clock / config.frequency / 2,
+        };
+        let prescale = (counts - 1) / 0x1_0000 + 1;
+        let period = counts / prescale;
+        self.write(prescale - 1, period - 1, config.centre_aligned);
+
+        let frequency = self.clock / prescale / period;
+        self.config = PwmConfig {
+            frequency: if config.centre_aligned { frequency / 2 } else { frequency },
+            centre_aligned: config.centre_aligned,
+        };
+        self.synchronise();
+        true
+    }
+
+    // Reset by TIM1's trigger output (ITR0) when locked, free running
+    // otherwise
+    #[allow(unsafe_code)]
+    fn synchronise(&self) {
+        let mode = if self.locked() { 0b100 } else { 0b000 };
+        self.timer().smcr.write(|w| unsafe { w.bits(mode) });
+    }
+
+    #[allow(unsafe_code)]
+    fn timer(&self) -> &'static stm32f1xx_hal::pac::tim2::RegisterBlock {
+        // Only ever used once the HAL has the timer going
+        unsafe { &*TIM3::ptr() }
+    }
+
+    #[allow(unsafe_code)]
+    fn write(&self, prescaler: u32, reload: u32, centre_aligned: bool) {
+        let timer = self.timer();
+        // The alignment can only be changed with the counter stopped
+        timer.cr1.modify(|_, w| w.cen().clear_bit());
+        timer.cr1.modify(|_, w| match centre_aligned {
+            false => w.cms().edge_aligned(),
+            true => w.cms().center_aligned1(),
+        });
+        timer.psc.write(|w| unsafe { w.bits(prescaler) });
+        timer.arr.write(|w| unsafe { w.bits(reload) });
+        // Load the new prescaler now rather than at the next update
+        timer.cnt.reset();
+        timer.egr.write(|w| w.ug().set_bit());
+        timer.cr1.modify(|_, w| w.cen().set_bit());
+    }
+}
+
+/// Holds every motor's PWM low, whoever has the motors, until it's let go.
+/// With its PWM low, no driver drives its motor. Only the e-stop has one of
+/// these, so it can stop the motors without waiting for them. It only
+/// changes the channels' output modes, which the HAL sets once, and leaves
+/// the duties to the motors.
+pub struct Cutoff(());
+
+impl Cutoff {
+    pub fn new() -> Self {
+        Cutoff(())
+    }
+
+    /// Forces every channel's output inactive
+    #[cfg(not(feature = "sensing"))]
+    pub fn cut(&mut self) {
+        let timer = self.timer();
+        timer.ccmr1_output().modify(|_, w| w.oc1m().force_inactive().oc2m().force_inactive());
+        timer.ccmr2_output().modify(|_, w| w.oc3m().force_inactive().oc4m().force_inactive());
+    }
+
+    /// Lets the PWM through again, as the HAL set it up
+    #[cfg(not(feature = "sensing"))]
+    pub fn release(&mut self) {
+        let timer = self.timer();
+        timer.ccmr1_output().modify(|_, w| w.oc1m().pwm_mode1().oc2m().pwm_mode1());
+        timer.ccmr2_output().modify(|_, w| w.oc3m().pwm_mode1().oc4m().pwm_mode1());
+    }
+
+    #[cfg(not(feature = "sensing"))]
+    #[allow(unsafe_code)]
+    fn timer(&self) -> &'static stm32f1xx_hal::pac::tim2::RegisterBlock {
+        // Only ever used once the HAL has the timer going
+        unsafe { &*TIM3::ptr() }
+    }
+
+    // The sensing board has no motors
+    #[cfg(feature = "sensing")]
+    pub fn cut(&mut self) {}
+
+    #[cfg(feature = "sensing")]
+    pub fn release(&mut self) {}
+}
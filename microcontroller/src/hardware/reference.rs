@@ -0,0 +1,105 @@
+use embedded_hal::Qei as _;
+use libm::sqrtf;
+use stm32f1xx_hal::{
+    gpio::{ Floating, Input },
+    gpio::gpiob::{ PB6, PB7 },
+    pac::TIM4,
+    qei::Qei,
+    timer::Tim4NoRemap,
+};
+use protocol::{ ReferenceComparison, ReferenceConfig };
+
+pub type ReferenceQei = Qei<TIM4, Tim4NoRemap, (PB6<Input<Floating>>, PB7<Input<Floating>>)>;
+
+/// A digital quadrature encoder counted by TIM4 in encoder mode, to check the
+/// analog decoding of an encoder on the same shaft against. Both are taken
+/// from 0 when the comparison starts, so only the drift between them counts.
+pub struct Reference {
+    qei: ReferenceQei,
+    // The timer's count last time, to extend it past 16 bits
+    last: u16,
+    count: i64,
+    encoder: usize,
+    config: ReferenceConfig,
+    // Where both were when the comparison started
+    start: Option<(f32, i64)>,
+    comparison: ReferenceComparison,
+    squares: f32,
+}
+
+impl Reference {
+    pub fn new(qei: ReferenceQei, encoder: usize) -> Self {
+        let last = qei.count();
+        Reference {
+            qei: qei,
+            last: last,
+            count: 0,
+            encoder: encoder,
+            config: ReferenceConfig::default(),
+            start: None,
+            comparison: ReferenceComparison::default(),
+            squares: 0.0,
+        }
+    }
+
+    /// The analog encoder it's compared with
+    pub fn encoder(&self) -> usize {
+        self.encoder
+    }
+
+    /// Starts the comparison again, against `encoder`
+    pub fn reset(&mut self, encoder: usize) {
+        self.encoder = encoder;
+        self.start = None;
+        self.comparison = ReferenceComparison::default();
+        self.squares = 0.0;
+    }
+
+    pub fn config(&self) -> ReferenceConfig {
+        self.config
+    }
+
+    /// Starts the comparison again. Returns false if the ratio is 0 or isn't
+    /// a number.
+    pub fn configure(&mut self, config: ReferenceConfig) -> bool {
+        if !config.counts_per_cycle.is_finite() || config.counts_per_cycle == 0.0 {
+            return false;
+        }
+        self.config = config;
+        self.reset(self.encoder);
+        true
+    }
+
+    /// Call often enough that the timer can't move by half its range in
+    /// between, with the analog encoder's position at the same moment.
+    pub fn update(&mut self, analog: f32) {
+        let now = self.qei.count();
+        self.count += now.wrapping_sub(self.last) as i16 as i64;
+        self.last = now;
+
+        let (analog_start, count_start) = *self.start.get_or_insert((analog, self.count));
+        let analog = analog - analog_start;
+        let reference = (self.count - count_start) as f32 / self.config.counts_per_cycle
+            * if self.config.reverse { -1.0 } else { 1.0 };
+        let difference = analog - reference;
+
+        let c = &mut self.comparison;
+        if c.samples == 0 {
+            c.min = difference;
+            c.max = difference;
+        }
+        c.encoder = self.encoder as u8;
+        c.analog = analog;
+        c.reference = reference;
+        c.difference = difference;
+        c.min = c.min.min(difference);
+        c.max = c.max.max(difference);
+        c.samples += 1;
+        self.squares += difference * difference;
+        c.rms = sqrtf(self.squares / c.samples as f32);
+    }
+
+    pub fn comparison(&self) -> ReferenceComparison {
+        self.comparison
+    }
+}
@@ -0,0 +1,205 @@
+use embedded_hal::blocking::spi::Transfer;
+use embedded_hal::digital::v2::OutputPin;
+use protocol::RADIO_PACKET;
+
+use crate::rpc::packet::Packets;
+
+// Commands
+const R_REGISTER: u8 = 0x00;
+const W_REGISTER: u8 = 0x20;
+const R_RX_PAYLOAD: u8 = 0x61;
+const W_TX_PAYLOAD: u8 = 0xa0;
+const FLUSH_TX: u8 = 0xe1;
+const FLUSH_RX: u8 = 0xe2;
+
+// Registers
+const CONFIG: u8 = 0x00;
+const EN_AA: u8 = 0x01;
+const EN_RXADDR: u8 = 0x02;
+const SETUP_AW: u8 = 0x03;
+const SETUP_RETR: u8 = 0x04;
+const RF_CH: u8 = 0x05;
+const RF_SETUP: u8 = 0x06;
+const STATUS: u8 = 0x07;
+const RX_ADDR_P0: u8 = 0x0a;
+const TX_ADDR: u8 = 0x10;
+const RX_PW_P0: u8 = 0x11;
+const FIFO_STATUS: u8 = 0x17;
+
+// CONFIG bits
+const EN_CRC: u8 = 0x08;
+const CRCO: u8 = 0x04;
+const PWR_UP: u8 = 0x02;
+const PRIM_RX: u8 = 0x01;
+
+// STATUS bits
+const RX_DR: u8 = 0x40;
+const TX_DS: u8 = 0x20;
+const MAX_RT: u8 = 0x10;
+
+// FIFO_STATUS bits
+const RX_EMPTY: u8 = 0x01;
+
+#[derive(Debug)]
+pub enum Error<E> {
+    Spi(E),
+    // The other end didn't acknowledge the packet
+    MaxRetries,
+}
+
+/// Both ends of the link use pipe 0 with the same address, so either can
+/// transmit to the other and get the auto-acknowledgement back.
+pub struct Rf24Config {
+    pub channel: u8,
+    pub address: [u8; 5],
+}
+
+impl Default for Rf24Config {
+    fn default() -> Self {
+        Rf24Config { channel: 76, address: *b"quadr" }
+    }
+}
+
+/// An nRF24L01(+) using fixed 32 byte payloads with auto-acknowledgement. It
+/// sits in receive mode, and only switches to transmit while it sends a
+/// packet.
+pub struct Rf24<SPI, CE, CSN> {
+    spi: SPI,
+    ce: CE,
+    csn: CSN,
+    transmitting: bool,
+}
+
+impl <SPI, CE, CSN, E> Rf24<SPI, CE, CSN>
+where
+    SPI: Transfer<u8, Error = E>,
+    CE: OutputPin,
+    CSN: OutputPin,
+{
+    /// Configures and powers up the radio. It needs 1.5ms to start its
+    /// oscillator before `listen` will do anything.
+    pub fn new(spi: SPI, ce: CE, csn: CSN, config: &Rf24Config) -> Result<Self, Error<E>> {
+        let mut rf24 = Rf24 { spi: spi, ce: ce, csn: csn, transmitting: false };
+        rf24.ce.set_low().ok();
+        rf24.csn.set_high().ok();
+
+        rf24.write_register(SETUP_AW, 0b11)?; // 5 byte addresses
+        rf24.write_register(SETUP_RETR, 0x2f)?; // 750us delay, 15 retries
+        rf24.write_register(RF_CH, config.channel & 0x7f)?;
+        rf24.write_register(RF_SETUP, 0x0e)?; // 2Mbps, 0dBm
+        rf24.write_register(EN_AA, 0x01)?;
+        rf24.write_register(EN_RXADDR, 0x01)?;
+        rf24.write_register(RX_PW_P0, RADIO_PACKET as u8)?;
+        rf24.write_registers(RX_ADDR_P0, &config.address)?;
+        rf24.write_registers(TX_ADDR, &config.address)?;
+        rf24.command(FLUSH_RX)?;
+        rf24.command(FLUSH_TX)?;
+        rf24.write_register(STATUS, RX_DR | TX_DS | MAX_RT)?;
+        rf24.write_register(CONFIG, EN_CRC | CRCO | PWR_UP)?;
+        Ok(rf24)
+    }
+
+    pub fn listen(&mut self) -> Result<(), Error<E>> {
+        self.write_register(CONFIG, EN_CRC | CRCO | PWR_UP | PRIM_RX)?;
+        self.ce.set_high().ok();
+        Ok(())
+    }
+
+    fn command(&mut self, command: u8) -> Result<u8, Error<E>> {
+        let mut buf = [command];
+        self.transfer(&mut buf)?;
+        Ok(buf[0])
+    }
+
+    fn transfer(&mut self, buf: &mut [u8]) -> Result<(), Error<E>> {
+        self.csn.set_low().ok();
+        let result = self.spi.transfer(buf).map(|_| ()).map_err(Error::Spi);
+        self.csn.set_high().ok();
+        result
+    }
+
+    fn read_register(&mut self, register: u8) -> Result<u8, Error<E>> {
+        let mut buf = [R_REGISTER | register, 0];
+        self.transfer(&mut buf)?;
+        Ok(buf[1])
+    }
+
+    fn write_register(&mut self, register: u8, value: u8) -> Result<(), Error<E>> {
+        self.transfer(&mut [W_REGISTER | register, value])
+    }
+
+    fn write_registers(&mut self, register: u8, values: &[u8; 5]) -> Result<(), Error<E>> {
+        let mut buf = [0u8; 6];
+        buf[0] = W_REGISTER | register;
+        buf[1..].copy_from_slice(values);
+        self.transfer(&mut buf)
+    }
+
+    // Returns the status register, and clears the bits that were set
+    fn clear_status(&mut self) -> Result<u8, Error<E>> {
+        let status = self.read_register(STATUS)?;
+        self.write_register(STATUS, status & (RX_DR | TX_DS | MAX_RT))?;
+        Ok(status)
+    }
+}
+
+impl <SPI, CE, CSN, E> Packets for Rf24<SPI, CE, CSN>
+where
+    SPI: Transfer<u8, Error = E>,
+    CE: OutputPin,
+    CSN: OutputPin,
+{
+    type Error = Error<E>;
+
+    fn mtu(&self) -> usize { RADIO_PACKET }
+
+    fn send(&mut self, packet: &[u8]) -> nb::Result<(), Self::Error> {
+        if !self.transmitting {
+            self.ce.set_low().ok();
+            self.write_register(CONFIG, EN_CRC | CRCO | PWR_UP)?;
+            let mut buf = [0u8; RADIO_PACKET + 1];
+            buf[0] = W_TX_PAYLOAD;
+            buf[1..packet.len() + 1].copy_from_slice(packet);
+            self.transfer(&mut buf)?;
+            // CE stays high until the packet has gone, then we go back to
+            // listening
+            self.ce.set_high().ok();
+            self.transmitting = true;
+            return Err(nb::Error::WouldBlock);
+        }
+
+        let status = self.read_register(STATUS)?;
+        if status & (TX_DS | MAX_RT) == 0 {
+            return Err(nb::Error::WouldBlock);
+        }
+
+        self.write_register(STATUS, TX_DS | MAX_RT)?;
+        self.transmitting = false;
+        if status & MAX_RT != 0 {
+            self.command(FLUSH_TX)?;
+        }
+        self.listen()?;
+
+        match status & MAX_RT {
+            0 => Ok(()),
+            _ => Err(nb::Error::Other(Error::MaxRetries)),
+        }
+    }
+
+    fn recv(&mut self, packet: &mut [u8]) -> nb::Result<usize, Self::Error> {
+        if self.transmitting {
+            return Err(nb::Error::WouldBlock);
+        }
+
+        self.clear_status()?;
+        if self.read_register(FIFO_STATUS)? & RX_EMPTY != 0 {
+            return Err(nb::Error::WouldBlock);
+        }
+
+        let mut buf = [0u8; RADIO_PACKET + 1];
+        buf[0] = R_RX_PAYLOAD;
+        self.transfer(&mut buf)?;
+        packet[..RADIO_PACKET].copy_from_slice(&buf[1..]);
+        Ok(RADIO_PACKET)
+    }
+}
@@ -0,0 +1,230 @@
+use stm32f1xx_hal::{
+    adc::{ self, Adc },
+    dma::{ dma1, Event },
+    pac::{ ADC1, ADC2, TIM1 },
+    pac::adc1::cr2::EXTSEL_A,
+};
+use rtfm::cyccnt::Instant;
+
+/// Conversions happen on TIM1 CC1, so this is exact.
+pub const SAMPLE_RATE: u32 = 10_000;
+/// Samples per half of the DMA buffer, so the interrupt rate is
+/// SAMPLE_RATE / BLOCK.
+pub const BLOCK: usize = 16;
+/// Each encoder is one rank of the scan: ADC1 converts its A channel while
+/// ADC2 converts its B channel.
+#[cfg(not(feature = "sensing"))]
+pub const ENCODERS: usize = 2;
+#[cfg(feature = "sensing")]
+pub const ENCODERS: usize = 5;
+/// After the encoders, the default board has a rank for its other analog
+/// inputs: the supply voltage on ADC1 and the motor current on ADC2.
+#[cfg(not(feature = "sensing"))]
+pub const AUXILIARY: usize = 1;
+#[cfg(feature = "sensing")]
+pub const AUXILIARY: usize = 0;
+pub const RANKS: usize = ENCODERS + AUXILIARY;
+/// The middle of the 12 bit conversions' range
+pub const MID_SCALE: u16 = 2048;
+
+const SAMPLE_TIME: adc::SampleTime = adc::SampleTime::T_28;
+// ADC cycles per conversion: the sample time plus 12.5, at 12MHz
+const CONVERSION_CYCLES: f32 = 28.5 + 12.5;
+const ADC_CLOCK: f32 = 12_000_000.0;
+
+/// How long after the trigger each encoder's pair is converted, in sample
+/// periods. The two channels of a pair are simultaneous, but each rank waits
+/// for the one before.
+pub fn delay(encoder: usize) -> f32 {
+    encoder as f32 * CONVERSION_CYCLES / ADC_CLOCK * SAMPLE_RATE as f32
+}
+
+// ADC2's result in the top half, ADC1's in the bottom
+pub type Block = [u32; BLOCK * RANKS];
+
+#[derive(Clone, Copy, Default)]
+pub struct SampleRate {
+    pub configured: u32,
+    pub measured: u32,
+    // Blocks we didn't get to before the DMA wrote over them
+    pub overruns: u32,
+}
+
+/// Continuous scans of the quadrature inputs using ADC1 and ADC2 in regular
+/// simultaneous mode, triggered by a timer, into a circular DMA buffer. Each
+/// half of the buffer is processed as a block when the DMA half or fully
+/// complete interrupt says it's ready.
+pub struct Sampler<PINS> {
+    _adcs: (Adc<ADC1>, Adc<ADC2>),
+    _pins: PINS,
+    channel: dma1::C1,
+    buffer: &'static mut [Block; 2],
+    timer: TIM1,
+    sysclk: u32,
+    rate: SampleRate,
+    // Samples since `since`, for working out the measured rate
+    samples: u32,
+    // Blocks read since the start, wrapping
+    blocks: u32,
+    since: Instant,
+    auxiliary: Option<(u16, u16)>,
+}
+
+impl <PINS> Sampler<PINS> {
+    /// `a_channels` and `b_channels` are the ADC channels of each rank: the
+    /// encoders' inputs, then the auxiliary ones. `timer` must already be
+    /// counting at SAMPLE_RATE; this sets up CC1 on it to trigger the ADC,
+    /// halfway through each period, and its trigger output for the motor PWM
+    /// to synchronise to.
+    pub fn new(
+        mut adc1: Adc<ADC1>,
+        mut adc2: Adc<ADC2>,
+        pins: PINS,
+        a_channels: &[u8; RANKS],
+        b_channels: &[u8; RANKS],
+        mut channel: dma1::C1,
+        buffer: &'static mut [Block; 2],
+        timer: TIM1,
+        sysclk: u32) -> Self {
+
+        for (a, b) in a_channels.iter().zip(b_channels.iter()) {
+            adc1.set_channel_sample_time(*a, SAMPLE_TIME);
+            adc2.set_channel_sample_time(*b, SAMPLE_TIME);
+        }
+        adc1.set_regular_sequence(a_channels);
+        adc2.set_regular_sequence(b_channels);
+        // Only the master is triggered by the timer; the slave follows it
+        adc1.set_external_trigger(EXTSEL_A::TIM1CC1);
+        dual_simultaneous_mode();
+
+        channel.set_peripheral_address(adc1_dr(), false);
+        channel.set_memory_address(buffer.as_ptr() as u32, true);
+        channel.set_transfer_length(BLOCK * RANKS * 2);
+        channel.ch().cr.modify(|_, w| w
+            .mem2mem().clear_bit()
+            .pl().high()
+            .msize().bits32()
+            .psize().bits32()
+            .circ().set_bit()
+            .dir().clear_bit());
+        channel.listen(Event::HalfTransfer);
+        channel.listen(Event::TransferComplete);
+        channel.start();
+
+        let arr = timer.arr.read().bits() as u16;
+        timer.ccmr1_output().modify(|_, w| w.oc1m().pwm_mode1());
+        timer.ccr1.write(|w| w.ccr().bits(arr / 2));
+        timer.ccer.modify(|_, w| w.cc1e().set_bit());
+        timer.bdtr.modify(|_, w| w.moe().set_bit());
+        timer.cr2.modify(|_, w| w.mms().update());
+        timer.cr1.modify(|_, w| w.cen().set_bit());
+
+        Sampler {
+            _adcs: (adc1, adc2),
+            _pins: pins,
+            channel: channel,
+            buffer: buffer,
+            timer: timer,
+            sysclk: sysclk,
+            rate: SampleRate { configured: SAMPLE_RATE, measured: 0, overruns: 0 },
+            samples: 0,
+            blocks: 0,
+            since: Instant::now(),
+            auxiliary: None,
+        }
+    }
+
+    pub fn rate(&self) -> SampleRate {
+        self.rate
+    }
+
+    /// How many blocks have been read, wrapping, to tell it's still going
+    pub fn blocks(&self) -> u32 {
+        self.blocks
+    }
+
+    /// The auxiliary inputs, ADC1's and ADC2's, averaged over the last block.
+    /// None on a board without them.
+    pub fn auxiliary(&self) -> Option<(u16, u16)> {
+        self.auxiliary
+    }
+
+    /// Where in each sample period the ADC is triggered, from 0 to 1
+    pub fn set_trigger_offset(&mut self, offset: f32) {
+        let arr = self.timer.arr.read().bits() as u16;
+        // CC1 has to match somewhere for there to be a trigger
+        let ccr = ((offset * (arr as f32 + 1.0)) as u16).max(1).min(arr);
+        self.timer.ccr1.write(|w| w.ccr().bits(ccr));
+    }
+
+    /// Passes each scan in the block that's ready to `f`, as an (A, B) pair
+    /// for each encoder.
+    pub fn read<F>(&mut self, mut f: F)
+    where F: FnMut(&[(u16, u16); ENCODERS]) {
+        let isr = self.channel.isr();
+        let (half, full) = (isr.htif1().bit_is_set(), isr.tcif1().bit_is_set());
+        self.channel.ifcr().write(|w| w.chtif1().set_bit().ctcif1().set_bit());
+
+        let block = match (half, full) {
+            (false, false) => return,
+            (true, false) => &self.buffer[0],
+            (false, true) => &self.buffer[1],
+            (true, true) => {
+                // We've missed one; the second half is the most recent
+                self.rate.overruns += 1;
+                &self.buffer[1]
+            }
+        };
+
+        let mut auxiliary = (0, 0);
+        for scan in block.chunks(RANKS) {
+            let mut samples = [(0, 0); ENCODERS];
+            for (sample, word) in samples.iter_mut().zip(scan) {
+                *sample = pair(*word);
+            }
+            f(&samples);
+            if let Some(word) = scan.get(ENCODERS) {
+                let (a, b) = pair(*word);
+                auxiliary = (auxiliary.0 + a as u32, auxiliary.1 + b as u32);
+            }
+        }
+        if AUXILIARY > 0 {
+            let n = BLOCK as u32;
+            self.auxiliary = Some(((auxiliary.0 / n) as u16, (auxiliary.1 / n) as u16));
+        }
+        self.blocks = self.blocks.wrapping_add(1);
+        self.count(BLOCK as u32);
+    }
+
+    // Update the measured rate about once a second
+    fn count(&mut self, samples: u32) {
+        self.samples += samples;
+        let elapsed = self.since.elapsed().as_cycles();
+        if elapsed >= self.sysclk {
+            self.rate.measured = ((self.samples as u64 * self.sysclk as u64) / elapsed as u64) as u32;
+            self.samples = 0;
+            self.since = Instant::now();
+        }
+    }
+}
+
+fn pair(word: u32) -> (u16, u16) {
+    (word as u16, (word >> 16) as u16)
+}
+
+fn adc1_dr() -> u32 {
+    #[allow(unsafe_code)]
+    let dr = unsafe { &(*ADC1::ptr()).dr as *const _ as u32 };
+    dr
+}
+
+// The HAL only knows about independent mode, so set up the rest behind its
+// back: regular simultaneous dual mode, scanning, and DMA from ADC1 only.
+#[allow(unsafe_code)]
+fn dual_simultaneous_mode() {
+    let (adc1, adc2) = unsafe { (&*ADC1::ptr(), &*ADC2::ptr()) };
+    adc1.cr1.modify(|_, w| unsafe { w.dualmod().bits(0b0110) }.scan().set_bit());
+    adc2.cr1.modify(|_, w| w.scan().set_bit());
+    adc1.cr2.modify(|_, w| w.dma().set_bit().cont().clear_bit());
+    adc2.cr2.modify(|_, w| w.dma().clear_bit().cont().clear_bit().exttrig().set_bit().extsel().swstart());
+}
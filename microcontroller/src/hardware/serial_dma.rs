@@ -0,0 +1,195 @@
+use heapless::ArrayLength;
+use heapless::spsc::{ Producer, Consumer };
+use protocol::LinkStatistics;
+use stm32f1::stm32f103::USART1;
+use stm32f1xx_hal::{
+    dma::{ dma1, Event },
+    gpio::{ Alternate, Floating, Input, PushPull },
+    gpio::gpioa::{ PA9, PA10 },
+};
+
+use crate::rpc::Link;
+
+pub const RX_BUFFER: usize = 256;
+pub const TX_BUFFER: usize = 256;
+
+/// USART1 with DMA in both directions. Receive runs continuously into a
+/// circular buffer, which is drained when the line goes idle or the buffer is
+/// half full. Transmit sends a whole frame from the response queue at a time.
+pub struct DmaSerialLink {
+    usart: USART1,
+    _pins: (PA9<Alternate<PushPull>>, PA10<Input<Floating>>),
+    rx_channel: dma1::C5,
+    tx_channel: dma1::C4,
+    rx_buffer: &'static mut [u8; RX_BUFFER],
+    // Where we have read the circular buffer up to
+    rx_position: usize,
+    rx_overruns: u32,
+    tx_buffer: &'static mut [u8; TX_BUFFER],
+    transmitting: bool,
+    // The USART's input clock
+    pclk: u32,
+    baud_rate: u32,
+    pending_baud_rate: Option<u32>,
+    baud_rate_switched: bool,
+}
+
+impl DmaSerialLink {
+    /// Takes over a USART that has already been configured (pins, clock and
+    /// baud rate) by the HAL.
+    pub fn new(
+        usart: USART1,
+        pins: (PA9<Alternate<PushPull>>, PA10<Input<Floating>>),
+        pclk: u32,
+        baud_rate: u32,
+        mut rx_channel: dma1::C5,
+        mut tx_channel: dma1::C4,
+        rx_buffer: &'static mut [u8; RX_BUFFER],
+        tx_buffer: &'static mut [u8; TX_BUFFER]) -> Self {
+
+        let dr = &usart.dr as *const _ as u32;
+
+        rx_channel.set_peripheral_address(dr, false);
+        rx_channel.set_memory_address(rx_buffer.as_ptr() as u32, true);
+        rx_channel.set_transfer_length(RX_BUFFER);
+        rx_channel.ch().cr.modify(|_, w| w
+            .mem2mem().clear_bit()
+            .pl().medium()
+            .msize().bits8()
+            .psize().bits8()
+            .circ().set_bit()
+            .dir().clear_bit());
+        rx_channel.listen(Event::HalfTransfer);
+        rx_channel.listen(Event::TransferComplete);
+
+        tx_channel.set_peripheral_address(dr, false);
+        tx_channel.set_memory_address(tx_buffer.as_ptr() as u32, true);
+        tx_channel.ch().cr.modify(|_, w| w
+            .mem2mem().clear_bit()
+            .pl().medium()
+            .msize().bits8()
+            .psize().bits8()
+            .circ().clear_bit()
+            .dir().set_bit());
+        tx_channel.listen(Event::TransferComplete);
+
+        usart.cr3.modify(|_, w| w.dmar().set_bit().dmat().set_bit());
+        usart.cr1.modify(|_, w| w.idleie().set_bit());
+        rx_channel.start();
+
+        DmaSerialLink {
+            usart: usart,
+            _pins: pins,
+            rx_channel: rx_channel,
+            tx_channel: tx_channel,
+            rx_buffer: rx_buffer,
+            rx_position: 0,
+            rx_overruns: 0,
+            tx_buffer: tx_buffer,
+            transmitting: false,
+            pclk: pclk,
+            baud_rate: baud_rate,
+            pending_baud_rate: None,
+            baud_rate_switched: false,
+        }
+    }
+
+    #[allow(unsafe_code)]
+    fn apply_baud_rate(&mut self, baud_rate: u32) {
+        // Let the last byte go before changing the rate under it
+        while self.usart.sr.read().tc().bit_is_clear() {}
+        let div = (self.pclk + baud_rate / 2) / baud_rate;
+        self.usart.brr.write(|w| unsafe { w.bits(div) });
+        self.baud_rate = self.pclk / div;
+        self.baud_rate_switched = true;
+    }
+}
+
+impl Link for DmaSerialLink {
+    fn receive<N>(&mut self, requests: &mut Producer<'_, u8, N>) -> bool
+    where N: ArrayLength<u8> {
+        // Reading SR then DR clears the idle and overrun flags; the DR read
+        // doesn't disturb the DMA, which has already taken the byte.
+        let sr = self.usart.sr.read();
+        if sr.idle().bit_is_set() || sr.ore().bit_is_set() {
+            self.usart.dr.read();
+        }
+        let isr = self.rx_channel.isr();
+        let passed_both = isr.htif5().bit_is_set() && isr.tcif5().bit_is_set();
+        self.rx_channel.ifcr().write(|w| w.chtif5().set_bit().ctcif5().set_bit());
+
+        let end = (RX_BUFFER - self.rx_channel.get_ndtr() as usize) % RX_BUFFER;
+        let mut read = false;
+        // Passing both the half way and the end takes more than half the
+        // buffer, so if it's passed both and is less than half way ahead it
+        // has come round and written over what we hadn't read yet.
+        let ahead = (end + RX_BUFFER - self.rx_position) % RX_BUFFER;
+        let lapped = passed_both && ahead < RX_BUFFER / 2;
+        if lapped || sr.ore().bit_is_set() {
+            self.rx_overruns += 1;
+            // Terminate the partial frame, so it fails to decode and is
+            // thrown away, and start again from the next delimiter
+            read |= requests.enqueue(0).is_ok();
+            if lapped {
+                self.rx_position = end;
+            }
+        }
+        while self.rx_position != end {
+            read |= requests.enqueue(self.rx_buffer[self.rx_position]).is_ok();
+            self.rx_position = (self.rx_position + 1) % RX_BUFFER;
+        }
+        return read;
+    }
+
+    fn transmit<N>(&mut self, responses: &mut Consumer<'_, u8, N>)
+    where N: ArrayLength<u8> {
+        if self.transmitting {
+            if self.tx_channel.in_progress() {
+                return;
+            }
+            self.tx_channel.stop();
+            self.transmitting = false;
+        }
+
+        // Up to the end of the next frame, or as much of it as fits
+        let mut len = 0;
+        while len < TX_BUFFER {
+            match responses.dequeue() {
+                Some(byte) => {
+                    self.tx_buffer[len] = byte;
+                    len += 1;
+                    if byte == 0 { break; }
+                },
+                None => break,
+            }
+        }
+
+        if len > 0 {
+            self.tx_channel.set_transfer_length(len);
+            self.tx_channel.start();
+            self.transmitting = true;
+        } else if let Some(baud_rate) = self.pending_baud_rate.take() {
+            self.apply_baud_rate(baud_rate);
+        }
+    }
+
+    fn set_baud_rate(&mut self, baud_rate: u32) -> Option<u32> {
+        if !super::baud_rate_possible(self.pclk, baud_rate) {
+            return None;
+        }
+        self.pending_baud_rate = Some(baud_rate);
+        Some(self.pclk / ((self.pclk + baud_rate / 2) / baud_rate))
+    }
+
+    fn baud_rate(&self) -> Option<u32> {
+        Some(self.baud_rate)
+    }
+
+    fn take_baud_rate_switched(&mut self) -> bool {
+        core::mem::replace(&mut self.baud_rate_switched, false)
+    }
+
+    fn add_statistics(&self, statistics: &mut LinkStatistics) {
+        statistics.rx_overruns += self.rx_overruns;
+    }
+}
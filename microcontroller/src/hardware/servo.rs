@@ -0,0 +1,115 @@
+use embedded_hal::PwmPin;
+use stm32f1xx_hal::{
+    pac::TIM4,
+    pwm::{ PwmChannel, C1, C2, C3, C4 },
+};
+use protocol::{ ServoConfig, MAX_SERVOS };
+
+/// Hobby servos want a pulse every 20ms
+pub const FREQUENCY: u32 = 50;
+const PERIOD_US: u32 = 1_000_000 / FREQUENCY;
+
+pub type ServoOutputs = (
+    PwmChannel<TIM4, C1>, PwmChannel<TIM4, C2>, PwmChannel<TIM4, C3>, PwmChannel<TIM4, C4>);
+
+/// Hobby servos on TIM4's channels, PB6 to PB9. Each one is limp, with no
+/// pulses, until it's given an angle.
+pub struct Servos {
+    outputs: ServoOutputs,
+    configs: [ServoConfig; MAX_SERVOS],
+    angles: [Option<f32>; MAX_SERVOS],
+}
+
+impl Servos {
+    /// `outputs` must be running at FREQUENCY
+    pub fn new(outputs: ServoOutputs) -> Self {
+        let mut servos = Servos {
+            outputs: outputs,
+            configs: [ServoConfig::default(); MAX_SERVOS],
+            angles: [None; MAX_SERVOS],
+        };
+        for servo in 0..MAX_SERVOS {
+            servos.output(servo);
+        }
+        servos
+    }
+
+    pub fn config(&self, servo: usize) -> Option<ServoConfig> {
+        self.configs.get(servo).copied()
+    }
+
+    /// Returns false if there's no such servo or the config doesn't make
+    /// sense. A servo that's holding an angle moves to where the new config
+    /// puts it.
+    pub fn configure(&mut self, servo: usize, config: ServoConfig) -> bool {
+        if servo >= MAX_SERVOS
+            || !(config.min_pulse_us > 0
+                && config.min_pulse_us < config.max_pulse_us
+                && (config.max_pulse_us as u32) < PERIOD_US
+                && config.min_angle.is_finite()
+                && config.max_angle.is_finite()
+                && config.min_angle != config.max_angle) {
+            return false;
+        }
+        self.configs[servo] = config;
+        self.output(servo);
+        true
+    }
+
+    /// None while it's limp
+    pub fn angle(&self, servo: usize) -> Option<f32> {
+        self.angles.get(servo).copied().flatten()
+    }
+
+    /// Returns false if there's no such servo or the angle is outside its
+    /// range
+    pub fn set(&mut self, servo: usize, angle: Option<f32>) -> bool {
+        let config = match self.config(servo) {
+            Some(config) => config,
+            None => return false,
+        };
+        if let Some(angle) = angle {
+            let fraction = (angle - config.min_angle) / (config.max_angle - config.min_angle);
+            if !(fraction >= 0.0 && fraction <= 1.0) {
+                return false;
+            }
+        }
+        self.angles[servo] = angle;
+        self.output(servo);
+        true
+    }
+
+    // Set the pulse to match the angle, or stop it
+    fn output(&mut self, servo: usize) {
+        let pulse = match self.angles[servo] {
+            Some(angle) => Some(pulse_us(&self.configs[servo], angle)),
+            None => None,
+        };
+        let out: &mut dyn PwmPin<Duty = u16> = match servo {
+            0 => &mut self.outputs.0,
+            1 => &mut self.outputs.1,
+            2 => &mut self.outputs.2,
+            _ => &mut self.outputs.3,
+        };
+        match pulse {
+            Some(pulse) => {
+                let duty = pulse * (out.get_max_duty() as u32 + 1) / PERIOD_US;
+                out.set_duty(duty as u16);
+                out.enable();
+            },
+            None => {
+                out.disable();
+                out.set_duty(0);
+            },
+        }
+    }
+}
+
+// The angle is clamped to the range, which it can only be outside of when
+// the config has just changed
+fn pulse_us(config: &ServoConfig, angle: f32) -> u32 {
+    let fraction = (angle - config.min_angle) / (config.max_angle - config.min_angle);
+    let fraction = fraction.max(0.0).min(1.0);
+    let span = (config.max_pulse_us - config.min_pulse_us) as f32;
+    config.min_pulse_us as u32 + (fraction * span) as u32
+}
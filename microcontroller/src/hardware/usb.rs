@@ -0,0 +1,66 @@
+use heapless::ArrayLength;
+use heapless::spsc::{ Producer, Consumer };
+use stm32f1xx_hal::usb::UsbBusType;
+use usb_device::{ prelude::*, bus::UsbBusAllocator };
+use usbd_serial::{ SerialPort, USB_CLASS_CDC };
+
+use crate::rpc::Link;
+
+/// The command link as a USB CDC-ACM serial port. The device has to be polled
+/// from the USB interrupt, so that happens on every `receive`.
+pub struct UsbLink {
+    device: UsbDevice<'static, UsbBusType>,
+    serial: SerialPort<'static, UsbBusType>,
+}
+
+impl UsbLink {
+    pub fn new(bus: &'static UsbBusAllocator<UsbBusType>) -> Self {
+        let serial = SerialPort::new(bus);
+        let device = UsbDeviceBuilder::new(bus, UsbVidPid(protocol::USB_VID, protocol::USB_PID))
+            .manufacturer("davidji")
+            .product("quadrature")
+            .serial_number("0001")
+            .device_class(USB_CLASS_CDC)
+            .build();
+        UsbLink { device: device, serial: serial }
+    }
+}
+
+impl Link for UsbLink {
+    fn receive<N>(&mut self, requests: &mut Producer<'_, u8, N>) -> bool
+    where N: ArrayLength<u8> {
+        // Whatever was left in the port when the queue was full is read even
+        // if the host hasn't sent anything new
+        self.device.poll(&mut [&mut self.serial]);
+
+        // Like SerialLink, only take what the queue has room for. The rest
+        // stays in the port, which holds the host off until it's read.
+        let mut read = false;
+        let mut byte = [0u8; 1];
+        while requests.ready() {
+            match self.serial.read(&mut byte) {
+                Ok(1) => read |= requests.enqueue(byte[0]).is_ok(),
+                // Nothing to read, or the host has gone away
+                _ => break,
+            }
+        }
+        return read;
+    }
+
+    fn transmit<N>(&mut self, responses: &mut Consumer<'_, u8, N>)
+    where N: ArrayLength<u8> {
+        loop {
+            match responses.peek() {
+                Some(byte) => {
+                    match self.serial.write(&[*byte]) {
+                        Ok(_) => assert!(responses.dequeue().is_some()),
+                        // The host isn't reading, or has gone away; try
+                        // again next time
+                        Err(_) => break,
+                    }
+                }
+                None => break
+            }
+        }
+    }
+}
@@ -0,0 +1,72 @@
+use embedded_hal::watchdog::{ Watchdog as _, WatchdogEnable as _ };
+use stm32f1xx_hal::{
+    pac::{ DBGMCU, IWDG, RCC },
+    time::U32Ext,
+    watchdog::IndependentWatchdog,
+};
+use protocol::ResetCause;
+
+/// Long enough to ride out a flash page erase while the settings are saved,
+/// which stalls everything for up to 40ms
+pub const TIMEOUT_MS: u32 = 250;
+
+/// The independent watchdog, which resets the microcontroller, and so frees
+/// the motors, unless the control loop feeds it. It's only fed while the
+/// sampling is still going as well.
+pub struct Watchdog {
+    iwdg: IndependentWatchdog,
+    reset_cause: ResetCause,
+    // The sampler's block count when it was last fed
+    blocks: u32,
+}
+
+impl Watchdog {
+    /// Works out why we were reset before starting the watchdog. It stops
+    /// while a debugger has the core halted.
+    pub fn new(iwdg: IWDG, dbgmcu: &DBGMCU) -> Self {
+        let reset_cause = reset_cause();
+        let mut iwdg = IndependentWatchdog::new(iwdg);
+        iwdg.stop_on_debug(dbgmcu, true);
+        iwdg.start(TIMEOUT_MS.ms());
+        Watchdog {
+            iwdg: iwdg,
+            reset_cause: reset_cause,
+            blocks: 0,
+        }
+    }
+
+    pub fn reset_cause(&self) -> ResetCause {
+        self.reset_cause
+    }
+
+    /// Call every control period with the number of blocks the sampler has
+    /// read. It's fed if there have been any since last time.
+    pub fn feed(&mut self, blocks: u32) {
+        if blocks != self.blocks {
+            self.blocks = blocks;
+            self.iwdg.feed();
+        }
+    }
+}
+
+// Reads the reset flags, then clears them for next time. A pin reset is
+// flagged along with every other kind, so it only counts on its own.
+#[allow(unsafe_code)]
+fn reset_cause() -> ResetCause {
+    let rcc = unsafe { &*RCC::ptr() };
+    let csr = rcc.csr.read();
+    rcc.csr.modify(|_, w| w.rmvf().set_bit());
+    if csr.iwdgrstf().bit_is_set() {
+        ResetCause::Watchdog
+    } else if csr.wwdgrstf().bit_is_set() {
+        ResetCause::WindowWatchdog
+    } else if csr.sftrstf().bit_is_set() {
+        ResetCause::Software
+    } else if csr.lpwrrstf().bit_is_set() {
+        ResetCause::LowPower
+    } else if csr.porrstf().bit_is_set() {
+        ResetCause::PowerOn
+    } else {
+        ResetCause::Pin
+    }
+}
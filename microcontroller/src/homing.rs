@@ -0,0 +1,104 @@
+use libm::fabsf;
+use protocol::{ Event, HomingState, ResponseBody };
+
+use crate::{ CONTROL_PERIOD, CONTROL_PERIOD_MS };
+use crate::hardware::{ Mode, Motors };
+
+/// How often the homing task runs, in cycles
+pub const HOMING_PERIOD: u32 = CONTROL_PERIOD;
+// Steps between progress reports
+const PROGRESS_STEPS: u32 = 10;
+
+/// Drives a motor slowly until its encoder's index pulse comes round, then
+/// stops it and makes the index position 0.
+pub struct Homing {
+    /// The Home request's, which every report about it carries
+    pub correlation_id: i32,
+    encoder: usize,
+    duty: f32,
+    steps: u32,
+    timeout_steps: u32,
+}
+
+impl Homing {
+    /// Sets the motor going. Fails if the encoder doesn't have both a motor
+    /// and an index, or the duty isn't a number from -1 to 1 other than 0.
+    pub fn start(
+        correlation_id: i32,
+        encoder: usize,
+        duty: f32,
+        timeout_ms: u32,
+        motors: &mut Motors) -> Option<Self> {
+
+        if !motors.has_index(encoder) || !duty.is_finite() || duty == 0.0 || fabsf(duty) > 1.0 {
+            return None;
+        }
+        motors.encoder(encoder)?.clear_index();
+        if !motors.drive(encoder, duty, Mode::Free) {
+            return None;
+        }
+        Some(Homing {
+            correlation_id: correlation_id,
+            encoder: encoder,
+            duty: duty,
+            steps: 0,
+            timeout_steps: timeout_ms / CONTROL_PERIOD_MS,
+        })
+    }
+
+    /// Call every HOMING_PERIOD. Returns a report when there's something to
+    /// say, and whether homing has finished.
+    pub fn step(&mut self, motors: &mut Motors) -> (Option<ResponseBody>, bool) {
+        self.steps += 1;
+        let index = motors.encoder(self.encoder).and_then(|encoder| encoder.index());
+        let state = match index {
+            Some(_) => HomingState::Homed,
+            None if self.steps >= self.timeout_steps => HomingState::TimedOut,
+            None => HomingState::Searching,
+        };
+
+        match state {
+            HomingState::Searching => {
+                // In case anything else has changed it
+                motors.drive(self.encoder, self.duty, Mode::Free);
+                if self.steps % PROGRESS_STEPS != 0 {
+                    return (None, false);
+                }
+            },
+            _ => {
+                motors.free(self.encoder);
+                if let (Some(index), Some(encoder)) = (index, motors.encoder(self.encoder)) {
+                    encoder.set_origin(index);
+                }
+            },
+        }
+        (Some(self.report(state, motors)), state != HomingState::Searching)
+    }
+
+    /// Call with the motor protection's events each control period. If it's
+    /// freed the motor being homed, says so, and homing should stop.
+    pub fn check_stall(&self, events: &[Event], motors: &mut Motors) -> Option<ResponseBody> {
+        let stalled = events.iter().any(|event| match *event {
+            Event::Stall { encoder, .. } => encoder as usize == self.encoder,
+            _ => false,
+        });
+        if !stalled {
+            return None;
+        }
+        Some(self.report(HomingState::Stalled, motors))
+    }
+
+    /// Stops the motor where it is
+    pub fn cancel(&mut self, motors: &mut Motors) -> ResponseBody {
+        motors.free(self.encoder);
+        self.report(HomingState::Cancelled, motors)
+    }
+
+    pub fn report(&self, state: HomingState, motors: &mut Motors) -> ResponseBody {
+        ResponseBody::Homing {
+            encoder: self.encoder as u8,
+            state: state,
+            position: motors.encoder(self.encoder).map_or(0.0, |encoder| encoder.position()),
+        }
+    }
+}
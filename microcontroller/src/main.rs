@@ -6,21 +6,84 @@
 
 mod rpc;
 mod hardware;
+mod settings;
+mod capture;
+mod homing;
 // mod int_pid;
 
 extern crate panic_semihosting;
 extern crate nb;
 
-use stm32f1::stm32f103;
 use protocol;
+use logic::CONTROL_PERIOD_MS;
 use heapless::{ consts::* };
-use hardware::{ CommandTx, CommandRx, Motors, hardware };
+use hardware::{ CommandLink, Encoder, Motors, Sampler, COMMAND_INTERRUPT, hardware };
+use hardware::sampling::SampleRate;
+use hardware::reference::Reference;
+use hardware::servo::Servos;
+use hardware::pwm::{ Cutoff, PwmTimer };
+use hardware::supply::Supply;
+use hardware::protection::{ self, Protection };
+use hardware::power::Power;
+use hardware::i2c::{ self, I2cBus };
+use hardware::imu::Imu;
+use hardware::heading::HeadingFilter;
+use hardware::estop::EStop;
+use hardware::watchdog::{ self, Watchdog };
+use rpc::Link;
+use settings::{ Settings, SettingsStore };
+use capture::{ Capture, CaptureBuffer };
+use homing::{ Homing, HOMING_PERIOD };
+use rtfm::Mutex;
 use rtfm::cyccnt::{ Instant, U32Ext };
 
 type Transport = rpc::Transport<'static, U256, U256>;
 type Service = rpc::Service<'static, U256, U256, U256>;
 
-const PERIOD: u32 = 8_000_000;
+// How long the host has to talk to us at a new baud rate: 2s at 72MHz
+const BAUD_RATE_CONFIRM: u32 = 144_000_000;
+const CYCLES_PER_MS: u32 = 72_000;
+const CONTROL_PERIOD: u32 = CONTROL_PERIOD_MS * CYCLES_PER_MS;
+
+/// A baud rate change waiting to be confirmed by a request arriving at the
+/// new rate.
+pub struct BaudRateChange {
+    previous: u32,
+    persist: bool,
+}
+
+/// What a request might need to look at or change. Things the sampling
+/// and e-stop interrupts also use are locked only while a request needs them.
+struct RequestContext<'a, C, M, R, S, E>
+where C: Mutex<T = Capture>, M: Mutex<T = Motors>, R: Mutex<T = Option<Reference>>, S: Mutex<T = Sampler>,
+      E: Mutex<T = EStop> {
+    statistics: protocol::LinkStatistics,
+    sample_rate: SampleRate,
+    command_link: &'a mut CommandLink,
+    sampler: S,
+    capture: C,
+    motors: M,
+    reference: R,
+    estop: E,
+    watchdog: &'a Watchdog,
+    homing: &'a mut Option<Homing>,
+    servos: &'a mut Option<Servos>,
+    pwm: &'a mut Option<PwmTimer>,
+    supply: &'a mut Option<Supply>,
+    protection: &'a mut Option<Protection>,
+    power: &'a mut Power,
+    i2c: &'a mut I2cBus,
+    imu: &'a mut Option<Imu>,
+    heading: &'a mut HeadingFilter,
+    event_watcher: &'a mut Option<i32>,
+    settings: &'a mut Settings,
+    settings_store: &'a mut SettingsStore,
+    // A baud rate change is waiting to be confirmed
+    baud_rate_unconfirmed: bool,
+    // Set when a request needs checking up on later
+    baud_rate_change: Option<BaudRateChange>,
+    homing_started: bool,
+}
 
 #[rtfm::app(device = stm32f1::stm32f103, monotonic = rtfm::cyccnt::CYCCNT)]
 const APP: () = {
@@ -28,77 +91,882 @@ const APP: () = {
     struct Resources {
         transport: Transport,
         service: Service,
-        command_tx: CommandTx,
-        command_rx: CommandRx,
-        motors : Motors
+        command_link: CommandLink,
+        motors : Motors,
+        reference: Option<Reference>,
+        servos: Option<Servos>,
+        pwm: Option<PwmTimer>,
+        supply: Option<Supply>,
+        protection: Option<Protection>,
+        power: Power,
+        i2c: I2cBus,
+        imu: Option<Imu>,
+        heading: HeadingFilter,
+        estop: EStop,
+        cutoff: Cutoff,
+        watchdog: Watchdog,
+        // The WatchEvents request events are sent as responses to
+        #[init(None)]
+        event_watcher: Option<i32>,
+        // Good frames received when the baud rate last changed
+        #[init(None)]
+        frames_at_baud_rate_switch: Option<u32>,
+        #[init(false)]
+        baud_rate_unconfirmed: bool,
+        sampler: Sampler,
+        capture: Capture,
+        #[init(None)]
+        homing: Option<Homing>,
+        settings_store: SettingsStore,
+        settings: Settings,
    }
 
-    #[init(schedule=[quadrature])]
+    #[init(schedule = [control])]
     fn init(c: init::Context) -> init::LateResources {
         static mut RPC: Option<rpc::Rpc<U256, U256>> = None;
         *RPC = Some(rpc::Rpc::new());
+        static mut CAPTURE: CaptureBuffer =
+            [[0; protocol::CAPTURE_CHANNELS]; protocol::CAPTURE_LENGTH as usize];
 
-        let (command_serial, motors) = hardware();
+        let hardware = hardware();
 
         let (transport, service) = RPC.as_mut().unwrap().split();
 
-        rtfm::pend(stm32f103::Interrupt::USART1);
-        let (mut tx, mut rx) = command_serial.split();
-        rx.listen();
-        tx.listen();
-
-		c.schedule.quadrature(Instant::now() + PERIOD.cycles()).unwrap();
+        rtfm::pend(COMMAND_INTERRUPT);
+        c.schedule.control(c.start + CONTROL_PERIOD.cycles()).unwrap();
 
         init::LateResources {
             transport: transport,
             service: service,
-            command_tx: tx,
-            command_rx: rx,
-            motors: motors,
+            command_link: hardware.command_link,
+            motors: hardware.motors,
+            reference: hardware.reference,
+            servos: hardware.servos,
+            pwm: hardware.pwm,
+            supply: hardware.supply,
+            protection: hardware.protection,
+            power: hardware.power,
+            i2c: hardware.i2c,
+            imu: hardware.imu,
+            heading: hardware.heading,
+            estop: hardware.estop,
+            cutoff: Cutoff::new(),
+            watchdog: hardware.watchdog,
+            sampler: hardware.sampler,
+            capture: Capture::new(CAPTURE),
+            settings_store: hardware.settings_store,
+            settings: hardware.settings,
         }
     }
 
+    // Only one of the command link interrupts is ever enabled, depending on
+    // which link hardware() built.
     #[task(binds = USART1,
-           resources = [command_tx, command_rx, transport],
+           resources = [command_link, transport],
            spawn = [ command_serial_rx_frame ])]
     fn command_serial_poll(c: command_serial_poll::Context) {
-        if c.resources.transport.read_nb(c.resources.command_rx) {
+        if poll_command_link(c.resources.transport, c.resources.command_link) {
+            c.spawn.command_serial_rx_frame().unwrap();
+        }
+    }
+
+    #[task(binds = USB_LP_CAN_RX0,
+           resources = [command_link, transport],
+           spawn = [ command_serial_rx_frame ])]
+    fn command_usb_poll(c: command_usb_poll::Context) {
+        if poll_command_link(c.resources.transport, c.resources.command_link) {
+            c.spawn.command_serial_rx_frame().unwrap();
+        }
+    }
+
+    // USART1 DMA receive half and fully complete
+    #[task(binds = DMA1_CHANNEL5,
+           resources = [command_link, transport],
+           spawn = [ command_serial_rx_frame ])]
+    fn command_dma_rx_poll(c: command_dma_rx_poll::Context) {
+        if poll_command_link(c.resources.transport, c.resources.command_link) {
             c.spawn.command_serial_rx_frame().unwrap();
         }
-        c.resources.transport.write_nb(c.resources.command_tx);
     }
 
-    #[task(resources = [command_tx, transport])]
+    // USART1 DMA transmit complete
+    #[task(binds = DMA1_CHANNEL4,
+           resources = [command_link, transport])]
+    fn command_dma_tx_poll(c: command_dma_tx_poll::Context) {
+        c.resources.transport.write_nb(c.resources.command_link);
+    }
+
+    #[task(binds = EXTI15_10,
+           resources = [command_link, transport],
+           spawn = [ command_serial_rx_frame ])]
+    fn command_radio_poll(c: command_radio_poll::Context) {
+        if poll_command_link(c.resources.transport, c.resources.command_link) {
+            c.spawn.command_serial_rx_frame().unwrap();
+        }
+    }
+
+    #[task(resources = [command_link, transport])]
     fn command_serial_tx(c: command_serial_tx::Context) {
-        c.resources.transport.write_nb(c.resources.command_tx);
+        c.resources.transport.write_nb(c.resources.command_link);
     }
 
-    #[task(resources = [service], spawn = [command_serial_tx])]
-    fn command_serial_rx_frame(c: command_serial_rx_frame::Context) {
-        c.resources.service.process(process_request);
+    #[task(resources = [service, command_link, sampler, capture, motors, reference, homing,
+                        servos, pwm, supply, protection, power, i2c, imu, heading, estop,
+                        watchdog, event_watcher, frames_at_baud_rate_switch,
+                        baud_rate_unconfirmed, settings, settings_store],
+           spawn = [command_serial_tx],
+           schedule = [baud_rate_confirm, homing_step])]
+    fn command_serial_rx_frame(mut c: command_serial_rx_frame::Context) {
+        // Anything decoded from here on came at the new rate
+        if c.resources.command_link.take_baud_rate_switched() {
+            *c.resources.frames_at_baud_rate_switch = Some(good_frames(&c.resources.service.statistics()));
+        }
+        let mut statistics = c.resources.service.statistics();
+        c.resources.command_link.add_statistics(&mut statistics);
+        let mut context = RequestContext {
+            statistics: statistics,
+            sample_rate: c.resources.sampler.lock(|sampler| sampler.rate()),
+            command_link: c.resources.command_link,
+            sampler: c.resources.sampler,
+            capture: c.resources.capture,
+            motors: c.resources.motors,
+            reference: c.resources.reference,
+            estop: c.resources.estop,
+            watchdog: c.resources.watchdog,
+            homing: c.resources.homing,
+            servos: c.resources.servos,
+            pwm: c.resources.pwm,
+            supply: c.resources.supply,
+            protection: c.resources.protection,
+            power: c.resources.power,
+            i2c: c.resources.i2c,
+            imu: c.resources.imu,
+            heading: c.resources.heading,
+            event_watcher: c.resources.event_watcher,
+            settings: c.resources.settings,
+            settings_store: c.resources.settings_store,
+            baud_rate_unconfirmed: *c.resources.baud_rate_unconfirmed,
+            baud_rate_change: None,
+            homing_started: false,
+        };
+        c.resources.service.process(|request| process_request(request, &mut context));
+        // If it's already pending it will pick up these responses too
+        c.spawn.command_serial_tx().ok();
+
+        if let Some(change) = context.baud_rate_change {
+            *c.resources.frames_at_baud_rate_switch = None;
+            match c.schedule.baud_rate_confirm(Instant::now() + BAUD_RATE_CONFIRM.cycles(), change) {
+                Ok(()) => *c.resources.baud_rate_unconfirmed = true,
+                // Nothing would ever go back, so don't change
+                Err(change) => {
+                    context.command_link.set_baud_rate(change.previous);
+                },
+            }
+        }
+        // If it's already going, it carries on with the new one
+        if context.homing_started {
+            c.schedule.homing_step(Instant::now() + HOMING_PERIOD.cycles()).ok();
+        }
     }
 
-    #[task(resources = [ motors])]
-    fn quadrature(c: quadrature::Context) {
-        c.resources.motors.update();
+    // Go back to the old baud rate if nothing has got through since it
+    // changed, otherwise save it if we were asked to.
+    #[task(resources = [service, command_link, transport, frames_at_baud_rate_switch,
+                        baud_rate_unconfirmed, settings, settings_store])]
+    fn baud_rate_confirm(c: baud_rate_confirm::Context, change: BaudRateChange) {
+        *c.resources.baud_rate_unconfirmed = false;
+        // Switched, but nothing has come in since
+        let unheard = c.resources.command_link.take_baud_rate_switched();
+        let frames = good_frames(&c.resources.service.statistics());
+        let confirmed = !unheard && c.resources.frames_at_baud_rate_switch.take()
+            .map_or(false, |switched| frames > switched);
+        if !confirmed {
+            c.resources.command_link.set_baud_rate(change.previous);
+            c.resources.transport.write_nb(c.resources.command_link);
+        } else if change.persist {
+            if let Some(baud_rate) = c.resources.command_link.baud_rate() {
+                c.resources.settings.baud_rate = baud_rate;
+                c.resources.settings_store.save(c.resources.settings).ok();
+            }
+        }
+    }
+
+    #[task(resources = [service, motors, homing],
+           spawn = [command_serial_tx],
+           schedule = [homing_step])]
+    fn homing_step(mut c: homing_step::Context) {
+        let homing = match c.resources.homing.as_mut() {
+            Some(homing) => homing,
+            None => return,
+        };
+        let (report, done) = c.resources.motors.lock(|motors| homing.step(motors));
+        if let Some(body) = report {
+            c.resources.service.response(&protocol::Response {
+                correlation_id: homing.correlation_id,
+                body: body,
+            });
+            c.spawn.command_serial_tx().ok();
+        }
+
+        if done {
+            *c.resources.homing = None;
+        } else {
+            c.schedule.homing_step(Instant::now() + HOMING_PERIOD.cycles()).ok();
+        }
+    }
+
+    // Steps the motor outputs, as far as the supply and the current allow,
+    // frees any that have stalled, keeps the heading up to date and watches
+    // the power button. It also checks on the e-stop, in case it was active
+    // before there were any edges, and feeds the watchdog if the sampling's
+    // still going.
+    #[task(resources = [service, motors, sampler, supply, protection, power, i2c, imu, heading,
+                        estop, watchdog, event_watcher, homing],
+           spawn = [command_serial_tx],
+           schedule = [control])]
+    fn control(mut c: control::Context) {
+        let (auxiliary, blocks) = c.resources.sampler.lock(|sampler| (sampler.auxiliary(), sampler.blocks()));
+        c.resources.watchdog.feed(blocks);
+        let mut scale = Some(1.0);
+        if let Some(supply) = c.resources.supply.as_mut() {
+            if let Some((voltage, _)) = auxiliary {
+                supply.update(voltage);
+            }
+            scale = supply.duty_scale();
+        }
+        let protection = c.resources.protection;
+        if let Some(protection) = protection.as_mut() {
+            if let Some((_, current)) = auxiliary {
+                protection.update_current(current);
+            }
+            scale = scale.map(|scale| scale * protection.duty_scale());
+        }
+
+        let gyro = match c.resources.imu.as_mut() {
+            Some(imu) => imu.update(c.resources.i2c).map(|reading| reading.gyro[2]),
+            None => None,
+        };
+
+        let mut estop = c.resources.estop;
+        let homing = c.resources.homing;
+        let (mut events, wheels, stopped, homing_report) = c.resources.motors.lock(|motors| {
+            let stopped = estop.lock(|estop| {
+                check_estop(estop, motors);
+                estop.take_unreported()
+            });
+            motors.step_outputs(scale);
+            let events = match protection.as_mut() {
+                Some(protection) => protection::check(protection, motors),
+                None => heapless::Vec::new(),
+            };
+            let homing_report = homing.as_ref().and_then(|homing| homing.check_stall(&events, motors));
+            (events, wheels(motors), stopped, homing_report)
+        });
+        if stopped {
+            events.push(protocol::Event::EStop).ok();
+        }
+        c.resources.heading.update(gyro, wheels);
+        if let Some(event) = c.resources.power.update() {
+            events.push(event).ok();
+        }
+        let mut send = send_events(c.resources.service, *c.resources.event_watcher, &events);
+        // The homing task finds it gone next time it runs
+        if let (Some(body), Some(homing)) = (homing_report, homing.take()) {
+            c.resources.service.response(&protocol::Response {
+                correlation_id: homing.correlation_id,
+                body: body,
+            });
+            send = true;
+        }
+        if send {
+            c.spawn.command_serial_tx().ok();
+        }
+        c.schedule.control(Instant::now() + CONTROL_PERIOD.cycles()).ok();
+    }
+
+    // The e-stop input changed. Once it's been active for the debounce time,
+    // every motor's PWM is cut there and then, without waiting for whatever
+    // has the motors, and they're halted properly once they're free.
+    #[task(binds = EXTI9_5, priority = 3, resources = [estop, cutoff],
+           spawn = [estop_halt], schedule = [estop_check])]
+    fn estop_edge(c: estop_edge::Context) {
+        let estop = c.resources.estop;
+        if estop.edge() {
+            match estop.config().debounce_ms {
+                0 => if latch_estop(estop, c.resources.cutoff) {
+                    c.spawn.estop_halt().ok();
+                },
+                debounce => {
+                    let at = Instant::now() + (debounce as u32 * CYCLES_PER_MS).cycles();
+                    c.schedule.estop_check(at).ok();
+                },
+            }
+        }
+    }
+
+    // Bouncing can mean a few of these are waiting
+    #[task(priority = 3, capacity = 4, resources = [estop, cutoff], spawn = [estop_halt])]
+    fn estop_check(c: estop_check::Context) {
+        if latch_estop(c.resources.estop, c.resources.cutoff) {
+            c.spawn.estop_halt().ok();
+        }
+    }
+
+    // Halts the motors as the e-stop says, then lets the PWM through again,
+    // as the halt has it
+    #[task(resources = [motors, estop, cutoff])]
+    fn estop_halt(c: estop_halt::Context) {
+        let mut estop = c.resources.estop;
+        let mut cutoff = c.resources.cutoff;
+        c.resources.motors.lock(|motors| {
+            estop.lock(|estop| check_estop(estop, motors));
+            cutoff.lock(|cutoff| cutoff.release());
+        });
+    }
+
+    // A block of encoder samples is ready
+    #[task(binds = DMA1_CHANNEL1, priority = 2, resources = [sampler, motors, capture, reference])]
+    fn quadrature(mut c: quadrature::Context) {
+        let sampler = c.resources.sampler;
+        let capture = c.resources.capture;
+        let reference = c.resources.reference;
+        c.resources.motors.lock(|motors| {
+            sampler.read(|samples| {
+                capture.record(samples);
+                motors.update(samples);
+            });
+
+            if let Some(reference) = reference.as_mut() {
+                if let Some(encoder) = motors.encoder(reference.encoder()) {
+                    reference.update(encoder.position());
+                }
+            }
+        });
     }
 
     extern "C" {
         fn USART2();
+        fn USART3();
     }
 };
 
-fn process_request(request : protocol::Request) -> Option<protocol::Response> {
-    match request.body {
-        protocol::RequestBody::Ping => {
-            return Some(protocol::Response {
-                correlation_id: request.correlation_id,
-                body: protocol::ResponseBody::Ping
+// Frames received that decoded
+fn good_frames(statistics: &protocol::LinkStatistics) -> u32 {
+    statistics.rx_frames - statistics.rx_errors
+}
+
+fn poll_command_link(transport: &mut Transport, link: &mut CommandLink) -> bool {
+    let read = transport.read_nb(link);
+    transport.write_nb(link);
+    read
+}
+
+fn process_request<C, M, R, S, E>(
+    request : protocol::Request,
+    context: &mut RequestContext<C, M, R, S, E>) -> Option<protocol::Response>
+where C: Mutex<T = Capture>, M: Mutex<T = Motors>, R: Mutex<T = Option<Reference>>, S: Mutex<T = Sampler>,
+      E: Mutex<T = EStop> {
+    let body = match request.body {
+        protocol::RequestBody::Ping => protocol::ResponseBody::Ping,
+        protocol::RequestBody::LinkStatistics =>
+            protocol::ResponseBody::LinkStatistics(context.statistics),
+        // Only one change at a time, or the first could never be undone
+        protocol::RequestBody::SetBaudRate { .. } if context.baud_rate_unconfirmed =>
+            protocol::ResponseBody::Error(protocol::Error::Busy),
+        protocol::RequestBody::SetBaudRate { baud_rate, persist } => {
+            let previous = context.command_link.baud_rate();
+            match (previous, context.command_link.set_baud_rate(baud_rate)) {
+                (Some(previous), Some(baud_rate)) => {
+                    context.baud_rate_unconfirmed = true;
+                    context.baud_rate_change = Some(BaudRateChange {
+                        previous: previous,
+                        persist: persist,
+                    });
+                    protocol::ResponseBody::SetBaudRate { baud_rate: baud_rate }
+                },
+                (Some(_), None) => protocol::ResponseBody::Error(protocol::Error::InvalidArgument),
+                (None, _) => protocol::ResponseBody::Error(protocol::Error::Unsupported),
+            }
+        },
+        protocol::RequestBody::SampleRate => protocol::ResponseBody::SampleRate {
+            configured: context.sample_rate.configured,
+            measured: context.sample_rate.measured,
+            overruns: context.sample_rate.overruns,
+        },
+        protocol::RequestBody::ArmCapture { trigger, pre_trigger } => context.capture.lock(|capture| {
+            if capture.arm(trigger, pre_trigger) {
+                capture_status(capture)
+            } else {
+                protocol::ResponseBody::Error(protocol::Error::InvalidArgument)
+            }
+        }),
+        protocol::RequestBody::CaptureStatus => context.capture.lock(|capture| capture_status(capture)),
+        protocol::RequestBody::ReadCapture { offset } => {
+            let mut samples = [[0; protocol::CAPTURE_CHANNELS]; protocol::CAPTURE_CHUNK];
+            match context.capture.lock(|capture| capture.read(offset, &mut samples)) {
+                Some(count) => protocol::ResponseBody::CaptureChunk {
+                    offset: offset,
+                    count: count as u8,
+                    samples: samples,
+                },
+                None => protocol::ResponseBody::Error(protocol::Error::InvalidArgument),
+            }
+        },
+        protocol::RequestBody::Encoders => context.motors.lock(|motors| {
+            protocol::ResponseBody::Encoders {
+                encoders: motors.encoders.len() as u8,
+                motors: motors.motor_count() as u8,
+            }
+        }),
+        protocol::RequestBody::EncoderHealth { encoder } => context.motors.lock(|motors| {
+            match motors.encoder(encoder as usize) {
+                Some(encoder) => protocol::ResponseBody::EncoderHealth(encoder.health()),
+                None => protocol::ResponseBody::Error(protocol::Error::InvalidArgument),
+            }
+        }),
+        protocol::RequestBody::Calibration { encoder } =>
+            calibration(&mut context.motors, encoder, |_| true),
+        protocol::RequestBody::SetCalibration { encoder, calibration: new } =>
+            calibration(&mut context.motors, encoder, |encoder| encoder.set_calibration(new)),
+        protocol::RequestBody::FreezeCalibration { encoder, frozen } =>
+            calibration(&mut context.motors, encoder, |encoder| {
+                encoder.freeze(frozen);
+                true
+            }),
+        protocol::RequestBody::Home { encoder, duty, timeout_ms } => {
+            let homing = &mut *context.homing;
+            let correlation_id = request.correlation_id;
+            let body = context.motors.lock(|motors| {
+                if let Some(mut previous) = homing.take() {
+                    previous.cancel(motors);
+                }
+                *homing = Homing::start(correlation_id, encoder as usize, duty, timeout_ms, motors);
+                match homing {
+                    Some(homing) => homing.report(protocol::HomingState::Searching, motors),
+                    None => protocol::ResponseBody::Error(protocol::Error::InvalidArgument),
+                }
             });
-        }
+            context.homing_started = context.homing.is_some();
+            body
+        },
+        protocol::RequestBody::CancelHoming => match context.homing.take() {
+            Some(mut homing) => context.motors.lock(|motors| homing.cancel(motors)),
+            None => protocol::ResponseBody::Error(protocol::Error::InvalidArgument),
+        },
+        protocol::RequestBody::MotorConfig { encoder } =>
+            match context.motors.lock(|motors| motors.config(encoder as usize)) {
+                Some(config) => protocol::ResponseBody::MotorConfig { encoder: encoder, config: config },
+                None => protocol::ResponseBody::Error(protocol::Error::InvalidArgument),
+            },
+        protocol::RequestBody::SetMotorConfig { encoder, config, persist } => {
+            if !context.motors.lock(|motors| motors.configure(encoder as usize, &config)) {
+                protocol::ResponseBody::Error(protocol::Error::InvalidArgument)
+            } else {
+                if persist {
+                    context.settings.motors[encoder as usize] = config;
+                    context.settings_store.save(context.settings).ok();
+                }
+                protocol::ResponseBody::MotorConfig { encoder: encoder, config: config }
+            }
+        },
+        protocol::RequestBody::OutputShaping { encoder } =>
+            match context.motors.lock(|motors| motors.shaping(encoder as usize)) {
+                Some(shaping) => protocol::ResponseBody::OutputShaping { encoder: encoder, shaping: shaping },
+                None => protocol::ResponseBody::Error(protocol::Error::InvalidArgument),
+            },
+        protocol::RequestBody::SetOutputShaping { encoder, shaping, persist } => {
+            if !context.motors.lock(|motors| motors.shape(encoder as usize, shaping)) {
+                protocol::ResponseBody::Error(protocol::Error::InvalidArgument)
+            } else {
+                if persist {
+                    context.settings.shaping[encoder as usize] = shaping;
+                    context.settings_store.save(context.settings).ok();
+                }
+                protocol::ResponseBody::OutputShaping { encoder: encoder, shaping: shaping }
+            }
+        },
+        protocol::RequestBody::PwmConfig => match context.pwm {
+            Some(pwm) => pwm_config(pwm),
+            None => protocol::ResponseBody::Error(protocol::Error::Unsupported),
+        },
+        protocol::RequestBody::SetPwmConfig { config, persist } => match context.pwm {
+            Some(pwm) => {
+                // Nothing else can set an output until they're all set again
+                // for the new range, as the halt or the shapers have them
+                let changed = context.motors.lock(|motors| {
+                    let changed = pwm.set(config);
+                    if changed {
+                        motors.reapply();
+                    }
+                    changed
+                });
+                if !changed {
+                    protocol::ResponseBody::Error(protocol::Error::InvalidArgument)
+                } else {
+                    // The PWM period has changed under the trigger offset
+                    let offset = pwm.sample_offset();
+                    context.sampler.lock(|sampler| sampler.set_trigger_offset(offset));
+                    if persist {
+                        context.settings.pwm = config;
+                        context.settings_store.save(context.settings).ok();
+                    }
+                    pwm_config(pwm)
+                }
+            },
+            None => protocol::ResponseBody::Error(protocol::Error::Unsupported),
+        },
+        protocol::RequestBody::SampleTrigger => match context.pwm {
+            Some(pwm) => sample_trigger(pwm),
+            None => protocol::ResponseBody::Error(protocol::Error::Unsupported),
+        },
+        protocol::RequestBody::SetSampleTrigger { trigger, persist } => match context.pwm {
+            Some(pwm) => {
+                if !pwm.set_trigger(trigger) {
+                    protocol::ResponseBody::Error(protocol::Error::InvalidArgument)
+                } else {
+                    let offset = pwm.sample_offset();
+                    context.sampler.lock(|sampler| sampler.set_trigger_offset(offset));
+                    if persist {
+                        context.settings.sample_trigger = trigger;
+                        context.settings_store.save(context.settings).ok();
+                    }
+                    sample_trigger(pwm)
+                }
+            },
+            None => protocol::ResponseBody::Error(protocol::Error::Unsupported),
+        },
+        protocol::RequestBody::Telemetry => {
+            let sample_rate = context.sample_rate.configured as f32;
+            let mut telemetry = context.motors.lock(|motors| {
+                let mut telemetry = protocol::Telemetry {
+                    encoders: motors.encoders.len() as u8,
+                    positions: [0.0; protocol::MAX_ENCODERS],
+                    velocities: [0.0; protocol::MAX_ENCODERS],
+                    supply_voltage: 0.0,
+                    supply_state: protocol::SupplyState::Normal,
+                    motor_current: 0.0,
+                    heading: 0.0,
+                    estop: protocol::EStopStatus::default(),
+                };
+                for (i, encoder) in motors.encoders.iter().enumerate() {
+                    telemetry.positions[i] = encoder.position();
+                    telemetry.velocities[i] = encoder.velocity() * sample_rate;
+                }
+                telemetry
+            });
+            if let Some(supply) = context.supply {
+                telemetry.supply_voltage = supply.voltage();
+                telemetry.supply_state = supply.state();
+            }
+            if let Some(protection) = context.protection {
+                telemetry.motor_current = protection.current();
+            }
+            telemetry.heading = context.heading.heading().heading;
+            telemetry.estop = context.estop.lock(|estop| estop.status());
+            protocol::ResponseBody::Telemetry(telemetry)
+        },
+        protocol::RequestBody::Supply => match context.supply {
+            Some(supply) => supply_status(supply),
+            None => protocol::ResponseBody::Error(protocol::Error::Unsupported),
+        },
+        protocol::RequestBody::SetSupplyConfig { config, persist } => match context.supply {
+            Some(supply) => {
+                if !supply.configure(config) {
+                    protocol::ResponseBody::Error(protocol::Error::InvalidArgument)
+                } else {
+                    if persist {
+                        context.settings.supply = config;
+                        context.settings_store.save(context.settings).ok();
+                    }
+                    supply_status(supply)
+                }
+            },
+            None => protocol::ResponseBody::Error(protocol::Error::Unsupported),
+        },
+        protocol::RequestBody::WatchEvents { watch } => {
+            *context.event_watcher = if watch { Some(request.correlation_id) } else { None };
+            protocol::ResponseBody::WatchEvents { watch: watch }
+        },
+        protocol::RequestBody::MotorProtection => match context.protection {
+            Some(protection) => motor_protection(protection),
+            None => protocol::ResponseBody::Error(protocol::Error::Unsupported),
+        },
+        protocol::RequestBody::SetMotorProtection { config, persist } => match context.protection {
+            Some(protection) => {
+                if !protection.configure(config) {
+                    protocol::ResponseBody::Error(protocol::Error::InvalidArgument)
+                } else {
+                    if persist {
+                        context.settings.protection = config;
+                        context.settings_store.save(context.settings).ok();
+                    }
+                    motor_protection(protection)
+                }
+            },
+            None => protocol::ResponseBody::Error(protocol::Error::Unsupported),
+        },
+        protocol::RequestBody::Servo { servo } => match context.servos {
+            Some(servos) => servo_status(servos, servo),
+            None => protocol::ResponseBody::Error(protocol::Error::Unsupported),
+        },
+        protocol::RequestBody::SetServo { servo, angle } => match context.servos {
+            Some(servos) => {
+                if !servos.set(servo as usize, angle) {
+                    protocol::ResponseBody::Error(protocol::Error::InvalidArgument)
+                } else {
+                    servo_status(servos, servo)
+                }
+            },
+            None => protocol::ResponseBody::Error(protocol::Error::Unsupported),
+        },
+        protocol::RequestBody::SetServoConfig { servo, config, persist } => match context.servos {
+            Some(servos) => {
+                if !servos.configure(servo as usize, config) {
+                    protocol::ResponseBody::Error(protocol::Error::InvalidArgument)
+                } else {
+                    if persist {
+                        context.settings.servos[servo as usize] = config;
+                        context.settings_store.save(context.settings).ok();
+                    }
+                    servo_status(servos, servo)
+                }
+            },
+            None => protocol::ResponseBody::Error(protocol::Error::Unsupported),
+        },
+        protocol::RequestBody::Power => power_status(context.power),
+        protocol::RequestBody::PowerOff { delay_ms } => {
+            context.power.power_off(delay_ms);
+            power_status(context.power)
+        },
+        protocol::RequestBody::Imu => match context.imu {
+            Some(imu) if imu.failing() => protocol::ResponseBody::Error(protocol::Error::Bus),
+            Some(imu) => protocol::ResponseBody::Imu(imu.reading()),
+            None => protocol::ResponseBody::Error(protocol::Error::Unsupported),
+        },
+        protocol::RequestBody::Heading => heading_status(context.heading),
+        protocol::RequestBody::SetHeading { heading } => {
+            context.heading.set(heading);
+            heading_status(context.heading)
+        },
+        protocol::RequestBody::SetHeadingConfig { config, persist } => {
+            if !context.heading.configure(config) {
+                protocol::ResponseBody::Error(protocol::Error::InvalidArgument)
+            } else {
+                if persist {
+                    context.settings.heading = config;
+                    context.settings_store.save(context.settings).ok();
+                }
+                heading_status(context.heading)
+            }
+        },
+        protocol::RequestBody::I2c { address, write, data, read } => {
+            let (write, read) = (write as usize, read as usize);
+            let mut buffer = [0; protocol::I2C_MAX];
+            if address > 0x7f || write > protocol::I2C_MAX || read > protocol::I2C_MAX {
+                protocol::ResponseBody::Error(protocol::Error::InvalidArgument)
+            } else {
+                match i2c::transfer(context.i2c, address, &data[..write], &mut buffer[..read]) {
+                    Ok(()) => protocol::ResponseBody::I2c { read: read as u8, data: buffer },
+                    Err(_) => protocol::ResponseBody::Error(protocol::Error::Bus),
+                }
+            }
+        },
+        protocol::RequestBody::EStop => context.estop.lock(|estop| estop_status(estop)),
+        protocol::RequestBody::SetEStopConfig { config, persist } => {
+            if !context.estop.lock(|estop| estop.configure(config)) {
+                protocol::ResponseBody::Error(protocol::Error::InvalidArgument)
+            } else {
+                if persist {
+                    context.settings.estop = config;
+                    context.settings_store.save(context.settings).ok();
+                }
+                context.estop.lock(|estop| estop_status(estop))
+            }
+        },
+        protocol::RequestBody::ClearEStop => {
+            let estop = &mut context.estop;
+            context.motors.lock(|motors| estop.lock(|estop| {
+                if estop.clear() {
+                    motors.release();
+                }
+                estop_status(estop)
+            }))
+        },
+        protocol::RequestBody::DeviceInfo => protocol::ResponseBody::DeviceInfo {
+            reset_cause: context.watchdog.reset_cause(),
+            watchdog_ms: watchdog::TIMEOUT_MS,
+        },
+        protocol::RequestBody::CompareReference { encoder, reset } => {
+            let encoders = context.motors.lock(|motors| motors.encoders.len());
+            context.reference.lock(|reference| match reference {
+                None => protocol::ResponseBody::Error(protocol::Error::Unsupported),
+                Some(_) if encoder as usize >= encoders =>
+                    protocol::ResponseBody::Error(protocol::Error::InvalidArgument),
+                Some(reference) => {
+                    if reset || encoder as usize != reference.encoder() {
+                        reference.reset(encoder as usize);
+                    }
+                    protocol::ResponseBody::ReferenceComparison(reference.comparison())
+                },
+            })
+        },
+        protocol::RequestBody::ReferenceConfig => context.reference.lock(|reference| match reference {
+            Some(reference) => protocol::ResponseBody::ReferenceConfig(reference.config()),
+            None => protocol::ResponseBody::Error(protocol::Error::Unsupported),
+        }),
+        protocol::RequestBody::SetReferenceConfig { config, persist } => {
+            let body = context.reference.lock(|reference| match reference {
+                Some(reference) => {
+                    if reference.configure(config) {
+                        protocol::ResponseBody::ReferenceConfig(reference.config())
+                    } else {
+                        protocol::ResponseBody::Error(protocol::Error::InvalidArgument)
+                    }
+                },
+                None => protocol::ResponseBody::Error(protocol::Error::Unsupported),
+            });
+            if persist {
+                if let protocol::ResponseBody::ReferenceConfig(_) = body {
+                    context.settings.reference = config;
+                    context.settings_store.save(context.settings).ok();
+                }
+            }
+            body
+        },
+    };
+
+    Some(protocol::Response {
+        correlation_id: request.correlation_id,
+        body: body
+    })
+}
+
+fn capture_status(capture: &Capture) -> protocol::ResponseBody {
+    protocol::ResponseBody::CaptureStatus {
+        state: capture.state(),
+        length: capture.length(),
+    }
+}
+
+fn pwm_config(pwm: &PwmTimer) -> protocol::ResponseBody {
+    protocol::ResponseBody::PwmConfig {
+        config: pwm.config(),
+        max_duty: pwm.max_duty(),
+    }
+}
+
+fn sample_trigger(pwm: &PwmTimer) -> protocol::ResponseBody {
+    protocol::ResponseBody::SampleTrigger {
+        trigger: pwm.trigger(),
+        locked: pwm.locked(),
+    }
+}
+
+fn supply_status(supply: &Supply) -> protocol::ResponseBody {
+    protocol::ResponseBody::Supply {
+        voltage: supply.voltage(),
+        state: supply.state(),
+        config: supply.config(),
     }
 }
 
+fn motor_protection(protection: &Protection) -> protocol::ResponseBody {
+    protocol::ResponseBody::MotorProtection {
+        config: protection.config(),
+        current: protection.current(),
+    }
+}
 
+// Cuts the motors' PWM if the e-stop has only just latched, and says so
+fn latch_estop(estop: &mut EStop, cutoff: &mut Cutoff) -> bool {
+    let latched = estop.check();
+    if latched {
+        cutoff.cut();
+    }
+    latched
+}
 
+// Halts the motors once the e-stop has latched
+fn check_estop(estop: &mut EStop, motors: &mut Motors) {
+    estop.check();
+    if let (Some(mode), None) = (estop.halt(), motors.halted) {
+        motors.halt(mode);
+    }
+}
 
+fn estop_status(estop: &EStop) -> protocol::ResponseBody {
+    protocol::ResponseBody::EStop {
+        status: estop.status(),
+        config: estop.config(),
+    }
+}
+
+fn heading_status(heading: &HeadingFilter) -> protocol::ResponseBody {
+    protocol::ResponseBody::Heading {
+        heading: heading.heading(),
+        config: heading.config(),
+    }
+}
+
+// The left and right wheels' positions, on a differential drive
+fn wheels(motors: &mut Motors) -> Option<(f32, f32)> {
+    if motors.motor_count() < 2 {
+        return None;
+    }
+    let left = motors.encoder(0)?.position();
+    let right = motors.encoder(1)?.position();
+    Some((left, right))
+}
+
+fn power_status(power: &Power) -> protocol::ResponseBody {
+    protocol::ResponseBody::Power {
+        button: power.button(),
+        off_in_ms: power.off_in(),
+    }
+}
+
+fn servo_status(servos: &Servos, servo: u8) -> protocol::ResponseBody {
+    match servos.config(servo as usize) {
+        Some(config) => protocol::ResponseBody::Servo {
+            servo: servo,
+            config: config,
+            angle: servos.angle(servo as usize),
+        },
+        None => protocol::ResponseBody::Error(protocol::Error::InvalidArgument),
+    }
+}
+
+// Sends events to whoever's watching for them, returning whether there were
+// any to send
+fn send_events(service: &mut Service, watcher: Option<i32>, events: &[protocol::Event]) -> bool {
+    let correlation_id = match watcher {
+        Some(correlation_id) if !events.is_empty() => correlation_id,
+        _ => return false,
+    };
+    for event in events {
+        service.response(&protocol::Response {
+            correlation_id: correlation_id,
+            body: protocol::ResponseBody::Event(*event),
+        });
+    }
+    true
+}
+
+// Does something to an encoder's calibration, then reports it
+// Responds InvalidArgument if `f` returns false
+fn calibration<M, F>(motors: &mut M, index: u8, f: F) -> protocol::ResponseBody
+where M: Mutex<T = Motors>, F: FnOnce(&mut Encoder) -> bool {
+    motors.lock(|motors| match motors.encoder(index as usize) {
+        Some(encoder) => {
+            if !f(encoder) {
+                return protocol::ResponseBody::Error(protocol::Error::InvalidArgument);
+            }
+            protocol::ResponseBody::Calibration {
+                encoder: index,
+                calibration: encoder.calibration(),
+                frozen: encoder.frozen(),
+            }
+        },
+        None => protocol::ResponseBody::Error(protocol::Error::InvalidArgument),
+    })
+}
@@ -1,9 +1,46 @@
 use heapless::{ ArrayLength, Vec };
 use heapless::spsc::{ Queue, Producer, Consumer };
-use embedded_hal::serial::{Read,Write};
-use nb::Error::WouldBlock;
 use postcard::{ self };
 use serde::{ Serialize, de::DeserializeOwned };
+use protocol::LinkStatistics;
+
+pub mod serial;
+pub mod packet;
+
+/// Something that can carry the COBS framed request and response streams
+/// between the host and the `Service`. The `Transport` only deals with the
+/// queues, so the same dispatch works over any link that implements this.
+pub trait Link {
+    /// Move whatever the link has received into the request queue. Returns
+    /// true if anything was queued, so the caller knows to process requests.
+    fn receive<N>(&mut self, requests: &mut Producer<'_, u8, N>) -> bool
+    where N: ArrayLength<u8>;
+
+    /// Move as much of the response queue onto the link as it will take
+    /// without blocking.
+    fn transmit<N>(&mut self, responses: &mut Consumer<'_, u8, N>)
+    where N: ArrayLength<u8>;
+
+    /// Change the baud rate once everything already queued has been sent.
+    /// Returns the rate that will actually be used, or None if the link
+    /// doesn't have a baud rate or can't do that one.
+    fn set_baud_rate(&mut self, _baud_rate: u32) -> Option<u32> {
+        None
+    }
+
+    fn baud_rate(&self) -> Option<u32> {
+        None
+    }
+
+    /// True the first time it's asked after a baud rate change has taken
+    /// effect
+    fn take_baud_rate_switched(&mut self) -> bool {
+        false
+    }
+
+    /// Add in whatever the link counts itself
+    fn add_statistics(&self, _statistics: &mut LinkStatistics) {}
+}
 
 pub struct Service<'a, Nin, Nout, Nb> 
 where
@@ -12,7 +49,8 @@ where
     Nb: ArrayLength<u8> {
     requests: Consumer<'a, u8, Nin>,
     responses: Producer<'a, u8, Nout>,
-    incomplete: Vec<u8, Nb>
+    incomplete: Vec<u8, Nb>,
+    statistics: LinkStatistics,
 }
 
 pub struct Transport<'a, Nin, Nout>
@@ -69,6 +107,7 @@ where
                 requests: requests_consumer, 
                 responses: responses_producer, 
                 incomplete: Vec::new(),
+                statistics: LinkStatistics::default(),
             });
     }
 }
@@ -78,40 +117,18 @@ where
     Nin: ArrayLength<u8>,
     Nout: ArrayLength<u8>,
 {
-    pub fn read_nb<R> (
+    pub fn read_nb<L> (
         &mut self,
-        command_rx: &mut R) -> bool
-    where R: Read<u8> {
-        let mut read = false;
-           loop {
-                match command_rx.read() {
-                    Ok(byte) => {
-                        read = self.requests.enqueue(byte).is_ok();
-                        if !read { break };
-                    },
-                    Err(WouldBlock) => break,
-                    Err(_) => panic!("Error reading from command serial"),
-                }
-            };
-            return read;
+        link: &mut L) -> bool
+    where L: Link {
+        link.receive(&mut self.requests)
     }
     
-    pub fn write_nb<W>(
+    pub fn write_nb<L>(
         &mut self,
-        command_tx: &mut W)
-    where W: Write<u8> {
-        loop {
-            match self.responses.peek() {
-                Some(byte) => {
-                    match command_tx.write(*byte) {
-                        Ok(_) => assert!(self.responses.dequeue().is_some()),
-                        Err(WouldBlock) => break,
-                        Err(_) => panic!("Error writing to command serial"),
-                    }
-                }
-                None => break
-            }
-        }
+        link: &mut L)
+    where L: Link {
+        link.transmit(&mut self.responses)
     }
 }
 
@@ -122,9 +139,16 @@ where
     Nb: ArrayLength<u8>
 {
     pub fn send(&mut self, packet: &[u8]) {
+        // Only send whole frames; half a frame would corrupt the next one too
+        if self.responses.capacity() - self.responses.len() < packet.len() {
+            self.statistics.tx_dropped += 1;
+            return;
+        }
         for byte in packet {
             self.responses.enqueue(*byte).unwrap();
         }
+        self.statistics.tx_bytes += packet.len() as u32;
+        self.statistics.tx_frames += 1;
     }
     
     pub fn response<R>(&mut self, r: &R)
@@ -133,6 +157,10 @@ where
         self.send(&encoded[..]);
     }
 
+    pub fn statistics(&self) -> LinkStatistics {
+        self.statistics
+    }
+
     pub fn recv<'a>(&'a mut self) -> Option<&'a mut [u8]> {
         // This is how I tell that the frame currently in
         // incomplete has already been returned
@@ -155,11 +183,11 @@ where
         }
     }
 
-    pub fn process<Request, Response, Service>(&mut self, service: Service)
+    pub fn process<Request, Response, Service>(&mut self, mut service: Service)
     where 
         Request: DeserializeOwned,
         Response: Serialize,
-        Service: Fn(Request) -> Option<Response>
+        Service: FnMut(Request) -> Option<Response>
     {
         loop {
             match self.requests.dequeue() {
@@ -167,12 +195,14 @@ where
                     // Just throw away empty frames
                     if self.incomplete.len() > 0 {
                         self.incomplete.push(0).unwrap();
+                        self.statistics.rx_bytes += self.incomplete.len() as u32;
+                        self.statistics.rx_frames += 1;
                         let result : postcard::Result<Request> = postcard::from_bytes_cobs(&mut self.incomplete[..]);
                         match result {
                             Ok(request) => {
                                 service(request).map(|x| self.response(&x));
                             },
-                            Err(_) => {}
+                            Err(_) => self.statistics.rx_errors += 1,
                         }
                         self.incomplete.clear();
                     }
@@ -0,0 +1,25 @@
+use heapless::ArrayLength;
+use heapless::spsc::{ Producer, Consumer };
+use protocol::LinkStatistics;
+
+pub use logic::packet::{ Packets, PacketLink };
+
+use super::Link;
+
+impl <P> Link for PacketLink<P>
+where P: Packets {
+    fn receive<N>(&mut self, requests: &mut Producer<'_, u8, N>) -> bool
+    where N: ArrayLength<u8> {
+        self.receive_into(|byte| requests.enqueue(byte).is_ok())
+    }
+
+    fn transmit<N>(&mut self, responses: &mut Consumer<'_, u8, N>)
+    where N: ArrayLength<u8> {
+        self.transmit_from(|| responses.dequeue())
+    }
+
+    fn add_statistics(&self, statistics: &mut LinkStatistics) {
+        statistics.tx_lost += self.tx_lost();
+        statistics.rx_lost += self.rx_lost();
+    }
+}
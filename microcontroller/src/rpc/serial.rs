@@ -0,0 +1,57 @@
+use heapless::ArrayLength;
+use heapless::spsc::{ Producer, Consumer };
+use embedded_hal::serial::{ Read, Write };
+use nb::Error::WouldBlock;
+
+use super::Link;
+
+/// A byte at a time link over anything that implements the embedded-hal
+/// serial traits: an interrupt driven USART, or a USB CDC-ACM port.
+pub struct SerialLink<TX, RX> {
+    pub tx: TX,
+    pub rx: RX,
+}
+
+impl <TX, RX> SerialLink<TX, RX> {
+    pub fn new(tx: TX, rx: RX) -> Self {
+        SerialLink { tx: tx, rx: rx }
+    }
+}
+
+impl <TX, RX> Link for SerialLink<TX, RX>
+where
+    TX: Write<u8>,
+    RX: Read<u8>,
+{
+    fn receive<N>(&mut self, requests: &mut Producer<'_, u8, N>) -> bool
+    where N: ArrayLength<u8> {
+        let mut read = false;
+        loop {
+            match self.rx.read() {
+                Ok(byte) => {
+                    read = requests.enqueue(byte).is_ok();
+                    if !read { break };
+                },
+                Err(WouldBlock) => break,
+                Err(_) => panic!("Error reading from command serial"),
+            }
+        };
+        return read;
+    }
+
+    fn transmit<N>(&mut self, responses: &mut Consumer<'_, u8, N>)
+    where N: ArrayLength<u8> {
+        loop {
+            match responses.peek() {
+                Some(byte) => {
+                    match self.tx.write(*byte) {
+                        Ok(_) => assert!(responses.dequeue().is_some()),
+                        Err(WouldBlock) => break,
+                        Err(_) => panic!("Error writing to command serial"),
+                    }
+                }
+                None => break
+            }
+        }
+    }
+}
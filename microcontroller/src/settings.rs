@@ -0,0 +1,34 @@
+use logic::settings::{ SETTINGS_SIZE, decode, encode };
+use stm32f1xx_hal::flash::{ self, FlashSize, SectorSize };
+
+pub use logic::settings::Settings;
+
+// The last 1K page of the 64K of flash
+const SETTINGS_OFFSET: u32 = 63 * 1024;
+
+pub struct SettingsStore {
+    flash: flash::Parts,
+}
+
+impl SettingsStore {
+    pub fn new(flash: flash::Parts) -> Self {
+        SettingsStore { flash: flash }
+    }
+
+    pub fn load(&mut self) -> Settings {
+        let writer = self.flash.writer(SectorSize::Sz1K, FlashSize::Sz64K);
+        match writer.read(SETTINGS_OFFSET, SETTINGS_SIZE) {
+            Ok(stored) => decode(stored),
+            Err(_) => Settings::default(),
+        }
+    }
+
+    pub fn save(&mut self, settings: &Settings) -> Result<(), flash::Error> {
+        let mut buf = [0xffu8; SETTINGS_SIZE];
+        let written = encode(settings, &mut buf);
+
+        let mut writer = self.flash.writer(SectorSize::Sz1K, FlashSize::Sz64K);
+        writer.page_erase(SETTINGS_OFFSET)?;
+        writer.write(SETTINGS_OFFSET, &buf[..written])
+    }
+}
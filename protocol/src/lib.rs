@@ -1,24 +1,667 @@
 #![no_std]
 use serde::{Serialize, Deserialize};
 
-#[derive(Serialize, Deserialize, Debug, Eq, PartialEq)]
+/// The USB vendor and product ids the microcontroller enumerates with when it
+/// uses USB for the command link. This is the shared V-USB CDC-ACM pair.
+pub const USB_VID: u16 = 0x16c0;
+pub const USB_PID: u16 = 0x27dd;
+
+/// Radio links carry the COBS framed stream in packets of `RADIO_PACKET`
+/// bytes: a sequence number, the number of stream bytes that follow, then the
+/// bytes. A gap in the sequence numbers means the frame in progress is lost.
+pub const RADIO_PACKET: usize = 32;
+pub const RADIO_HEADER: usize = 2;
+
+/// The most encoders a board can have. Encoders are numbered from 0, and
+/// the ones with motors come first.
+pub const MAX_ENCODERS: usize = 5;
+
+/// Hobby servo outputs, on TIM4 CH1 to CH4 (PB6 to PB9), on a board built
+/// with them.
+pub const MAX_SERVOS: usize = 4;
+
+/// The most bytes an `I2c` request can write, or read back
+pub const I2C_MAX: usize = 16;
+
+/// A capture records the raw ADC value of every encoder input, each sample
+/// being channels A and B of the first encoder, then A and B of the next.
+/// Channels past the board's encoders are 0.
+pub const CAPTURE_CHANNELS: usize = 2 * MAX_ENCODERS;
+pub const CAPTURE_LENGTH: u16 = 256;
+/// Samples in each `CaptureChunk`
+pub const CAPTURE_CHUNK: usize = 8;
+
+pub type CaptureSample = [u16; CAPTURE_CHANNELS];
+
+#[derive(Serialize, Deserialize, Debug, Eq, PartialEq, Clone, Copy)]
+pub enum CaptureTrigger {
+    /// Start capturing straight away
+    Immediate,
+    /// A channel going from below `level` to at or above it
+    Rising { channel: u8, level: u16 },
+    /// A channel going from at or above `level` to below it
+    Falling { channel: u8, level: u16 },
+    /// A channel jumping by more than `threshold` from one sample to the next
+    Glitch { channel: u8, threshold: u16 },
+}
+
+#[derive(Serialize, Deserialize, Debug, Eq, PartialEq, Clone, Copy)]
+pub enum CaptureState {
+    Idle,
+    /// Waiting for the trigger
+    Armed,
+    /// Recording what comes after the trigger
+    Triggered,
+    /// The buffer is ready to read
+    Complete,
+}
+
+#[derive(Serialize, Deserialize, Debug, PartialEq)]
 pub enum RequestBody {
-    Ping
+    Ping,
+    LinkStatistics,
+    /// Change the command serial baud rate once the response has been sent.
+    /// If no request arrives at the new rate within a couple of seconds, the
+    /// microcontroller goes back to the old one. Once it has, the new rate is
+    /// saved for the next reset if `persist` is set. Until then, another
+    /// change is refused as `Busy`.
+    SetBaudRate { baud_rate: u32, persist: bool },
+    SampleRate,
+    /// Start recording `CAPTURE_LENGTH` samples, `pre_trigger` of them from
+    /// before the trigger.
+    ArmCapture { trigger: CaptureTrigger, pre_trigger: u16 },
+    CaptureStatus,
+    /// Read `CAPTURE_CHUNK` samples of a complete capture from `offset`.
+    ReadCapture { offset: u16 },
+    /// What encoders and motors the board has
+    Encoders,
+    /// Signal quality of an encoder's inputs
+    EncoderHealth { encoder: u8 },
+    Calibration { encoder: u8 },
+    /// Replace an encoder's calibration. None goes back to the raw signals
+    /// until the next fit. InvalidArgument if an amplitude is under 50 or the
+    /// phase error is over 45 degrees, as a fit would never give that.
+    SetCalibration { encoder: u8, calibration: Option<Calibration> },
+    /// Stop, or start again, updating the calibration from the signals.
+    FreezeCalibration { encoder: u8, frozen: bool },
+    /// Drive an encoder's motor at `duty` (the sign is the direction) until
+    /// its index pulse comes round, then stop and make that position 0. The
+    /// response comes straight away, then again every 100ms with the
+    /// progress, and a last time when it's homed, times out or the motor
+    /// protection stops it, all with this request's correlation id.
+    Home { encoder: u8, duty: f32, timeout_ms: u32 },
+    CancelHoming,
+    /// How the analog decoding of `encoder` compares with the reference
+    /// decoder. `reset`, or asking about a different encoder, starts the
+    /// comparison again.
+    CompareReference { encoder: u8, reset: bool },
+    ReferenceConfig,
+    /// Change how the reference decoder's count compares with the analog
+    /// encoder's cycles, which starts the comparison again. With `persist`
+    /// it's saved for after a reset.
+    SetReferenceConfig { config: ReferenceConfig, persist: bool },
+    MotorConfig { encoder: u8 },
+    /// Change how an encoder and its motor, if it has one, are wired up.
+    /// With `persist` it's saved for after a reset.
+    SetMotorConfig { encoder: u8, config: MotorConfig, persist: bool },
+    OutputShaping { encoder: u8 },
+    /// Change how the duty asked of an encoder's motor is shaped. With
+    /// `persist` it's saved for after a reset.
+    SetOutputShaping { encoder: u8, shaping: OutputShaping, persist: bool },
+    PwmConfig,
+    /// Change the motor PWM. With `persist` it's saved for after a reset.
+    SetPwmConfig { config: PwmConfig, persist: bool },
+    SampleTrigger,
+    /// Change when the encoders are sampled. With `persist` it's saved for
+    /// after a reset.
+    SetSampleTrigger { trigger: SampleTrigger, persist: bool },
+    /// A snapshot of the encoders and the supply
+    Telemetry,
+    Supply,
+    /// Change the supply measurement and limits. With `persist` they're
+    /// saved for after a reset.
+    SetSupplyConfig { config: SupplyConfig, persist: bool },
+    /// Send events, like a motor stalling, as responses to this request from
+    /// now on, or stop sending them
+    WatchEvents { watch: bool },
+    MotorProtection,
+    /// Change the current limit and stall detection. With `persist` it's
+    /// saved for after a reset.
+    SetMotorProtection { config: MotorProtection, persist: bool },
+    Servo { servo: u8 },
+    /// Move a servo to `angle`, which has to be within its config's range,
+    /// or with None stop its pulses so it goes limp.
+    SetServo { servo: u8, angle: Option<f32> },
+    /// Change a servo's pulse widths and the angles they stand for. With
+    /// `persist` it's saved for after a reset.
+    SetServoConfig { servo: u8, config: ServoConfig, persist: bool },
+    Power,
+    /// Cut the board's power, and whatever it powers, `delay_ms` from now,
+    /// giving the host time to shut down. None cancels it.
+    PowerOff { delay_ms: Option<u32> },
+    /// The last reading of the IMU on the I2C2 expansion bus
+    Imu,
+    Heading,
+    /// Start the heading again from `heading`, in degrees
+    SetHeading { heading: f32 },
+    /// Change how the heading is worked out. With `persist` it's saved for
+    /// after a reset.
+    SetHeadingConfig { config: HeadingConfig, persist: bool },
+    /// Write the first `write` bytes of `data` to the device at the 7 bit
+    /// `address` on the I2C2 expansion bus, then read `read` bytes back,
+    /// with a repeated start if there's both. For bringing up new sensors.
+    I2c { address: u8, write: u8, data: [u8; I2C_MAX], read: u8 },
+    EStop,
+    /// Change the e-stop input. With `persist` it's saved for after a reset.
+    SetEStopConfig { config: EStopConfig, persist: bool },
+    /// Let the motors go again after an e-stop, as long as the input isn't
+    /// still active. They stay stopped until they're next driven.
+    ClearEStop,
+    /// Why the microcontroller last reset, and how long its watchdog waits
+    DeviceInfo,
 }
 
-#[derive(Serialize, Deserialize, Debug, Eq, PartialEq)]
+#[derive(Serialize, Deserialize, Debug, PartialEq)]
 pub struct Request {
     // pub message_id : u64,
     pub correlation_id : i32,
     pub body : RequestBody
 }
 
-#[derive(Serialize, Deserialize, Debug, Eq, PartialEq)]
+/// Counters for the command link, as the microcontroller sees it. Comparing
+/// two of these over a known time gives the achieved throughput.
+#[derive(Serialize, Deserialize, Debug, Eq, PartialEq, Clone, Copy, Default)]
+pub struct LinkStatistics {
+    pub rx_bytes: u32,
+    pub rx_frames: u32,
+    // Frames that failed to decode
+    pub rx_errors: u32,
+    pub tx_bytes: u32,
+    pub tx_frames: u32,
+    // Responses dropped because the response queue was full
+    pub tx_dropped: u32,
+    // Radio fragments the other end never acknowledged
+    pub tx_lost: u32,
+    // Radio fragments that couldn't be read
+    pub rx_lost: u32,
+    // Times received bytes were overwritten before they were read
+    pub rx_overruns: u32,
+}
+
+#[derive(Serialize, Deserialize, Debug, Eq, PartialEq, Clone, Copy)]
+pub enum Error {
+    /// This build or this link can't do that
+    Unsupported,
+    InvalidArgument,
+    /// An earlier request hasn't finished yet
+    Busy,
+    /// A device didn't answer, or the bus is stuck
+    Bus,
+}
+
+#[derive(Serialize, Deserialize, Debug, Eq, PartialEq, Clone, Copy)]
+pub enum SignalFault {
+    None,
+    /// The input isn't swinging much, which may just mean it isn't turning
+    LowAmplitude,
+    /// The input is stuck at one end of the ADC's range
+    Disconnected,
+}
+
+/// One input of an analog encoder, in ADC counts.
+#[derive(Serialize, Deserialize, Debug, PartialEq, Clone, Copy)]
+pub struct ChannelHealth {
+    /// The mean level
+    pub offset: f32,
+    /// The peak amplitude of a sine wave with the same power
+    pub amplitude: f32,
+    /// The RMS noise about the signal
+    pub noise: f32,
+    pub fault: SignalFault,
+}
+
+/// How good an analog encoder's signals look, measured over the last
+/// `samples` samples. Amplitude and phase are only meaningful if the encoder
+/// turned through at least a few cycles in that time.
+#[derive(Serialize, Deserialize, Debug, PartialEq, Clone, Copy)]
+pub struct EncoderHealth {
+    pub a: ChannelHealth,
+    pub b: ChannelHealth,
+    /// The difference between the amplitudes over their mean
+    pub amplitude_mismatch: f32,
+    /// How far the phase between A and B is from 90 degrees, in degrees
+    pub phase_error: f32,
+    pub samples: u32,
+}
+
+/// For motors and encoders that are mounted or wired the other way round
+#[derive(Serialize, Deserialize, Debug, Eq, PartialEq, Clone, Copy, Default)]
+pub struct MotorConfig {
+    /// Positive duty drives the motor the other way
+    pub invert_output: bool,
+    /// The encoder counts the other way
+    pub reverse_encoder: bool,
+    /// The encoder's A and B inputs are the other way round, which also
+    /// reverses it
+    pub swap_channels: bool,
+}
+
+/// What a motor's output does with the duty it's asked for. A duty that
+/// isn't zero is scaled from `min_duty` to `max_duty`, so the smallest one
+/// still gets past static friction, and the output moves towards it by at
+/// most `max_step` each 10ms control period.
+#[derive(Serialize, Deserialize, Debug, PartialEq, Clone, Copy)]
+pub struct OutputShaping {
+    pub max_step: f32,
+    pub min_duty: f32,
+    pub max_duty: f32,
+}
+
+impl Default for OutputShaping {
+    /// No shaping at all
+    fn default() -> Self {
+        OutputShaping {
+            max_step: 2.0,
+            min_duty: 0.0,
+            max_duty: 1.0,
+        }
+    }
+}
+
+/// The motor PWM, which all the motors share
+#[derive(Serialize, Deserialize, Debug, Eq, PartialEq, Clone, Copy)]
+pub struct PwmConfig {
+    /// In Hz, from 1kHz to 25kHz
+    pub frequency: u32,
+    /// The timer counts up and down, so each pulse is centred in the period
+    /// rather than every output switching on together at the start of it
+    pub centre_aligned: bool,
+}
+
+impl Default for PwmConfig {
+    fn default() -> Self {
+        PwmConfig {
+            frequency: 10_000,
+            centre_aligned: false,
+        }
+    }
+}
+
+/// When the encoders are sampled, relative to the motor PWM. The switching
+/// edges couple into the encoder inputs, so it's quieter to sample between
+/// them.
+#[derive(Serialize, Deserialize, Debug, PartialEq, Clone, Copy)]
+pub struct SampleTrigger {
+    /// Lock the PWM to the sampling, so every sample is taken at the same
+    /// point of the PWM period. That only works when the PWM frequency is a
+    /// whole multiple of the sample rate.
+    pub synchronised: bool,
+    /// Where in the PWM period the samples are taken, from 0, its start, to
+    /// 1
+    pub offset: f32,
+}
+
+impl Default for SampleTrigger {
+    fn default() -> Self {
+        SampleTrigger {
+            synchronised: false,
+            offset: 0.5,
+        }
+    }
+}
+
+/// How the motor supply is measured, on PA4 through a divider, and what's
+/// done about it. The voltages are of the supply itself.
+#[derive(Serialize, Deserialize, Debug, PartialEq, Clone, Copy)]
+pub struct SupplyConfig {
+    /// Supply volts per volt at the pin
+    pub divider: f32,
+    /// Below this the motors are freed
+    pub undervoltage: f32,
+    /// Below this the motors' duty is scaled down, to nothing at
+    /// `undervoltage`. The same as `undervoltage` for no derating.
+    pub derate: f32,
+    /// Above this the motors are freed, in case they're what's pushing it up
+    pub overvoltage: f32,
+    /// The supply the duty is meant for: at any other, it's scaled so the
+    /// motors see the same voltage, up to twice the duty asked for. 0 to
+    /// leave the duty alone; otherwise `undervoltage` can't be 0.
+    pub nominal: f32,
+}
+
+impl Default for SupplyConfig {
+    /// A 10k/3.3k divider, with no limits and no compensation
+    fn default() -> Self {
+        SupplyConfig {
+            divider: 4.03,
+            undervoltage: 0.0,
+            derate: 0.0,
+            overvoltage: 50.0,
+            nominal: 0.0,
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Eq, PartialEq, Clone, Copy)]
+pub enum SupplyState {
+    Normal,
+    /// Between `derate` and `undervoltage`
+    Derated,
+    Undervoltage,
+    Overvoltage,
+}
+
+/// Where everything is, in one go. Only the first `encoders` of each
+/// array mean anything.
+#[derive(Serialize, Deserialize, Debug, PartialEq, Clone, Copy)]
+pub struct Telemetry {
+    pub encoders: u8,
+    /// In cycles
+    pub positions: [f32; MAX_ENCODERS],
+    /// In cycles a second
+    pub velocities: [f32; MAX_ENCODERS],
+    /// In volts, 0 on a board that doesn't measure it
+    pub supply_voltage: f32,
+    pub supply_state: SupplyState,
+    /// Of all the motors, in amps; 0 without a current sensor
+    pub motor_current: f32,
+    /// In degrees, as `Heading`'s
+    pub heading: f32,
+    pub estop: EStopStatus,
+}
+
+/// Keeps the motors from burning out. The current is of all the motors
+/// together, through a sensor on PA5; without one, a stall can still be
+/// told from a motor being driven hard without turning.
+#[derive(Serialize, Deserialize, Debug, PartialEq, Clone, Copy)]
+pub struct MotorProtection {
+    /// Amps per volt at the pin, 0 if there's no current sensor
+    pub amps_per_volt: f32,
+    /// Volts at the pin with no current
+    pub zero_current: f32,
+    /// Above this, in amps, the duty is cut back until it isn't. 0 for no
+    /// limit.
+    pub current_limit: f32,
+    /// A motor driven at least this hard...
+    pub stall_duty: f32,
+    /// ...that turns slower than this, in cycles a second...
+    pub stall_velocity: f32,
+    /// ...for this long has stalled, and is freed. 0 never to free it.
+    pub stall_timeout_ms: u32,
+}
+
+impl Default for MotorProtection {
+    /// No current sensor, and a stall is anything over half duty that
+    /// hasn't moved for a second
+    fn default() -> Self {
+        MotorProtection {
+            amps_per_volt: 0.0,
+            zero_current: 0.0,
+            current_limit: 0.0,
+            stall_duty: 0.5,
+            stall_velocity: 0.1,
+            stall_timeout_ms: 1000,
+        }
+    }
+}
+
+/// A hobby servo's pulse widths at the two ends of its travel, and the
+/// angles they stand for. The angles are in whatever units suit: degrees,
+/// or a gripper's opening in mm. `min_angle` can be more than `max_angle`
+/// to turn the servo round.
+#[derive(Serialize, Deserialize, Debug, PartialEq, Clone, Copy)]
+pub struct ServoConfig {
+    /// In microseconds
+    pub min_pulse_us: u16,
+    pub max_pulse_us: u16,
+    pub min_angle: f32,
+    pub max_angle: f32,
+}
+
+impl Default for ServoConfig {
+    /// The usual 1ms to 2ms for 0 to 180 degrees
+    fn default() -> Self {
+        ServoConfig {
+            min_pulse_us: 1000,
+            max_pulse_us: 2000,
+            min_angle: 0.0,
+            max_angle: 180.0,
+        }
+    }
+}
+
+/// An IMU reading, with the IMU mounted flat, components up: the
+/// accelerations in g and the rotation rates in degrees a second, about X,
+/// Y and Z, and its temperature in degrees C.
+#[derive(Serialize, Deserialize, Debug, PartialEq, Clone, Copy, Default)]
+pub struct ImuReading {
+    pub accel: [f32; 3],
+    pub gyro: [f32; 3],
+    pub temperature: f32,
+}
+
+/// How the heading is worked out from the wheels of a differential drive
+/// and the IMU's Z rotation. Each control period the gyro's turn is added to
+/// the heading, which is then pulled `1 - gyro_weight` of the way towards
+/// the wheels' heading, so the gyro follows quick turns and wheel slip
+/// while the wheels keep it from drifting. Without an IMU it's the wheels'
+/// heading, and without the wheels' geometry it's the gyro's.
+#[derive(Serialize, Deserialize, Debug, PartialEq, Clone, Copy)]
+pub struct HeadingConfig {
+    /// How far a wheel goes for each encoder cycle, with encoder 0 on the
+    /// left wheel and encoder 1 on the right, both counting up going
+    /// forwards. 0 if it's not known.
+    pub metres_per_cycle: f32,
+    /// From the middle of one wheel to the middle of the other
+    pub track_width: f32,
+    /// From 0 to 1
+    pub gyro_weight: f32,
+}
+
+impl Default for HeadingConfig {
+    fn default() -> Self {
+        HeadingConfig {
+            metres_per_cycle: 0.0,
+            track_width: 0.0,
+            gyro_weight: 0.98,
+        }
+    }
+}
+
+/// The heading, in degrees anticlockwise seen from above, and what it was
+/// worked out from: the wheels' heading and the gyro's, all since the
+/// heading was last set. None of them wrap at a whole turn.
+#[derive(Serialize, Deserialize, Debug, PartialEq, Clone, Copy, Default)]
+pub struct Heading {
+    pub heading: f32,
+    pub odometry: f32,
+    pub gyro: f32,
+    /// What the gyro reads standing still, in degrees a second, learned
+    /// while the wheels aren't turning
+    pub gyro_bias: f32,
+}
+
+/// The emergency stop input on PA8. When it's active for `debounce_ms`
+/// every motor is stopped, and held stopped until it's cleared with
+/// `ClearEStop`, whatever the host asks for in between. The input is pulled
+/// towards its active level, so with a normally closed switch a broken wire
+/// stops the motors too.
+#[derive(Serialize, Deserialize, Debug, Eq, PartialEq, Clone, Copy)]
+pub struct EStopConfig {
+    /// Whether there's an e-stop wired up
+    pub enabled: bool,
+    /// The input is high when it's stopping the motors
+    pub active_high: bool,
+    /// 0 to stop on the first edge
+    pub debounce_ms: u16,
+    /// Brake the motors rather than letting them coast
+    pub brake: bool,
+}
+
+impl Default for EStopConfig {
+    /// None, as an unconnected input would stop the motors
+    fn default() -> Self {
+        EStopConfig {
+            enabled: false,
+            active_high: true,
+            debounce_ms: 5,
+            brake: false,
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Eq, PartialEq, Clone, Copy, Default)]
+pub struct EStopStatus {
+    /// The input is active now
+    pub active: bool,
+    /// The motors are being held stopped until it's cleared
+    pub latched: bool,
+}
+
+/// Why the microcontroller last reset. The F103 has no brown-out flag of its
+/// own: the supply dipping too low resets it as if it had just come on.
+#[derive(Serialize, Deserialize, Debug, Eq, PartialEq, Clone, Copy)]
+pub enum ResetCause {
+    /// The power coming on, or a brown-out
+    PowerOn,
+    /// The reset pin
+    Pin,
+    /// The firmware asked for it
+    Software,
+    /// The independent watchdog wasn't fed, because the firmware had hung or
+    /// the sampling had stopped
+    Watchdog,
+    WindowWatchdog,
+    LowPower,
+}
+
+/// Something that happened, sent to whoever asked with `WatchEvents`
+#[derive(Serialize, Deserialize, Debug, PartialEq, Clone, Copy)]
+pub enum Event {
+    /// The encoder's motor stalled and was freed. The duty was what it was
+    /// driven at, and the current in amps is of all the motors.
+    Stall { encoder: u8, duty: f32, current: f32 },
+    /// The power button was pressed. The host should shut down, asking for
+    /// `PowerOff` first. Holding the button down for a few seconds cuts the
+    /// power without waiting for it.
+    PowerButton,
+    /// The e-stop stopped the motors
+    EStop,
+}
+
+#[derive(Serialize, Deserialize, Debug, Eq, PartialEq, Clone, Copy)]
+pub enum HomingState {
+    Searching,
+    /// Found the index; the position is now relative to it
+    Homed,
+    TimedOut,
+    Cancelled,
+    /// The motor protection freed the motor for stalling
+    Stalled,
+}
+
+/// How an analog encoder's A and B inputs, in ADC counts, differ from an
+/// ideal sine and cosine: A = offset_a + amplitude_a sin(t + phase_error) and
+/// B = offset_b + amplitude_b cos(t), with the phase error in degrees.
+#[derive(Serialize, Deserialize, Debug, PartialEq, Clone, Copy)]
+pub struct Calibration {
+    pub offset_a: f32,
+    pub offset_b: f32,
+    pub amplitude_a: f32,
+    pub amplitude_b: f32,
+    pub phase_error: f32,
+}
+
+/// The position of an analog encoder and of a digital one on the same shaft,
+/// counted by a timer, in cycles since the comparison started, and how far
+/// apart they've been.
+#[derive(Serialize, Deserialize, Debug, PartialEq, Clone, Copy, Default)]
+pub struct ReferenceComparison {
+    pub encoder: u8,
+    pub analog: f32,
+    pub reference: f32,
+    pub difference: f32,
+    pub min: f32,
+    pub max: f32,
+    pub rms: f32,
+    pub samples: u32,
+}
+
+/// How the reference decoder's count compares with the analog encoder's
+/// cycles. The timer counts every edge of both channels, so four times a
+/// line.
+#[derive(Serialize, Deserialize, Debug, PartialEq, Clone, Copy)]
+pub struct ReferenceConfig {
+    /// Timer counts to each analog cycle
+    pub counts_per_cycle: f32,
+    /// The reference counts the other way from the analog encoder
+    pub reverse: bool,
+}
+
+impl Default for ReferenceConfig {
+    /// A line of the digital encoder to each analog cycle
+    fn default() -> Self {
+        ReferenceConfig {
+            counts_per_cycle: 4.0,
+            reverse: false,
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, PartialEq)]
 pub enum ResponseBody {
-    Ping
+    Ping,
+    LinkStatistics(LinkStatistics),
+    /// The rate that will be used, which may be a little different from the
+    /// one asked for.
+    SetBaudRate { baud_rate: u32 },
+    /// The encoder inputs' sample rate: the one the sampling timer is set up
+    /// for, and the one we're actually getting through, in Hz.
+    SampleRate { configured: u32, measured: u32, overruns: u32 },
+    CaptureStatus { state: CaptureState, length: u16 },
+    /// `count` is less than `CAPTURE_CHUNK` at the end of the capture.
+    CaptureChunk { offset: u16, count: u8, samples: [CaptureSample; CAPTURE_CHUNK] },
+    /// Encoders 0 to `encoders` - 1 are available, and the first `motors`
+    /// of them have motors. On a differential drive 0 is the left and 1 the
+    /// right.
+    Encoders { encoders: u8, motors: u8 },
+    EncoderHealth(EncoderHealth),
+    /// The calibration the encoder is using, if it has one yet, and whether
+    /// it's frozen.
+    Calibration { encoder: u8, calibration: Option<Calibration>, frozen: bool },
+    /// The encoder's position in cycles
+    Homing { encoder: u8, state: HomingState, position: f32 },
+    ReferenceComparison(ReferenceComparison),
+    ReferenceConfig(ReferenceConfig),
+    MotorConfig { encoder: u8, config: MotorConfig },
+    OutputShaping { encoder: u8, shaping: OutputShaping },
+    /// The frequency is what the timer really runs at, which can be a little
+    /// off what was asked for. A duty has `max_duty` + 1 steps.
+    PwmConfig { config: PwmConfig, max_duty: u16 },
+    /// `locked` if the PWM really is synchronised to the sampling
+    SampleTrigger { trigger: SampleTrigger, locked: bool },
+    Telemetry(Telemetry),
+    /// The voltage is in volts
+    Supply { voltage: f32, state: SupplyState, config: SupplyConfig },
+    WatchEvents { watch: bool },
+    Event(Event),
+    /// The current is in amps
+    MotorProtection { config: MotorProtection, current: f32 },
+    /// The angle is None while the servo is limp
+    Servo { servo: u8, config: ServoConfig, angle: Option<f32> },
+    /// Whether the power button is down, and how long until the power is
+    /// cut, if it's going to be
+    Power { button: bool, off_in_ms: Option<u32> },
+    Imu(ImuReading),
+    Heading { heading: Heading, config: HeadingConfig },
+    /// The first `read` bytes of `data` are what was read
+    I2c { read: u8, data: [u8; I2C_MAX] },
+    EStop { status: EStopStatus, config: EStopConfig },
+    DeviceInfo { reset_cause: ResetCause, watchdog_ms: u32 },
+    Error(Error),
 }
 
-#[derive(Serialize, Deserialize, Debug, Eq, PartialEq)]
+#[derive(Serialize, Deserialize, Debug, PartialEq)]
 pub struct Response {
     // pub message_id : u64,
     pub correlation_id : i32,